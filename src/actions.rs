@@ -1,10 +1,17 @@
+pub mod acl;
 mod action;
+pub mod backup;
 mod cause;
+pub mod diff;
 mod errors;
+mod merge;
 mod package;
 pub mod package_manager;
 
-pub use action::{Action, RunConfig};
+pub use acl::AclEntry;
+pub use action::{Action, RunConfig, write_securely};
+pub use backup::BackupStore;
 pub use cause::Cause;
+pub use diff::Change;
 pub use errors::ActionError;
 pub use package::Package;
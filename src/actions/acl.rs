@@ -0,0 +1,406 @@
+// POSIX ACLs as stored by the kernel in the system.posix_acl_access and
+// system.posix_acl_default extended attributes: a version header followed by
+// (tag, perms, id) entries sorted by tag and id.
+//
+// pets manages the named user and group entries only. The owner, owning
+// group and other entries follow the file mode, and the mask is the union of
+// the owning group and the named entries, as setfacl(1) computes it. As the
+// group bits of the mode show the mask, they include the named entries too.
+
+use std::{fmt, fs, io, os::unix::fs::PermissionsExt, path::Path};
+
+const ACCESS: &str = "system.posix_acl_access";
+const DEFAULT: &str = "system.posix_acl_default";
+const VERSION: u32 = 2;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Qualifier {
+    User(u32),
+    Group(u32),
+    // Accounts that do not exist yet, such as those declared by `user` or
+    // `group` directives, are looked up when the ACL is applied.
+    UserName(String),
+    GroupName(String),
+}
+
+/// A named user or group entry of the access or default ACL.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AclEntry {
+    default: bool,
+    qualifier: Qualifier,
+    perms: u16,
+}
+
+impl fmt::Display for AclEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.default {
+            write!(f, "d:")?;
+        }
+        match &self.qualifier {
+            Qualifier::User(uid) => match uzers::get_user_by_uid(*uid) {
+                Some(user) => write!(f, "u:{}:", user.name().to_string_lossy())?,
+                None => write!(f, "u:{uid}:")?,
+            },
+            Qualifier::Group(gid) => match uzers::get_group_by_gid(*gid) {
+                Some(group) => write!(f, "g:{}:", group.name().to_string_lossy())?,
+                None => write!(f, "g:{gid}:")?,
+            },
+            Qualifier::UserName(name) => write!(f, "u:{name}:")?,
+            Qualifier::GroupName(name) => write!(f, "g:{name}:")?,
+        }
+        for (bit, c) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            write!(f, "{}", if self.perms & bit == 0 { '-' } else { c })?;
+        }
+        Ok(())
+    }
+}
+
+impl AclEntry {
+    /// returns whether this is an entry of the default ACL of a directory.
+    pub fn is_default(&self) -> bool {
+        self.default
+    }
+
+    /// parses a setfacl(1) style entry such as `g:adm:r-x`, `u:deploy:rw`
+    /// or `d:g:devs:rwx`. Returns `None` if it is malformed. Users and groups
+    /// that do not exist yet are kept by name, see `unknown_account`.
+    pub fn parse(entry: &str) -> Option<Self> {
+        let mut parts = entry.split(':').collect::<Vec<_>>();
+        let default = matches!(parts.first(), Some(&("d" | "default")));
+        if default {
+            parts.remove(0);
+        }
+        let [tag, name, perms] = parts[..] else {
+            return None;
+        };
+
+        if name.is_empty() {
+            return None;
+        }
+        let qualifier = match tag {
+            "u" | "user" => match name.parse() {
+                Ok(uid) => Qualifier::User(uid),
+                Err(_) => uzers::get_user_by_name(name).map_or_else(
+                    || Qualifier::UserName(name.to_string()),
+                    |user| Qualifier::User(user.uid()),
+                ),
+            },
+            "g" | "group" => match name.parse() {
+                Ok(gid) => Qualifier::Group(gid),
+                Err(_) => uzers::get_group_by_name(name).map_or_else(
+                    || Qualifier::GroupName(name.to_string()),
+                    |group| Qualifier::Group(group.gid()),
+                ),
+            },
+            _ => return None,
+        };
+        let perms = perms.chars().try_fold(0, |perms, c| match c {
+            'r' => Some(perms | 4),
+            'w' => Some(perms | 2),
+            'x' => Some(perms | 1),
+            '-' => Some(perms),
+            _ => None,
+        })?;
+
+        Some(Self {
+            default,
+            qualifier,
+            perms,
+        })
+    }
+
+    /// returns the kind and name of the user or group this entry names if
+    /// it did not exist when parsed.
+    pub fn unknown_account(&self) -> Option<(&'static str, &str)> {
+        match &self.qualifier {
+            Qualifier::UserName(name) => Some(("user", name)),
+            Qualifier::GroupName(name) => Some(("group", name)),
+            Qualifier::User(_) | Qualifier::Group(_) => None,
+        }
+    }
+
+    /// returns this entry with its user or group looked up by name.
+    fn resolve(&self) -> io::Result<Self> {
+        let unknown = |kind| io::Error::new(io::ErrorKind::NotFound, format!("unknown {kind}"));
+        let qualifier = match &self.qualifier {
+            Qualifier::UserName(name) => Qualifier::User(
+                uzers::get_user_by_name(name)
+                    .ok_or_else(|| unknown(format!("user {name}")))?
+                    .uid(),
+            ),
+            Qualifier::GroupName(name) => Qualifier::Group(
+                uzers::get_group_by_name(name)
+                    .ok_or_else(|| unknown(format!("group {name}")))?
+                    .gid(),
+            ),
+            qualifier => qualifier.clone(),
+        };
+        Ok(Self {
+            qualifier,
+            ..self.clone()
+        })
+    }
+}
+
+/// returns `entries` with their users and groups looked up, sorted like
+/// `named_entries`.
+fn resolve(entries: &[AclEntry]) -> io::Result<Vec<AclEntry>> {
+    let mut entries = entries
+        .iter()
+        .map(AclEntry::resolve)
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// returns the named entries of the access and default ACLs of `path`,
+/// sorted.
+pub fn named_entries(path: &Path) -> io::Result<Vec<AclEntry>> {
+    let mut entries = Vec::new();
+    for (default, attr) in [(false, ACCESS), (true, DEFAULT)] {
+        for (tag, perms, id) in read(path, attr)? {
+            let qualifier = match tag {
+                USER => Qualifier::User(id),
+                GROUP => Qualifier::Group(id),
+                _ => continue,
+            };
+            entries.push(AclEntry {
+                default,
+                qualifier,
+                perms,
+            });
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// replaces the named entries of the ACLs of `path` with `entries`. The
+/// default ACL is removed if `entries` has no default entries.
+pub fn apply(path: &Path, entries: &[AclEntry]) -> io::Result<()> {
+    let entries = &resolve(entries)?;
+    let mode = fs::metadata(path)?.permissions().mode();
+    let base = |tag: u16, shift: u32| {
+        let perms = u16::try_from((mode >> shift) & 0o7).unwrap_or_default();
+        (tag, perms, UNDEFINED_ID)
+    };
+    let current = read(path, ACCESS)?;
+    let find = |tag| current.iter().copied().find(|entry| entry.0 == tag);
+    let user_obj = find(USER_OBJ).unwrap_or_else(|| base(USER_OBJ, 6));
+    let group_obj = find(GROUP_OBJ).unwrap_or_else(|| base(GROUP_OBJ, 3));
+    let other = find(OTHER).unwrap_or_else(|| base(OTHER, 0));
+
+    let access = entries.iter().filter(|e| !e.default).collect::<Vec<_>>();
+    let acl = if access.is_empty() {
+        vec![user_obj, group_obj, other]
+    } else {
+        let mask = access_mask(group_obj.1, entries);
+        with_named(
+            user_obj,
+            group_obj,
+            &access,
+            (MASK, mask, UNDEFINED_ID),
+            other,
+        )
+    };
+    xattr::set(path, ACCESS, &encode(&acl))?;
+
+    let default = entries.iter().filter(|e| e.default).collect::<Vec<_>>();
+    if default.is_empty() {
+        if xattr::get(path, DEFAULT)?.is_some() {
+            xattr::remove(path, DEFAULT)?;
+        }
+        return Ok(());
+    }
+    let current = read(path, DEFAULT)?;
+    let find = |tag, fallback| {
+        current
+            .iter()
+            .copied()
+            .find(|entry| entry.0 == tag)
+            .unwrap_or(fallback)
+    };
+    let group_obj = find(GROUP_OBJ, group_obj);
+    let mask = default.iter().fold(group_obj.1, |mask, e| mask | e.perms);
+    let acl = with_named(
+        find(USER_OBJ, user_obj),
+        group_obj,
+        &default,
+        (MASK, mask, UNDEFINED_ID),
+        find(OTHER, other),
+    );
+    xattr::set(path, DEFAULT, &encode(&acl))
+}
+
+/// returns whether the ACLs of `path` hold exactly the named `entries`,
+/// with the access mask `apply` computes for them.
+pub fn is_applied(path: &Path, entries: &[AclEntry]) -> io::Result<bool> {
+    let entries = &resolve(entries)?;
+    if named_entries(path)? != *entries {
+        return Ok(false);
+    }
+    let access = read(path, ACCESS)?;
+    let perms = |tag| {
+        access
+            .iter()
+            .find(|entry| entry.0 == tag)
+            .map(|entry| entry.1)
+    };
+    Ok(match (perms(GROUP_OBJ), perms(MASK)) {
+        (Some(group), Some(mask)) => mask == access_mask(group, entries),
+        _ => true,
+    })
+}
+
+/// returns the permissions the ACL mask of `path` grants for `entries`
+/// besides the owning group, as group bits of a mode. 0 while `path` has no
+/// access ACL, whose mask the group bits would show.
+pub fn mask_bits(path: &Path, entries: &[AclEntry]) -> u32 {
+    let has_mask = read(path, ACCESS).is_ok_and(|acl| acl.iter().any(|entry| entry.0 == MASK));
+    if has_mask {
+        u32::from(access_mask(0, entries)) << 3
+    } else {
+        0
+    }
+}
+
+/// returns the access mask for the owning group permissions `group` and the
+/// named access entries of `entries`.
+fn access_mask(group: u16, entries: &[AclEntry]) -> u16 {
+    entries
+        .iter()
+        .filter(|entry| !entry.default)
+        .fold(group, |mask, entry| mask | entry.perms)
+}
+
+type RawEntry = (u16, u16, u32);
+
+fn with_named(
+    user_obj: RawEntry,
+    group_obj: RawEntry,
+    named: &[&AclEntry],
+    mask: RawEntry,
+    other: RawEntry,
+) -> Vec<RawEntry> {
+    let named = |want_user: bool| {
+        named.iter().filter_map(move |entry| match entry.qualifier {
+            Qualifier::User(uid) if want_user => Some((USER, entry.perms, uid)),
+            Qualifier::Group(gid) if !want_user => Some((GROUP, entry.perms, gid)),
+            _ => None,
+        })
+    };
+    std::iter::once(user_obj)
+        .chain(named(true))
+        .chain([group_obj])
+        .chain(named(false))
+        .chain([mask, other])
+        .collect()
+}
+
+/// returns the raw entries of the ACL kept in `attr`, empty if there is none.
+fn read(path: &Path, attr: &str) -> io::Result<Vec<RawEntry>> {
+    let Some(value) = xattr::get(path, attr)? else {
+        return Ok(Vec::new());
+    };
+    Ok(value
+        .get(4..)
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .map(|chunk| {
+            (
+                u16::from_le_bytes([chunk[0], chunk[1]]),
+                u16::from_le_bytes([chunk[2], chunk[3]]),
+                u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+            )
+        })
+        .collect())
+}
+
+fn encode(entries: &[RawEntry]) -> Vec<u8> {
+    let mut value = VERSION.to_le_bytes().to_vec();
+    for (tag, perms, id) in entries {
+        value.extend(tag.to_le_bytes());
+        value.extend(perms.to_le_bytes());
+        value.extend(id.to_le_bytes());
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_accepts_setfacl_entries() {
+        let root = AclEntry::parse("u:0:rw-").unwrap();
+        assert_eq!(root.qualifier, Qualifier::User(0));
+        assert_eq!(root.perms, 6);
+        assert!(!root.default);
+
+        let default = AclEntry::parse("default:group:0:rx").unwrap();
+        assert_eq!(default.qualifier, Qualifier::Group(0));
+        assert_eq!(default.perms, 5);
+        assert!(default.default);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_entries() {
+        for entry in ["g:0", "m::rwx", "g:0:rwq", "g::r", "x:u:0:r"] {
+            assert_eq!(AclEntry::parse(entry), None, "{entry}");
+        }
+    }
+
+    #[test]
+    fn parse_keeps_unknown_accounts_by_name() {
+        let entry = AclEntry::parse("g:pets-no-such-group:r").unwrap();
+        assert_eq!(
+            entry.unknown_account(),
+            Some(("group", "pets-no-such-group"))
+        );
+        assert_eq!(entry.to_string(), "g:pets-no-such-group:r--");
+        assert_eq!(entry.resolve().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            AclEntry::parse("u:root:rw").unwrap().unknown_account(),
+            None
+        );
+    }
+
+    #[test]
+    fn access_mask_is_union_of_group_and_named_entries() {
+        let entries = [
+            AclEntry::parse("g:0:r-x").unwrap(),
+            AclEntry::parse("u:0:rw").unwrap(),
+            AclEntry::parse("d:g:0:rwx").unwrap(),
+        ];
+        assert_eq!(access_mask(4, &entries), 7);
+        assert_eq!(access_mask(0, &entries[..1]), 5);
+        assert_eq!(access_mask(4, &entries[2..]), 4);
+    }
+
+    #[test]
+    fn encode_orders_entries_by_tag() {
+        let entries = [
+            AclEntry::parse("g:0:r").unwrap(),
+            AclEntry::parse("u:0:rw").unwrap(),
+        ];
+        let refs = entries.iter().collect::<Vec<_>>();
+        let acl = with_named(
+            (USER_OBJ, 6, UNDEFINED_ID),
+            (GROUP_OBJ, 4, UNDEFINED_ID),
+            &refs,
+            (MASK, 4, UNDEFINED_ID),
+            (OTHER, 0, UNDEFINED_ID),
+        );
+        let tags = acl.iter().map(|entry| entry.0).collect::<Vec<_>>();
+        assert_eq!(tags, [USER_OBJ, USER, GROUP_OBJ, GROUP, MASK, OTHER]);
+        assert_eq!(encode(&acl).len(), 4 + 6 * 8);
+    }
+}
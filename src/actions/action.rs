@@ -1,30 +1,76 @@
-use super::{ActionError, Cause};
-use similar::TextDiff;
+use super::{
+    ActionError, BackupStore, Cause, acl,
+    acl::AclEntry,
+    diff::{Content, content_diff},
+    merge::merge3,
+};
+use crate::state::merged_source_path;
+use serde_json::{Value, json};
 use std::{
     fmt, fs, io,
     os::unix::fs as unix_fs,
-    os::unix::fs::PermissionsExt,
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
     path::{Path, PathBuf},
     process::{Command, Stdio},
 };
+use walkdir::WalkDir;
 
 pub struct RunConfig {
     pub dry_run: bool,
-    pub backup: bool,
+    // Files larger than this many bytes are not diffed in dry-run mode
+    pub diff_limit: u64,
+    // Where to back up what is overwritten or removed, if anywhere
+    pub backup: Option<BackupStore>,
 }
 
 /// The underlying filesystem or system operation to perform.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Operation {
-    /// Copy a file (or directory) from source to dest.
-    Copy { source: PathBuf, dest: PathBuf },
+    /// Copy a file (or directory) from source to dest, remembering the
+    /// deployed content at `deployed` if set. A file gets `mode`, `uid` and
+    /// `gid` before it is renamed into place. The files of a directory get
+    /// `uid`, `gid` and their mode in `file_modes` the same way.
+    Copy {
+        source: PathBuf,
+        dest: PathBuf,
+        deployed: Option<PathBuf>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        file_modes: Vec<(PathBuf, u32)>,
+    },
+    /// Three-way merge source into dest, using the source last deployed as
+    /// common ancestor. `base` holds the content last deployed and becomes
+    /// the merged content.
+    Merge {
+        source: PathBuf,
+        dest: PathBuf,
+        base: PathBuf,
+    },
     /// Create a symbolic link at `dest` pointing to `source`.
     Symlink { source: PathBuf, dest: PathBuf },
+    /// Replace whatever is at `dest` with a symbolic link pointing to `source`.
+    ReplaceWithSymlink { source: PathBuf, dest: PathBuf },
+    /// Remove a file, symbolic link or directory tree.
+    Remove { path: PathBuf },
     /// Create a directory and its parents.
     CreateDir { path: PathBuf },
     /// Set file permissions (octal mode).
     Chmod { path: PathBuf, mode: u32 },
+    /// Replace the named user and group entries of the ACLs of `path`.
+    SetAcl {
+        path: PathBuf,
+        entries: Vec<AclEntry>,
+    },
+    /// Set the extended attribute `name` of `path` to `value`.
+    SetXattr {
+        path: PathBuf,
+        name: String,
+        value: String,
+    },
     /// Change file ownership, falling back to `sudo chown` on permission errors.
+    /// Without `uid` and `gid` the names in `display_arg` are looked up when
+    /// performing, for accounts created earlier in the same run.
     Chown {
         path: PathBuf,
         uid: Option<u32>,
@@ -43,14 +89,27 @@ pub enum Operation {
 impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::Copy { source, dest } => {
+            Self::Copy { source, dest, .. } => {
                 write!(f, "cp {} {}", source.display(), dest.display())
             }
+            Self::Merge { source, dest, .. } => {
+                write!(f, "merge {} into {}", source.display(), dest.display())
+            }
             Self::Symlink { source, dest } => {
                 write!(f, "ln -s {} {}", source.display(), dest.display())
             }
+            Self::ReplaceWithSymlink { source, dest } => {
+                write!(f, "ln -sfn {} {}", source.display(), dest.display())
+            }
+            Self::Remove { path } => write!(f, "rm -rf {}", path.display()),
             Self::CreateDir { path } => write!(f, "mkdir -p {}", path.display()),
             Self::Chmod { path, mode } => write!(f, "chmod {mode:o} {}", path.display()),
+            Self::SetAcl { path, entries } => {
+                write!(f, "set ACL {} on {}", acl_arg(entries), path.display())
+            }
+            Self::SetXattr { path, name, value } => {
+                write!(f, "setfattr -n {name} -v {value} {}", path.display())
+            }
             Self::Chown {
                 path,
                 display_arg,
@@ -81,6 +140,10 @@ impl fmt::Display for Operation {
 pub struct Action {
     cause: Cause,
     operation: Operation,
+    // Performed by the root helper when running with `--become`
+    privileged: bool,
+    // What `--check --no-exec` did not run that could still prevent it
+    gate: Option<String>,
 }
 
 impl fmt::Display for Action {
@@ -94,23 +157,115 @@ impl Action {
         self.cause
     }
 
+    pub(super) fn operation(&self) -> &Operation {
+        &self.operation
+    }
+
     pub fn copy_file(cause: Cause, source: PathBuf, dest: PathBuf) -> Self {
         Self {
             cause,
-            operation: Operation::Copy { source, dest },
+            privileged: false,
+            gate: None,
+            operation: Operation::Copy {
+                source,
+                dest,
+                deployed: None,
+                mode: None,
+                uid: None,
+                gid: None,
+                file_modes: Vec::new(),
+            },
+        }
+    }
+
+    pub fn merge(cause: Cause, source: PathBuf, dest: PathBuf, base: PathBuf) -> Self {
+        Self {
+            cause,
+            privileged: false,
+            gate: None,
+            operation: Operation::Merge { source, dest, base },
+        }
+    }
+
+    /// remembers the copied content at `path` once deployed.
+    pub fn record_deployed(mut self, path: PathBuf) -> Self {
+        match &mut self.operation {
+            Operation::Copy { deployed, .. } => *deployed = Some(path),
+            _ => log::warn!("record_deployed called on operation that doesn't support it"),
         }
+        self
+    }
+
+    /// takes the final mode and ownership of a copied file, or the files of
+    /// a copied tree, from the chmod and chown `attributes` of the same paths,
+    /// so no file ever exists with other permissions at its destination.
+    /// Other operations are returned unchanged.
+    pub fn with_attributes(mut self, attributes: &[Action]) -> Self {
+        let Operation::Copy {
+            dest,
+            mode,
+            uid,
+            gid,
+            file_modes,
+            ..
+        } = &mut self.operation
+        else {
+            return self;
+        };
+        for action in attributes {
+            match &action.operation {
+                Operation::Chmod { path, mode: want } if path == dest => *mode = Some(*want),
+                Operation::Chmod { path, mode: want } if path.starts_with(&*dest) => {
+                    file_modes.push((path.clone(), *want));
+                }
+                // A tree is owned by the same user and group throughout.
+                Operation::Chown {
+                    path,
+                    uid: owner,
+                    gid: group,
+                    ..
+                } if path.starts_with(&*dest) => {
+                    *uid = *owner;
+                    *gid = *group;
+                }
+                _ => {}
+            }
+        }
+        self
     }
 
     pub fn symlink(cause: Cause, source: PathBuf, dest: PathBuf) -> Self {
         Self {
             cause,
+            privileged: false,
+            gate: None,
             operation: Operation::Symlink { source, dest },
         }
     }
 
+    pub fn replace_with_symlink(cause: Cause, source: PathBuf, dest: PathBuf) -> Self {
+        Self {
+            cause,
+            privileged: false,
+            gate: None,
+            operation: Operation::ReplaceWithSymlink { source, dest },
+        }
+    }
+
+    pub fn remove(cause: Cause, path: PathBuf) -> Self {
+        Self {
+            cause,
+            privileged: false,
+            gate: None,
+            operation: Operation::Remove { path },
+        }
+    }
+
     pub fn create_dir(cause: Cause, path: PathBuf) -> Self {
         Self {
             cause,
+            privileged: false,
+            gate: None,
             operation: Operation::CreateDir { path },
         }
     }
@@ -118,10 +273,30 @@ impl Action {
     pub fn chmod(cause: Cause, path: PathBuf, mode: u32) -> Self {
         Self {
             cause,
+            privileged: false,
+            gate: None,
             operation: Operation::Chmod { path, mode },
         }
     }
 
+    pub fn set_acl(cause: Cause, path: PathBuf, entries: Vec<AclEntry>) -> Self {
+        Self {
+            cause,
+            privileged: false,
+            gate: None,
+            operation: Operation::SetAcl { path, entries },
+        }
+    }
+
+    pub fn set_xattr(cause: Cause, path: PathBuf, name: String, value: String) -> Self {
+        Self {
+            cause,
+            privileged: false,
+            gate: None,
+            operation: Operation::SetXattr { path, name, value },
+        }
+    }
+
     pub fn chown(
         cause: Cause,
         path: PathBuf,
@@ -131,6 +306,8 @@ impl Action {
     ) -> Self {
         Self {
             cause,
+            privileged: false,
+            gate: None,
             operation: Operation::Chown {
                 path,
                 uid,
@@ -144,6 +321,8 @@ impl Action {
     pub fn command(cause: Cause, args: Vec<String>) -> Self {
         Self {
             cause,
+            privileged: false,
+            gate: None,
             operation: Operation::Command {
                 args,
                 requires_sudo: false,
@@ -154,6 +333,8 @@ impl Action {
     pub fn command_with_sudo(cause: Cause, args: Vec<String>) -> Self {
         Self {
             cause,
+            privileged: false,
+            gate: None,
             operation: Operation::Command {
                 args,
                 requires_sudo: true,
@@ -161,6 +342,193 @@ impl Action {
         }
     }
 
+    /// marks the action as needing root, see `is_privileged`.
+    pub fn privileged(mut self) -> Self {
+        self.privileged = true;
+        self
+    }
+
+    /// marks the action as depending on `gate`, a `pre` command or `when`
+    /// condition that was not run.
+    pub fn gated(mut self, gate: String) -> Self {
+        self.gate = Some(gate);
+        self
+    }
+
+    /// returns what was not run that could still prevent the action.
+    pub fn gate(&self) -> Option<&str> {
+        self.gate.as_deref()
+    }
+
+    /// returns true for actions of system-scope files, package installs and
+    /// account creation, which `--become` hands to the root helper.
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+
+    /// returns the action as JSON for the root helper. Whether to use sudo
+    /// is left out, the helper runs as root already.
+    pub fn to_json(&self) -> Value {
+        let path = |path: &Path| path.to_string_lossy().into_owned();
+        let operation = match &self.operation {
+            Operation::Copy {
+                source,
+                dest,
+                deployed,
+                mode,
+                uid,
+                gid,
+                file_modes,
+            } => json!({
+                "op": "copy",
+                "source": path(source),
+                "dest": path(dest),
+                "deployed": deployed.as_deref().map(path),
+                "mode": mode,
+                "uid": uid,
+                "gid": gid,
+                "file_modes": file_modes
+                    .iter()
+                    .map(|(file, mode)| json!([path(file), mode]))
+                    .collect::<Vec<_>>(),
+            }),
+            Operation::Merge { source, dest, base } => json!({
+                "op": "merge",
+                "source": path(source),
+                "dest": path(dest),
+                "base": path(base),
+            }),
+            Operation::Symlink { source, dest } => {
+                json!({"op": "symlink", "source": path(source), "dest": path(dest)})
+            }
+            Operation::ReplaceWithSymlink { source, dest } => json!({
+                "op": "replace_with_symlink",
+                "source": path(source),
+                "dest": path(dest),
+            }),
+            Operation::Remove { path: p } => json!({"op": "remove", "path": path(p)}),
+            Operation::CreateDir { path: p } => json!({"op": "create_dir", "path": path(p)}),
+            Operation::Chmod { path: p, mode } => {
+                json!({"op": "chmod", "path": path(p), "mode": mode})
+            }
+            Operation::SetAcl { path: p, entries } => json!({
+                "op": "set_acl",
+                "path": path(p),
+                "entries": entries.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            }),
+            Operation::SetXattr {
+                path: p,
+                name,
+                value,
+            } => json!({"op": "set_xattr", "path": path(p), "name": name, "value": value}),
+            Operation::Chown {
+                path: p,
+                uid,
+                gid,
+                display_arg,
+                ..
+            } => json!({
+                "op": "chown",
+                "path": path(p),
+                "uid": uid,
+                "gid": gid,
+                "display_arg": display_arg,
+            }),
+            Operation::Command { args, .. } => json!({"op": "command", "args": args}),
+        };
+        json!({"cause": format!("{:?}", self.cause), "operation": operation})
+    }
+
+    /// parses an action sent to the root helper, see `to_json`.
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let cause = Cause::from_name(value.get("cause")?.as_str()?)?;
+        let op = value.get("operation")?;
+        let string = |key: &str| op.get(key)?.as_str().map(ToString::to_string);
+        let path = |key: &str| string(key).map(PathBuf::from);
+        let id = |key: &str| op.get(key)?.as_u64().and_then(|id| u32::try_from(id).ok());
+        let strings = |key: &str| {
+            op.get(key)?
+                .as_array()?
+                .iter()
+                .map(|s| s.as_str().map(ToString::to_string))
+                .collect::<Option<Vec<_>>>()
+        };
+
+        let operation = match op.get("op")?.as_str()? {
+            "copy" => Operation::Copy {
+                source: path("source")?,
+                dest: path("dest")?,
+                deployed: path("deployed"),
+                mode: id("mode"),
+                uid: id("uid"),
+                gid: id("gid"),
+                file_modes: op
+                    .get("file_modes")?
+                    .as_array()?
+                    .iter()
+                    .map(|entry| {
+                        let file = entry.get(0)?.as_str()?;
+                        let mode = u32::try_from(entry.get(1)?.as_u64()?).ok()?;
+                        Some((PathBuf::from(file), mode))
+                    })
+                    .collect::<Option<_>>()?,
+            },
+            "merge" => Operation::Merge {
+                source: path("source")?,
+                dest: path("dest")?,
+                base: path("base")?,
+            },
+            "symlink" => Operation::Symlink {
+                source: path("source")?,
+                dest: path("dest")?,
+            },
+            "replace_with_symlink" => Operation::ReplaceWithSymlink {
+                source: path("source")?,
+                dest: path("dest")?,
+            },
+            "remove" => Operation::Remove {
+                path: path("path")?,
+            },
+            "create_dir" => Operation::CreateDir {
+                path: path("path")?,
+            },
+            "chmod" => Operation::Chmod {
+                path: path("path")?,
+                mode: id("mode")?,
+            },
+            "set_acl" => Operation::SetAcl {
+                path: path("path")?,
+                entries: strings("entries")?
+                    .iter()
+                    .map(|entry| AclEntry::parse(entry))
+                    .collect::<Option<_>>()?,
+            },
+            "set_xattr" => Operation::SetXattr {
+                path: path("path")?,
+                name: string("name")?,
+                value: string("value")?,
+            },
+            "chown" => Operation::Chown {
+                path: path("path")?,
+                uid: id("uid"),
+                gid: id("gid"),
+                display_arg: string("display_arg")?,
+                force_sudo: false,
+            },
+            "command" => Operation::Command {
+                args: strings("args")?,
+                requires_sudo: false,
+            },
+            _ => return None,
+        };
+        Some(Self {
+            cause,
+            operation,
+            privileged: true,
+            gate: None,
+        })
+    }
+
     pub fn use_sudo(mut self) -> Self {
         match &mut self.operation {
             Operation::Chown { force_sudo, .. } => *force_sudo = true,
@@ -174,31 +542,69 @@ impl Action {
         log::info!("{}", self.operation);
 
         if config.dry_run {
-            self.log_dry_run_details()?;
+            self.log_dry_run_details(config.diff_limit)?;
             return Ok(0);
         }
 
-        let Action { cause, operation } = self;
+        let Action {
+            cause, operation, ..
+        } = self;
 
         match operation {
-            Operation::Copy { source, dest } => {
-                if config.backup && cause == Cause::Update && !source.is_dir() && dest.exists() {
-                    let backup = backup_path_for(&dest);
-                    fs::copy(&dest, &backup)?;
-                    log::info!("backed up {} to {}", dest.display(), backup.display());
+            Operation::Copy {
+                source,
+                dest,
+                deployed,
+                mode,
+                uid,
+                gid,
+                file_modes,
+            } => {
+                if let Some(store) = &config.backup {
+                    if matches!(cause, Cause::Update | Cause::Drift)
+                        && !source.is_dir()
+                        && dest.exists()
+                    {
+                        store.save(&dest)?;
+                    }
+                }
+                if cause == Cause::Drift {
+                    log::warn!(
+                        "{} has local changes, overwriting them with {} (use `pets pull` to bring them into the repo first)",
+                        dest.display(),
+                        source.display()
+                    );
                 }
 
                 if source.is_dir() {
-                    copy_dir_all(&source, &dest)?;
+                    copy_tree(&source, &dest, &file_modes, uid, gid)?;
                 } else {
-                    atomic_copy(&source, &dest)?;
+                    atomic_copy(&source, &dest, mode, uid, gid)?;
+                }
+                if let Some(deployed) = deployed {
+                    record_deployed(&source, &deployed);
+                    forget_merged_source(&deployed);
                 }
                 Ok(0)
             }
+            Operation::Merge { source, dest, base } => {
+                merge(&source, &dest, &base, config.backup.as_ref())?;
+                Ok(0)
+            }
             Operation::Symlink { source, dest } => {
                 unix_fs::symlink(&source, &dest)?;
                 Ok(0)
             }
+            Operation::ReplaceWithSymlink { source, dest } => {
+                replace_with_symlink(&source, &dest, config.backup.as_ref())?;
+                Ok(0)
+            }
+            Operation::Remove { path } => {
+                // Leftover temporary files are not worth a backup.
+                let backup = config.backup.as_ref().filter(|_| cause != Cause::Cleanup);
+                remove_or_back_up(&path, backup)?;
+                Ok(0)
+            }
             Operation::CreateDir { path } => {
                 fs::create_dir_all(&path)?;
                 Ok(0)
@@ -207,6 +613,14 @@ impl Action {
                 fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
                 Ok(0)
             }
+            Operation::SetAcl { path, entries } => {
+                acl::apply(&path, &entries)?;
+                Ok(0)
+            }
+            Operation::SetXattr { path, name, value } => {
+                xattr::set(&path, &name, value.as_bytes())?;
+                Ok(0)
+            }
             Operation::Chown {
                 path,
                 uid,
@@ -217,14 +631,7 @@ impl Action {
                 if force_sudo {
                     return sudo_chown(&display_arg, &path);
                 }
-                match unix_fs::chown(&path, uid, gid) {
-                    Ok(()) => Ok(0),
-                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                        log::info!("chown requires elevated privileges, retrying with sudo");
-                        sudo_chown(&display_arg, &path)
-                    }
-                    Err(e) => Err(e.into()),
-                }
+                chown(&path, uid, gid, &display_arg)
             }
             Operation::Command {
                 args,
@@ -233,13 +640,45 @@ impl Action {
         }
     }
 
-    fn log_dry_run_details(&self) -> Result<(), ActionError> {
+    fn log_dry_run_details(&self, diff_limit: u64) -> Result<(), ActionError> {
         match (&self.cause, &self.operation) {
             (Cause::Create, Operation::Copy { source, .. }) => {
                 log::info!("new file: {}", source.display());
             }
-            (Cause::Update, Operation::Copy { source, dest }) => {
-                log_unified_diff(source, dest)?;
+            (Cause::Update | Cause::Drift, Operation::Copy { source, dest, .. }) => {
+                let (from, to) = (dest.display().to_string(), source.display().to_string());
+                let (old, new) = (Content::File(dest.clone()), Content::File(source.clone()));
+                for line in content_diff(&from, &to, Some(&old), Some(&new), diff_limit)? {
+                    log::info!("{line}");
+                }
+            }
+            (Cause::Merge, Operation::Merge { source, dest, base }) => {
+                let merged = Content::Text(merged_content(source, dest, base)?);
+                let from = dest.display().to_string();
+                let current = Content::File(dest.clone());
+                for line in content_diff(&from, &from, Some(&current), Some(&merged), diff_limit)? {
+                    log::info!("{line}");
+                }
+            }
+            (Cause::Replace, Operation::ReplaceWithSymlink { dest, .. }) => {
+                log::info!("would replace existing {}", dest.display());
+            }
+            (Cause::Remove, Operation::Remove { path }) => {
+                log::info!("would remove {}", path.display());
+            }
+            (Cause::Acl, Operation::SetAcl { path, entries }) => {
+                let current = acl::named_entries(path).unwrap_or_default();
+                log::info!("current ACL: {}", acl_arg(&current));
+                log::info!("desired ACL: {}", acl_arg(entries));
+            }
+            (Cause::Xattr, Operation::SetXattr { path, name, value }) => {
+                match xattr::get(path, name).ok().flatten() {
+                    Some(current) => {
+                        log::info!("current {name}: {}", String::from_utf8_lossy(&current));
+                    }
+                    None => log::info!("current {name}: (unset)"),
+                }
+                log::info!("desired {name}: {value}");
             }
             _ => {}
         }
@@ -305,31 +744,110 @@ fn run_command(args: &[String], requires_sudo: bool, cause: Cause) -> Result<i32
     Ok(status)
 }
 
-fn backup_path_for(dest: &Path) -> PathBuf {
-    PathBuf::from(format!("{}.pets-backup", dest.to_string_lossy()))
+/// merges `source` into `dest`, backing up `dest` first, and records the
+/// merged content at `base` as deployed.
+fn merge(
+    source: &Path,
+    dest: &Path,
+    base: &Path,
+    backup: Option<&BackupStore>,
+) -> Result<(), ActionError> {
+    let merged = merged_content(source, dest, base)?;
+    if let Some(store) = backup {
+        store.save(dest)?;
+    }
+    atomic_write(dest, &merged)?;
+    log::warn!(
+        "{} has local changes, keeping them merged with the changes to {}",
+        dest.display(),
+        source.display()
+    );
+    record_deployed(dest, base);
+    record_deployed(source, &merged_source_path(base));
+    Ok(())
 }
 
-fn log_unified_diff(source: &Path, dest: &Path) -> Result<(), ActionError> {
-    let source_content = read_text_file(source)?;
-    let dest_content = read_text_file(dest)?;
-
-    match (source_content, dest_content) {
-        (Some(source_text), Some(dest_text)) => {
-            let from = dest.display().to_string();
-            let to = source.display().to_string();
-            let diff = TextDiff::from_lines(&dest_text, &source_text)
-                .unified_diff()
-                .header(&from, &to)
-                .to_string();
-
-            for line in diff.lines() {
-                log::info!("{line}");
-            }
-        }
-        _ => log::info!("binary file differs"),
+/// merges the changes made to `source` since it was deployed into `dest`.
+/// The common ancestor is the source last merged, if local changes were
+/// merged before, or else the content last deployed at `base`.
+pub(super) fn merged_content(
+    source: &Path,
+    dest: &Path,
+    base: &Path,
+) -> Result<String, ActionError> {
+    let conflict = || ActionError::MergeConflict(dest.display().to_string());
+    let read = |path: &Path| read_text_file(path)?.ok_or_else(conflict);
+    let merged_source = merged_source_path(base);
+    let ancestor = if merged_source.is_file() {
+        merged_source
+    } else {
+        base.to_path_buf()
+    };
+    merge3(&read(&ancestor)?, &read(dest)?, &read(source)?).ok_or_else(conflict)
+}
+
+/// keeps a copy of the deployed `source` at `deployed`. Failing to do so only
+/// means the next run cannot tell local edits apart, so it is not an error.
+fn record_deployed(source: &Path, deployed: &Path) {
+    // The root helper records into the state directory of the invoking user,
+    // so what it creates there belongs to the owner of that directory.
+    let owner = deployed
+        .ancestors()
+        .skip(1)
+        .find_map(|dir| fs::metadata(dir).ok())
+        .map(|metadata| (metadata.uid(), metadata.gid()))
+        .filter(|&(uid, _)| uid != 0 && uzers::get_effective_uid() == 0);
+    let created = deployed
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| !dir.exists())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    let result = deployed
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::copy(source, deployed))
+        .and_then(|_| match owner {
+            Some((uid, gid)) => created
+                .iter()
+                .map(PathBuf::as_path)
+                .chain([deployed])
+                .try_for_each(|path| unix_fs::chown(path, Some(uid), Some(gid))),
+            None => Ok(()),
+        });
+    if let Err(err) = result {
+        log::warn!(
+            "cannot record deployed content at {}: {err}",
+            deployed.display()
+        );
     }
+}
 
-    Ok(())
+/// drops the merged source kept for `deployed`, once the source itself is
+/// deployed again.
+fn forget_merged_source(deployed: &Path) {
+    let merged_source = merged_source_path(deployed);
+    match fs::remove_file(&merged_source) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::warn!("cannot remove {}: {err}", merged_source.display()),
+    }
+}
+
+/// returns ACL entries the way setfacl(1) takes them, eg: 'u:deploy:rw-,g:adm:r--'.
+pub(super) fn acl_arg(entries: &[AclEntry]) -> String {
+    if entries.is_empty() {
+        return "(none)".to_string();
+    }
+    entries
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn tmp_path_for(dest: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.pets-tmp", dest.to_string_lossy()))
 }
 
 fn read_text_file(path: &Path) -> Result<Option<String>, ActionError> {
@@ -341,6 +859,50 @@ fn read_text_file(path: &Path) -> Result<Option<String>, ActionError> {
 }
 
 /// Falls back to `sudo chown` when native chown fails with permission denied.
+fn chown(
+    path: &Path,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    display_arg: &str,
+) -> Result<i32, ActionError> {
+    let (uid, gid) = if uid.is_none() && gid.is_none() {
+        lookup_ids(display_arg)?
+    } else {
+        (uid, gid)
+    };
+    match unix_fs::chown(path, uid, gid) {
+        Ok(()) => Ok(0),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            log::info!("chown requires elevated privileges, retrying with sudo");
+            sudo_chown(display_arg, path)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// returns the ids of the user and group in a chown argument like 'root:staff'.
+fn lookup_ids(arg: &str) -> Result<(Option<u32>, Option<u32>), ActionError> {
+    let (user, group) = arg.split_once(':').unwrap_or((arg, ""));
+    let unknown = |name: &str| ActionError::UnknownAccount(name.to_string());
+    let uid = match user {
+        "" => None,
+        user => Some(
+            uzers::get_user_by_name(user)
+                .ok_or_else(|| unknown(user))?
+                .uid(),
+        ),
+    };
+    let gid = match group {
+        "" => None,
+        group => Some(
+            uzers::get_group_by_name(group)
+                .ok_or_else(|| unknown(group))?
+                .gid(),
+        ),
+    };
+    Ok((uid, gid))
+}
+
 fn sudo_chown(arg: &str, path: &Path) -> Result<i32, ActionError> {
     let output = Command::new("sudo")
         .args(["chown", arg, &path.to_string_lossy()])
@@ -362,8 +924,42 @@ fn sudo_chown(arg: &str, path: &Path) -> Result<i32, ActionError> {
     }
 }
 
+/// Puts a symbolic link to `source` at `dest`. An existing link is swapped
+/// atomically; any other file or directory is moved to the backup store
+/// first, or removed when backups are disabled.
+fn replace_with_symlink(
+    source: &Path,
+    dest: &Path,
+    backup: Option<&BackupStore>,
+) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dest)?;
+    if metadata.file_type().is_symlink() {
+        let tmp_path = tmp_path_for(dest);
+        let _ = fs::remove_file(&tmp_path);
+        unix_fs::symlink(source, &tmp_path)?;
+        return fs::rename(&tmp_path, dest);
+    }
+
+    remove_or_back_up(dest, backup)?;
+    unix_fs::symlink(source, dest)
+}
+
+/// Gets `path` out of the way: it is moved to the backup store, or removed
+/// when backups are disabled. Symbolic links are never followed.
+fn remove_or_back_up(path: &Path, backup: Option<&BackupStore>) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if let Some(store) = backup {
+        store.move_in(path)?;
+        Ok(())
+    } else if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
 /// Recursively copies a directory tree from `src` to `dst`.
-fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
+pub(super) fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
     fs::create_dir_all(dst)?;
     for entry in fs::read_dir(src)? {
         let entry = entry?;
@@ -377,27 +973,191 @@ fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
     Ok(())
 }
 
-/// Copies `source` to `dest` atomically by writing to a temporary file in the
-/// same directory, then renaming. If the rename fails (e.g. cross-device), falls
-/// back to a direct copy.
-fn atomic_copy(source: &Path, dest: &Path) -> io::Result<()> {
-    let tmp_path = PathBuf::from(format!("{}.pets-tmp", dest.to_string_lossy()));
-    fs::copy(source, &tmp_path)?;
-    if fs::rename(&tmp_path, dest).is_err() {
-        let _ = fs::remove_file(&tmp_path);
-        fs::copy(source, dest)?;
+/// Copies the tree `source` into `dest`. Every file is copied like
+/// `atomic_copy`, with `uid`, `gid` and its mode in `file_modes` if any.
+fn copy_tree(
+    source: &Path,
+    dest: &Path,
+    file_modes: &[(PathBuf, u32)],
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in WalkDir::new(source).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let target = dest.join(entry.path().strip_prefix(source).unwrap_or(entry.path()));
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            let mode = file_modes
+                .iter()
+                .find(|(file, _)| *file == target)
+                .map(|(_, mode)| *mode);
+            atomic_copy(entry.path(), &target, mode, uid, gid)?;
+        }
     }
     Ok(())
 }
 
+/// Replaces `dest` with `content` atomically, keeping its mode and ownership.
+fn atomic_write(dest: &Path, content: &str) -> io::Result<()> {
+    let metadata = fs::metadata(dest)?;
+    write_securely(
+        dest,
+        &mut content.as_bytes(),
+        metadata.mode() & 0o7777,
+        Some(metadata.uid()),
+        Some(metadata.gid()),
+    )
+}
+
+/// Copies `source` to `dest` atomically. The copy gets `mode`, `uid` and
+/// `gid` if set, or else keeps those of an existing `dest`. A new file
+/// without `mode` gets the permissions of `source`.
+fn atomic_copy(
+    source: &Path,
+    dest: &Path,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    let current = fs::metadata(dest).ok();
+    let mode = match (mode, &current) {
+        (Some(mode), _) => mode,
+        (None, Some(current)) => current.mode(),
+        (None, None) => fs::metadata(source)?.mode(),
+    };
+    write_securely(
+        dest,
+        &mut fs::File::open(source)?,
+        mode & 0o7777,
+        uid.or_else(|| current.as_ref().map(MetadataExt::uid)),
+        gid.or_else(|| current.as_ref().map(MetadataExt::gid)),
+    )
+}
+
+/// Writes `content` to a temporary file next to `dest` and renames it over
+/// `dest`. The temporary file is created exclusively and readable by its
+/// owner only, and gets its final ownership and `mode` before it is synced
+/// and renamed, so `dest` never has other permissions. A leftover from an
+/// interrupted run is replaced. If the temporary file is on another
+/// filesystem than `dest`, it is copied into the real directory of `dest`
+/// first, so `dest` is still replaced atomically and never followed.
+pub fn write_securely(
+    dest: &Path,
+    content: &mut impl io::Read,
+    mode: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    let tmp_path = tmp_path_for(dest);
+    let result = write_temporary(&tmp_path, content, mode, uid, gid).and_then(|()| {
+        match fs::rename(&tmp_path, dest) {
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                log::warn!(
+                    "cannot rename {} over {}: {err}, copying it into its directory first",
+                    tmp_path.display(),
+                    dest.display()
+                );
+                rename_via_real_directory(&tmp_path, dest, mode, uid, gid)
+            }
+            result => result,
+        }?;
+        sync_parent(dest)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// writes `content` to a new file at `tmp_path`, replacing a leftover of an
+/// interrupted run, with its final ownership and `mode`, and syncs it.
+fn write_temporary(
+    tmp_path: &Path,
+    content: &mut impl io::Read,
+    mode: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    match fs::remove_file(tmp_path) {
+        Ok(()) => log::debug!("removed leftover {}", tmp_path.display()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(tmp_path)?;
+    io::copy(content, &mut file)?;
+    match unix_fs::fchown(&file, uid, gid) {
+        Ok(()) => {}
+        // Left to the chown action, which can fall back to sudo.
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            log::debug!("cannot chown {}: {err}", tmp_path.display());
+        }
+        Err(err) => return Err(err),
+    }
+    // After chown, which clears the setuid and setgid bits.
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    file.sync_all()
+}
+
+/// moves `tmp_path` over `dest` through a second temporary file in the
+/// directory `dest` really is in, for when the two are on different
+/// filesystems.
+fn rename_via_real_directory(
+    tmp_path: &Path,
+    dest: &Path,
+    mode: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    let parent = dest
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = dest
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let target = fs::canonicalize(parent)?.join(name);
+    let second = tmp_path_for(&target);
+    let result = write_temporary(&second, &mut fs::File::open(tmp_path)?, mode, uid, gid)
+        .and_then(|()| fs::rename(&second, &target));
+    if result.is_err() {
+        let _ = fs::remove_file(&second);
+    }
+    result?;
+    fs::remove_file(tmp_path)
+}
+
+/// flushes the directory entry of `path` to disk.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::File::open(parent)?.sync_all()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::actions::diff::DEFAULT_DIFF_LIMIT;
     use std::io::Write;
     use tempfile::tempdir;
 
+    fn backup_path_for(dest: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.pets-backup", dest.display()))
+    }
+
     fn run_config(dry_run: bool, backup: bool) -> RunConfig {
-        RunConfig { dry_run, backup }
+        RunConfig {
+            dry_run,
+            diff_limit: DEFAULT_DIFF_LIMIT,
+            backup: backup.then(BackupStore::default),
+        }
     }
 
     #[test]
@@ -435,6 +1195,51 @@ mod tests {
         assert!(command.to_string().contains("echo hello"));
     }
 
+    #[test]
+    fn test_action_json_round_trip_drops_sudo() {
+        let actions = [
+            Action::copy_file(Cause::Update, "/tmp/src".into(), "/tmp/dest".into())
+                .record_deployed("/tmp/deployed".into()),
+            Action::copy_file(Cause::Create, "/tmp/tree".into(), "/tmp/out".into())
+                .with_attributes(&[Action::chmod(Cause::Mode, "/tmp/out/file".into(), 0o600)]),
+            Action::chmod(Cause::Mode, "/etc/file".into(), 0o4755),
+            Action::set_xattr(
+                Cause::Xattr,
+                "/etc/file".into(),
+                "user.origin".to_string(),
+                "pets".to_string(),
+            ),
+            Action::chown(
+                Cause::Owner,
+                "/etc/file".into(),
+                None,
+                Some(0),
+                ":root".to_string(),
+            ),
+            Action::command(
+                Cause::Pkg,
+                vec!["apt-get".to_string(), "install".to_string()],
+            ),
+        ];
+        for action in actions {
+            let decoded = Action::from_json(&action.to_json()).unwrap();
+            assert_eq!(decoded, action.clone().privileged());
+        }
+
+        let sudo = Action::command_with_sudo(Cause::Pkg, vec!["true".to_string()]);
+        let decoded = Action::from_json(&sudo.to_json()).unwrap();
+        assert_eq!(
+            decoded.operation,
+            Operation::Command {
+                args: vec!["true".to_string()],
+                requires_sudo: false,
+            }
+        );
+        assert!(
+            Action::from_json(&json!({"cause": "Mode", "operation": {"op": "chmod"}})).is_none()
+        );
+    }
+
     #[test]
     fn test_action_cause_returns_stored_cause() {
         let action = Action::create_dir(Cause::Dir, PathBuf::from("/tmp/test"));
@@ -486,6 +1291,7 @@ mod tests {
         let dest = tmp.path().join("dest.txt");
         fs::write(&src, "new-content\n").unwrap();
         fs::write(&dest, "old-content\n").unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o600)).unwrap();
 
         let action = Action::copy_file(Cause::Update, src.clone(), dest.clone());
         let config = run_config(false, true);
@@ -494,6 +1300,7 @@ mod tests {
         let backup = backup_path_for(&dest);
         assert_eq!(fs::read_to_string(&dest).unwrap(), "new-content\n");
         assert_eq!(fs::read_to_string(&backup).unwrap(), "old-content\n");
+        assert_eq!(fs::metadata(&backup).unwrap().mode() & 0o7777, 0o600);
     }
 
     #[test]
@@ -511,6 +1318,181 @@ mod tests {
         assert_eq!(link_target, src);
     }
 
+    #[test]
+    fn test_perform_replace_with_symlink_backs_up_existing_file() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("source.txt");
+        let dest = tmp.path().join("dest.txt");
+        fs::write(&src, "managed").unwrap();
+        fs::write(&dest, "local edits").unwrap();
+
+        let action = Action::replace_with_symlink(Cause::Replace, src.clone(), dest.clone());
+        assert_eq!(action.perform(&run_config(false, true)).unwrap(), 0);
+
+        assert_eq!(fs::read_link(&dest).unwrap(), src);
+        assert_eq!(
+            fs::read_to_string(backup_path_for(&dest)).unwrap(),
+            "local edits"
+        );
+    }
+
+    #[test]
+    fn test_perform_replace_with_symlink_removes_directory_without_backup() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("source");
+        let dest = tmp.path().join("dest");
+        fs::create_dir_all(dest.join("nested")).unwrap();
+
+        let action = Action::replace_with_symlink(Cause::Replace, src.clone(), dest.clone());
+        assert_eq!(action.perform(&run_config(false, false)).unwrap(), 0);
+
+        assert_eq!(fs::read_link(&dest).unwrap(), src);
+        assert!(!backup_path_for(&dest).exists());
+    }
+
+    #[test]
+    fn test_perform_replace_with_symlink_repoints_link() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("source.txt");
+        let dest = tmp.path().join("dest.link");
+        unix_fs::symlink(tmp.path().join("elsewhere"), &dest).unwrap();
+
+        let action = Action::replace_with_symlink(Cause::Relink, src.clone(), dest.clone());
+        assert_eq!(action.perform(&run_config(false, true)).unwrap(), 0);
+
+        assert_eq!(fs::read_link(&dest).unwrap(), src);
+        assert!(!backup_path_for(&dest).exists());
+    }
+
+    #[test]
+    fn test_perform_copy_records_deployed_content() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("source.txt");
+        let dest = tmp.path().join("dest.txt");
+        let deployed = tmp.path().join("state/deployed/dest.txt");
+        fs::write(&src, "content\n").unwrap();
+
+        let action = Action::copy_file(Cause::Create, src, dest).record_deployed(deployed.clone());
+        assert_eq!(action.perform(&run_config(false, false)).unwrap(), 0);
+        assert_eq!(fs::read_to_string(deployed).unwrap(), "content\n");
+    }
+
+    #[test]
+    fn test_state_recorded_as_root_belongs_to_the_state_dir_owner() {
+        // Only root records state on behalf of another user.
+        if uzers::get_effective_uid() != 0 {
+            return;
+        }
+        let tmp = tempdir().unwrap();
+        let state = tmp.path().join("state");
+        fs::create_dir(&state).unwrap();
+        unix_fs::chown(&state, Some(65534), Some(65534)).unwrap();
+        let src = tmp.path().join("source.txt");
+        let dest = tmp.path().join("dest.txt");
+        let deployed = state.join("deployed/etc/dest.txt");
+        fs::write(&src, "content\n").unwrap();
+
+        let action =
+            Action::copy_file(Cause::Create, src, dest.clone()).record_deployed(deployed.clone());
+        assert_eq!(action.perform(&run_config(false, false)).unwrap(), 0);
+        for path in [
+            &deployed,
+            &state.join("deployed/etc"),
+            &state.join("deployed"),
+        ] {
+            let metadata = fs::metadata(path).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (65534, 65534), "{path:?}");
+        }
+        assert_eq!(fs::metadata(&dest).unwrap().uid(), 0);
+    }
+
+    #[test]
+    fn test_perform_merge_combines_source_and_local_changes() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("source.txt");
+        let dest = tmp.path().join("dest.txt");
+        let base = tmp.path().join("base.txt");
+        fs::write(&base, "a\nb\nc\n").unwrap();
+        fs::write(&src, "A\nb\nc\n").unwrap();
+        fs::write(&dest, "a\nb\nC\n").unwrap();
+
+        let action = Action::merge(Cause::Merge, src, dest.clone(), base.clone());
+        assert_eq!(action.perform(&run_config(false, true)).unwrap(), 0);
+
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "A\nb\nC\n");
+        assert_eq!(fs::read_to_string(&base).unwrap(), "A\nb\nC\n");
+        assert_eq!(
+            fs::read_to_string(merged_source_path(&base)).unwrap(),
+            "A\nb\nc\n"
+        );
+        assert_eq!(
+            fs::read_to_string(backup_path_for(&dest)).unwrap(),
+            "a\nb\nC\n"
+        );
+    }
+
+    #[test]
+    fn test_perform_merge_conflict_leaves_destination_alone() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("source.txt");
+        let dest = tmp.path().join("dest.txt");
+        let base = tmp.path().join("base.txt");
+        fs::write(&base, "a\n").unwrap();
+        fs::write(&src, "b\n").unwrap();
+        fs::write(&dest, "c\n").unwrap();
+
+        let action = Action::merge(Cause::Merge, src, dest.clone(), base.clone());
+        assert!(matches!(
+            action.perform(&run_config(false, true)),
+            Err(ActionError::MergeConflict(_))
+        ));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "c\n");
+        assert_eq!(fs::read_to_string(&base).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn test_perform_remove_backs_up_file() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("legacy.list");
+        fs::write(&path, "deb http://old").unwrap();
+
+        let action = Action::remove(Cause::Remove, path.clone());
+        assert_eq!(action.perform(&run_config(false, true)).unwrap(), 0);
+
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read_to_string(backup_path_for(&path)).unwrap(),
+            "deb http://old"
+        );
+    }
+
+    #[test]
+    fn test_perform_remove_deletes_directory_and_link_without_backup() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("old.d");
+        let link = tmp.path().join("old.link");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        unix_fs::symlink(&dir, &link).unwrap();
+
+        let config = run_config(false, false);
+        assert_eq!(
+            Action::remove(Cause::Remove, link.clone())
+                .perform(&config)
+                .unwrap(),
+            0
+        );
+        assert!(dir.is_dir());
+        assert_eq!(
+            Action::remove(Cause::Remove, dir.clone())
+                .perform(&config)
+                .unwrap(),
+            0
+        );
+
+        assert!(fs::symlink_metadata(&link).is_err());
+        assert!(!dir.exists());
+    }
+
     #[test]
     fn test_perform_create_dir_creates_directory_tree() {
         let tmp = tempdir().unwrap();
@@ -558,10 +1540,110 @@ mod tests {
         let dest = tmp.path().join("dest.txt");
         fs::write(&src, "atomic").unwrap();
 
-        atomic_copy(&src, &dest).unwrap();
+        atomic_copy(&src, &dest, None, None, None).unwrap();
         assert_eq!(fs::read_to_string(dest).unwrap(), "atomic");
     }
 
+    #[test]
+    fn test_atomic_copy_sets_mode_and_replaces_leftover() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("secret");
+        let dest = tmp.path().join("deployed");
+        fs::write(&src, "token").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(tmp_path_for(&dest), "stale").unwrap();
+
+        atomic_copy(&src, &dest, Some(0o600), None, None).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "token");
+        assert_eq!(fs::metadata(&dest).unwrap().mode() & 0o7777, 0o600);
+        assert!(!tmp_path_for(&dest).exists());
+
+        // Updates keep the mode of the existing file.
+        fs::write(&src, "rotated").unwrap();
+        atomic_copy(&src, &dest, None, None, None).unwrap();
+        assert_eq!(fs::metadata(&dest).unwrap().mode() & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn test_write_securely_returns_rename_errors() {
+        let tmp = tempdir().unwrap();
+        let dest = tmp.path().join("busy");
+        fs::create_dir(&dest).unwrap();
+        fs::write(dest.join("kept"), "kept").unwrap();
+
+        let err = write_securely(&dest, &mut "new".as_bytes(), 0o644, None, None).unwrap_err();
+        assert_ne!(err.kind(), io::ErrorKind::CrossesDevices);
+        assert_eq!(fs::read_to_string(dest.join("kept")).unwrap(), "kept");
+        assert!(!tmp_path_for(&dest).exists());
+    }
+
+    #[test]
+    fn test_rename_via_real_directory_replaces_destination() {
+        let tmp = tempdir().unwrap();
+        let real = tmp.path().join("real");
+        fs::create_dir(&real).unwrap();
+        unix_fs::symlink(&real, tmp.path().join("link")).unwrap();
+        let dest = tmp.path().join("link/app.conf");
+        fs::write(real.join("app.conf"), "old").unwrap();
+        let staged = tmp.path().join("staged");
+        fs::write(&staged, "new").unwrap();
+
+        rename_via_real_directory(&staged, &dest, 0o640, None, None).unwrap();
+        assert_eq!(fs::read_to_string(real.join("app.conf")).unwrap(), "new");
+        assert_eq!(fs::metadata(&dest).unwrap().mode() & 0o7777, 0o640);
+        assert!(!staged.exists());
+        assert!(!tmp_path_for(&real.join("app.conf")).exists());
+    }
+
+    #[test]
+    fn test_with_attributes_takes_mode_and_owner_of_same_path() {
+        let dest = PathBuf::from("/etc/secret");
+        let attributes = [
+            Action::chown(
+                Cause::Owner,
+                dest.clone(),
+                Some(0),
+                Some(42),
+                "root:x".to_string(),
+            ),
+            Action::chmod(Cause::Mode, PathBuf::from("/etc/other"), 0o644),
+            Action::chmod(Cause::Mode, dest.clone(), 0o600),
+        ];
+        let copy = Action::copy_file(Cause::Create, "/src/secret".into(), dest.clone())
+            .with_attributes(&attributes);
+        assert!(matches!(
+            copy.operation,
+            Operation::Copy {
+                mode: Some(0o600),
+                uid: Some(0),
+                gid: Some(42),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_copy_tree_writes_files_with_their_final_mode() {
+        let tmp = tempdir().unwrap();
+        let source = tmp.path().join("src");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("nested/secret"), "key").unwrap();
+        fs::set_permissions(
+            source.join("nested/secret"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        fs::write(source.join("plain"), "text").unwrap();
+        let dest = tmp.path().join("dest");
+        let secret = dest.join("nested/secret");
+
+        copy_tree(&source, &dest, &[(secret.clone(), 0o600)], None, None).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().mode() & 0o7777;
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "key");
+        assert_eq!(mode(&secret), 0o600);
+        assert_eq!(mode(&dest.join("plain")), mode(&source.join("plain")));
+    }
+
     #[test]
     fn test_copy_dir_all_recursively_copies_directory_contents() {
         let tmp = tempdir().unwrap();
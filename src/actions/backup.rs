@@ -0,0 +1,523 @@
+// Backups of what pets overwrites or removes. By default the backup is kept
+// next to the destination as `<dest>.pets-backup` and replaced by the next
+// one. A central store keeps timestamped versions in one directory instead,
+// mirroring the destination path:
+//
+// /var/backups/pets/etc/ssh/sshd_config/20261018T101500.123456Z
+//
+// Backups keep the owner and mode of the original. The retention policy,
+// `keep` versions that are at most `max_age` old, is applied after every
+// backup and by `pets clean-backups`.
+
+use super::action::{copy_dir_all, write_securely};
+use jiff::{Timestamp, civil::DateTime, tz::TimeZone};
+use std::{
+    cmp::Reverse,
+    fs, io,
+    os::unix::fs::{self as unix_fs, DirBuilderExt, MetadataExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+const VERSION_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Location {
+    /// `<dest>.pets-backup` next to the destination.
+    #[default]
+    Sibling,
+    /// Timestamped versions below a directory.
+    Central(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupStore {
+    location: Location,
+    keep: Option<usize>,
+    max_age: Option<Duration>,
+}
+
+/// A backup of a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub name: String,
+    pub path: PathBuf,
+    pub taken: Timestamp,
+}
+
+impl BackupStore {
+    pub fn new(location: Location, keep: Option<usize>, max_age: Option<Duration>) -> Self {
+        Self {
+            location,
+            keep,
+            max_age,
+        }
+    }
+
+    /// returns the command line options selecting this store, to pass it on
+    /// to the root helper.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Location::Central(dir) = &self.location {
+            args.extend([
+                "--backup-dir".to_string(),
+                dir.to_string_lossy().into_owned(),
+            ]);
+        }
+        if let Some(keep) = self.keep {
+            args.extend(["--backup-keep".to_string(), keep.to_string()]);
+        }
+        if let Some(max_age) = self.max_age {
+            args.extend([
+                "--backup-max-age".to_string(),
+                format!("{}s", max_age.as_secs()),
+            ]);
+        }
+        args
+    }
+
+    /// returns the backups of `dest`, newest first.
+    pub fn versions(&self, dest: &Path) -> io::Result<Vec<Version>> {
+        let dir = match &self.location {
+            Location::Sibling => {
+                let path = sibling_path(dest);
+                return match fs::symlink_metadata(&path) {
+                    Ok(metadata) => {
+                        let taken =
+                            Timestamp::try_from(metadata.modified()?).map_err(io::Error::other)?;
+                        Ok(vec![Version {
+                            name: taken.strftime(VERSION_FORMAT).to_string(),
+                            path,
+                            taken,
+                        }])
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+                    Err(err) => Err(err),
+                };
+            }
+            Location::Central(dir) => mirrored(dir, dest),
+        };
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut versions = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            match parse_version(&name) {
+                Some(taken) => versions.push(Version {
+                    name,
+                    path: entry.path(),
+                    taken,
+                }),
+                None => log::debug!("ignoring {} in backup store", entry.path().display()),
+            }
+        }
+        versions.sort_by_key(|version| Reverse(version.taken));
+        Ok(versions)
+    }
+
+    /// returns the same retention policy for backups next to destinations.
+    pub fn sibling(&self) -> Self {
+        Self::new(Location::Sibling, self.keep, self.max_age)
+    }
+
+    /// returns every backup in a central store with the destination it was
+    /// taken of, by destination and oldest first. A sibling store has none
+    /// of its own.
+    pub fn all_versions(&self) -> io::Result<Vec<(PathBuf, Version)>> {
+        let Location::Central(dir) = &self.location else {
+            return Ok(Vec::new());
+        };
+        let mut found = Vec::new();
+        let mut entries = WalkDir::new(dir)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter();
+        while let Some(entry) = entries.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err)
+                    if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) =>
+                {
+                    return Ok(Vec::new());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let name = entry.file_name().to_string_lossy();
+            let Some(taken) = parse_version(&name) else {
+                continue;
+            };
+            if entry.file_type().is_dir() {
+                entries.skip_current_dir();
+            }
+            let Some(relative) = entry.path().parent().and_then(|p| p.strip_prefix(dir).ok())
+            else {
+                continue;
+            };
+            let version = Version {
+                name: name.into_owned(),
+                path: entry.path().to_path_buf(),
+                taken,
+            };
+            found.push((Path::new("/").join(relative), version));
+        }
+        Ok(found)
+    }
+
+    /// removes the backup `version` of `dest` from the store.
+    pub fn remove(&self, dest: &Path, version: &Version) -> io::Result<()> {
+        remove(&version.path)?;
+        self.remove_empty_dir(dest);
+        Ok(())
+    }
+
+    /// returns the backup of `dest` named `name`, or the newest one.
+    pub fn version(&self, dest: &Path, name: Option<&str>) -> io::Result<Option<Version>> {
+        let mut versions = self.versions(dest)?.into_iter();
+        Ok(match name {
+            Some(name) => versions.find(|version| version.name == name),
+            None => versions.next(),
+        })
+    }
+
+    /// copies the file `dest` into the store, keeping its owner and mode,
+    /// and applies the retention policy.
+    pub fn save(&self, dest: &Path) -> io::Result<PathBuf> {
+        let backup = self.new_path(dest)?;
+        let metadata = fs::metadata(dest)?;
+        write_securely(
+            &backup,
+            &mut fs::File::open(dest)?,
+            metadata.mode() & 0o7777,
+            Some(metadata.uid()),
+            Some(metadata.gid()),
+        )?;
+        log::info!("backed up {} to {}", dest.display(), backup.display());
+        self.prune(dest, Some(&backup))?;
+        Ok(backup)
+    }
+
+    /// moves `path`, a file, symbolic link or directory, into the store and
+    /// applies the retention policy. Symbolic links are never followed.
+    pub fn move_in(&self, path: &Path) -> io::Result<PathBuf> {
+        let backup = self.new_path(path)?;
+        let metadata = fs::symlink_metadata(path)?;
+        if let Err(err) = fs::rename(path, &backup) {
+            if matches!(self.location, Location::Sibling) {
+                return Err(err);
+            }
+            // The store is on another filesystem.
+            log::debug!("cannot move {} into the store: {err}", path.display());
+            if metadata.is_symlink() {
+                unix_fs::symlink(fs::read_link(path)?, &backup)?;
+                fs::remove_file(path)?;
+            } else if metadata.is_dir() {
+                copy_dir_all(path, &backup)?;
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::copy(path, &backup)?;
+                unix_fs::lchown(&backup, Some(metadata.uid()), Some(metadata.gid()))?;
+                fs::remove_file(path)?;
+            }
+        } else if matches!(self.location, Location::Sibling) {
+            // The age of a sibling backup is its modification time, which
+            // the rename kept from the original.
+            mark_taken(&backup, &metadata)?;
+        }
+        log::info!("backed up {} to {}", path.display(), backup.display());
+        self.prune(path, Some(&backup))?;
+        Ok(backup)
+    }
+
+    /// puts `version` back at `dest`, backing up what is there first.
+    /// Restoring directories is not supported.
+    pub fn restore(&self, dest: &Path, version: &Version) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(&version.path)?;
+        // Read the backup first, the sibling store only has room for one.
+        if metadata.is_symlink() {
+            let target = fs::read_link(&version.path)?;
+            if fs::symlink_metadata(dest).is_ok() {
+                self.move_in(dest)?;
+            }
+            return unix_fs::symlink(target, dest);
+        }
+        if metadata.is_dir() {
+            return Err(io::Error::other(format!(
+                "cannot restore directories, copy {} instead",
+                version.path.display()
+            )));
+        }
+
+        let content = fs::read(&version.path)?;
+        match fs::symlink_metadata(dest) {
+            Ok(current) if current.is_file() => {
+                self.save(dest)?;
+            }
+            Ok(_) => {
+                self.move_in(dest)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        write_securely(
+            dest,
+            &mut content.as_slice(),
+            metadata.mode() & 0o7777,
+            Some(metadata.uid()),
+            Some(metadata.gid()),
+        )
+    }
+
+    /// removes the backups of `dest` the retention policy does not keep,
+    /// or all of them without a policy. Returns the removed backups.
+    pub fn clean(&self, dest: &Path) -> io::Result<Vec<PathBuf>> {
+        if self.keep.is_none() && self.max_age.is_none() {
+            let versions = self.versions(dest)?;
+            versions
+                .iter()
+                .try_for_each(|version| remove(&version.path))?;
+            self.remove_empty_dir(dest);
+            return Ok(versions.into_iter().map(|version| version.path).collect());
+        }
+        self.prune(dest, None)
+    }
+
+    /// returns the backups of `dest` beyond `keep` or older than `max_age`,
+    /// none without a policy.
+    pub fn expired(&self, dest: &Path) -> io::Result<Vec<Version>> {
+        if self.keep.is_none() && self.max_age.is_none() {
+            return Ok(Vec::new());
+        }
+        let now = Timestamp::now();
+        let mut expired = Vec::new();
+        for (i, version) in self.versions(dest)?.into_iter().enumerate() {
+            let too_many = self.keep.is_some_and(|keep| i >= keep);
+            let too_old = self.max_age.is_some_and(|max_age| {
+                now.duration_since(version.taken)
+                    .as_secs()
+                    .try_into()
+                    .is_ok_and(|age: u64| age > max_age.as_secs())
+            });
+            if too_many || too_old {
+                expired.push(version);
+            }
+        }
+        Ok(expired)
+    }
+
+    /// removes the backups of `dest` beyond `keep` or older than `max_age`,
+    /// but never `taken`, the backup just made.
+    fn prune(&self, dest: &Path, taken: Option<&Path>) -> io::Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        for version in self.expired(dest)? {
+            if taken != Some(version.path.as_path()) {
+                remove(&version.path)?;
+                log::debug!("removed backup {}", version.path.display());
+                removed.push(version.path);
+            }
+        }
+        self.remove_empty_dir(dest);
+        Ok(removed)
+    }
+
+    /// returns where to put a new backup of `dest`.
+    fn new_path(&self, dest: &Path) -> io::Result<PathBuf> {
+        match &self.location {
+            Location::Sibling => Ok(sibling_path(dest)),
+            Location::Central(dir) => {
+                let dir = mirrored(dir, dest);
+                // Backups may hold secrets.
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(&dir)?;
+                let name = Timestamp::now().strftime(VERSION_FORMAT).to_string();
+                Ok(dir.join(name))
+            }
+        }
+    }
+
+    fn remove_empty_dir(&self, dest: &Path) {
+        if let Location::Central(dir) = &self.location {
+            let _ = fs::remove_dir(mirrored(dir, dest));
+        }
+    }
+}
+
+/// parses a retention age such as `30d`, `12h` or `2w`.
+pub fn parse_age(age: &str) -> Result<Duration, String> {
+    let unit_at = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (count, unit) = age.split_at(unit_at);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "" | "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid age '{age}', expected eg: 30d, 12h or 2w")),
+    };
+    let count = count
+        .parse::<u64>()
+        .map_err(|_| format!("invalid age '{age}', expected eg: 30d, 12h or 2w"))?;
+    Ok(Duration::from_secs(count * seconds))
+}
+
+/// sets the modification time of `backup`, moved into place from a path
+/// with `metadata`, to now. A symbolic link cannot be touched without
+/// following it, so it is made again.
+fn mark_taken(backup: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    if !metadata.is_symlink() {
+        return fs::File::open(backup)?.set_modified(SystemTime::now());
+    }
+    let target = fs::read_link(backup)?;
+    fs::remove_file(backup)?;
+    unix_fs::symlink(target, backup)?;
+    match unix_fs::lchown(backup, Some(metadata.uid()), Some(metadata.gid())) {
+        Err(err) if err.kind() != io::ErrorKind::PermissionDenied => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn sibling_path(dest: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.pets-backup", dest.to_string_lossy()))
+}
+
+/// returns the directory holding the versions of `dest` below `dir`.
+fn mirrored(dir: &Path, dest: &Path) -> PathBuf {
+    let relative = dest
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect::<PathBuf>();
+    dir.join(relative)
+}
+
+fn parse_version(name: &str) -> Option<Timestamp> {
+    let time = DateTime::strptime("%Y%m%dT%H%M%S%.fZ", name).ok()?;
+    Some(time.to_zoned(TimeZone::UTC).ok()?.timestamp())
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn central_store_keeps_timestamped_versions() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("etc/secret");
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        let store = BackupStore::new(Location::Central(dir.path().join("store")), Some(2), None);
+
+        for content in ["one", "two", "three"] {
+            fs::write(&dest, content).unwrap();
+            fs::set_permissions(&dest, fs::Permissions::from_mode(0o600)).unwrap();
+            store.save(&dest).unwrap();
+        }
+
+        let versions = store.versions(&dest).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].path.starts_with(dir.path().join("store")));
+        assert_eq!(fs::read_to_string(&versions[0].path).unwrap(), "three");
+        assert_eq!(fs::read_to_string(&versions[1].path).unwrap(), "two");
+        let mode = fs::metadata(&versions[1].path).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn restore_puts_version_back_and_backs_up_current() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("config");
+        let store = BackupStore::new(Location::Central(dir.path().join("store")), None, None);
+        fs::write(&dest, "good").unwrap();
+        store.save(&dest).unwrap();
+        fs::write(&dest, "broken").unwrap();
+
+        let good = store.version(&dest, None).unwrap().unwrap();
+        store.restore(&dest, &good).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "good");
+
+        let versions = store.versions(&dest).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(fs::read_to_string(&versions[0].path).unwrap(), "broken");
+    }
+
+    #[test]
+    fn clean_without_policy_removes_everything() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("config");
+        fs::write(&dest, "x").unwrap();
+        let store = BackupStore::default();
+        let backup = store.save(&dest).unwrap();
+        assert_eq!(backup, dir.path().join("config.pets-backup"));
+
+        assert_eq!(store.clean(&dest).unwrap(), std::slice::from_ref(&backup));
+        assert!(!backup.exists());
+
+        let keep_one = BackupStore::new(Location::Sibling, Some(1), None);
+        keep_one.save(&dest).unwrap();
+        assert!(keep_one.clean(&dest).unwrap().is_empty());
+        assert!(backup.exists());
+    }
+
+    #[test]
+    fn sibling_backup_age_is_the_time_it_was_taken() {
+        let dir = tempdir().unwrap();
+        let store = BackupStore::new(
+            Location::Sibling,
+            None,
+            Some(Duration::from_secs(30 * 24 * 3600)),
+        );
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_580_000_000);
+
+        let file = dir.path().join("config");
+        fs::write(&file, "2020").unwrap();
+        fs::File::open(&file).unwrap().set_modified(old).unwrap();
+        let backup = store.move_in(&file).unwrap();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "2020");
+        assert!(fs::metadata(&backup).unwrap().modified().unwrap() > old);
+
+        let link = dir.path().join("link");
+        unix_fs::symlink("/nonexistent/pets-test-target", &link).unwrap();
+        let backup = store.move_in(&link).unwrap();
+        assert_eq!(
+            fs::read_link(&backup).unwrap(),
+            Path::new("/nonexistent/pets-test-target")
+        );
+        assert!(store.clean(&link).unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_keeps_the_backup_just_taken() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("config");
+        fs::write(&dest, "x").unwrap();
+        let store = BackupStore::new(Location::Sibling, Some(0), None);
+
+        let backup = store.save(&dest).unwrap();
+        assert!(backup.exists());
+        assert_eq!(store.clean(&dest).unwrap(), [backup]);
+    }
+
+    #[test]
+    fn parse_age_accepts_units() {
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("2w"), Ok(Duration::from_secs(14 * 24 * 3600)));
+        assert_eq!(parse_age("30"), Ok(Duration::from_secs(30 * 24 * 3600)));
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("5y").is_err());
+    }
+}
@@ -5,25 +5,74 @@ use std::fmt;
 pub enum Cause {
     None,
     Pkg,
+    Group,
+    User,
     Create,
     Update,
+    Merge,
+    Drift,
     Link,
+    Relink,
+    Replace,
     Dir,
+    Remove,
+    Cleanup,
     Owner,
     Mode,
+    Acl,
+    Xattr,
     Post,
 }
 
+impl Cause {
+    /// parses the `Debug` name of a cause, eg: 'Update'.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Cause::None,
+            Cause::Pkg,
+            Cause::Group,
+            Cause::User,
+            Cause::Create,
+            Cause::Update,
+            Cause::Merge,
+            Cause::Drift,
+            Cause::Link,
+            Cause::Relink,
+            Cause::Replace,
+            Cause::Dir,
+            Cause::Remove,
+            Cause::Cleanup,
+            Cause::Owner,
+            Cause::Mode,
+            Cause::Acl,
+            Cause::Xattr,
+            Cause::Post,
+        ]
+        .into_iter()
+        .find(|cause| format!("{cause:?}") == name)
+    }
+}
+
 impl fmt::Display for Cause {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let pets_cause = match self {
             Cause::Pkg => "PACKAGE_INSTALL".cyan().to_string(),
+            Cause::Group => "GROUP_CREATE".cyan().to_string(),
+            Cause::User => "USER_CREATE".cyan().to_string(),
             Cause::Create => "FILE_CREATE".green().to_string(),
             Cause::Update => "FILE_UPDATE".yellow().to_string(),
+            Cause::Merge => "FILE_MERGE".yellow().to_string(),
+            Cause::Drift => "LOCAL_CHANGE".magenta().to_string(),
             Cause::Link => "LINK_CREATE".green().to_string(),
+            Cause::Relink => "LINK_REPOINT".yellow().to_string(),
+            Cause::Replace => "LINK_REPLACE".red().to_string(),
             Cause::Dir => "DIR_CREATE".green().to_string(),
+            Cause::Remove => "REMOVE".red().to_string(),
+            Cause::Cleanup => "CLEANUP".normal().to_string(),
             Cause::Owner => "OWNER".normal().to_string(),
             Cause::Mode => "CHMOD".normal().to_string(),
+            Cause::Acl => "ACL".normal().to_string(),
+            Cause::Xattr => "XATTR".normal().to_string(),
             Cause::Post => "POST_UPDATE".blue().to_string(),
             Cause::None => "NONE".normal().to_string(),
         };
@@ -36,6 +85,16 @@ impl fmt::Display for Cause {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_from_name_round_trips_debug_name() {
+        assert_eq!(Cause::from_name("Update"), Some(Cause::Update));
+        assert_eq!(
+            Cause::from_name(&format!("{:?}", Cause::Xattr)),
+            Some(Cause::Xattr)
+        );
+        assert_eq!(Cause::from_name("update"), None);
+    }
+
     fn assert_rendered_contains(cause: Cause, expected: &str) {
         let rendered = cause.to_string();
         assert!(
@@ -48,12 +107,22 @@ mod tests {
     fn test_display_variants() {
         assert_rendered_contains(Cause::None, "NONE");
         assert_rendered_contains(Cause::Pkg, "PACKAGE_INSTALL");
+        assert_rendered_contains(Cause::Group, "GROUP_CREATE");
+        assert_rendered_contains(Cause::User, "USER_CREATE");
         assert_rendered_contains(Cause::Create, "FILE_CREATE");
         assert_rendered_contains(Cause::Update, "FILE_UPDATE");
+        assert_rendered_contains(Cause::Merge, "FILE_MERGE");
+        assert_rendered_contains(Cause::Drift, "LOCAL_CHANGE");
         assert_rendered_contains(Cause::Link, "LINK_CREATE");
+        assert_rendered_contains(Cause::Relink, "LINK_REPOINT");
+        assert_rendered_contains(Cause::Replace, "LINK_REPLACE");
         assert_rendered_contains(Cause::Dir, "DIR_CREATE");
+        assert_rendered_contains(Cause::Remove, "REMOVE");
         assert_rendered_contains(Cause::Owner, "OWNER");
         assert_rendered_contains(Cause::Mode, "CHMOD");
+        assert_rendered_contains(Cause::Acl, "ACL");
+        assert_rendered_contains(Cause::Xattr, "XATTR");
+        assert_rendered_contains(Cause::Cleanup, "CLEANUP");
         assert_rendered_contains(Cause::Post, "POST_UPDATE");
     }
 }
@@ -0,0 +1,622 @@
+// Changes an action would make to a destination, for `pets diff` and
+// `--dry-run`. They are read from the live destination and the pets
+// configuration, without performing the action.
+//
+// Content is compared as text where possible. Known binary formats are
+// converted to text by an external command first, like git's textconv, other
+// binary files are described by size, hash and a hexdump of the first region
+// that differs. Files larger than the diff limit are never read whole.
+
+use super::{
+    Action, Cause,
+    action::{Operation, acl_arg, merged_content},
+};
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::{
+    collections::BTreeSet,
+    env,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use walkdir::WalkDir;
+
+/// Files larger than this many bytes are not diffed unless configured.
+pub const DEFAULT_DIFF_LIMIT: u64 = 1024 * 1024;
+
+// Rows of 16 bytes shown around the first difference of binary files
+const HEXDUMP_ROWS: usize = 4;
+
+// Binary formats converted to text before diffing: name, magic bytes and the
+// default command, which gets the file as $1. PETS_TEXTCONV_<NAME> overrides
+// the command, an empty one turns conversion off.
+const TEXTCONV: [(&str, &[u8], &str); 2] = [
+    ("gzip", b"\x1f\x8b", "gzip -dc \"$1\""),
+    (
+        "sqlite",
+        b"SQLite format 3\0",
+        "sqlite3 -readonly \"$1\" .dump",
+    ),
+];
+
+/// One side of a content change.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Content {
+    File(PathBuf),
+    Text(String),
+}
+
+impl Content {
+    pub fn size(&self) -> io::Result<u64> {
+        match self {
+            Self::File(path) => Ok(fs::metadata(path)?.len()),
+            Self::Text(text) => Ok(text.len() as u64),
+        }
+    }
+
+    fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::File(path) => fs::read(path),
+            Self::Text(text) => Ok(text.clone().into_bytes()),
+        }
+    }
+
+    fn sha256(&self) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        match self {
+            Self::File(path) => {
+                let mut file = File::open(path)?;
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    match file.read(&mut buffer)? {
+                        0 => break,
+                        n => hasher.update(&buffer[..n]),
+                    }
+                }
+            }
+            Self::Text(text) => hasher.update(text.as_bytes()),
+        }
+        Ok(hasher
+            .finalize()
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            }))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Change {
+    /// Content of a file, `None` where it does not exist.
+    Content {
+        path: PathBuf,
+        old: Option<Content>,
+        new: Option<Content>,
+    },
+    /// Target of a symbolic link, `None` if `path` is not a link yet.
+    Link {
+        path: PathBuf,
+        old: Option<PathBuf>,
+        new: PathBuf,
+    },
+    /// Permission bits, `None` if `path` does not exist yet.
+    Mode {
+        path: PathBuf,
+        old: Option<u32>,
+        new: u32,
+    },
+    /// Ownership as 'user:group', `None` if `path` does not exist yet.
+    Owner {
+        path: PathBuf,
+        old: Option<String>,
+        new: String,
+    },
+    /// ACL entries or an extended attribute, as described.
+    Attribute { path: PathBuf, description: String },
+    /// Something removed that is not a regular file, like a symbolic link.
+    Removed { path: PathBuf },
+    /// Only present in a destination directory tree, and left alone.
+    Extra { path: PathBuf },
+}
+
+impl Change {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Content { path, .. }
+            | Self::Link { path, .. }
+            | Self::Mode { path, .. }
+            | Self::Owner { path, .. }
+            | Self::Attribute { path, .. }
+            | Self::Removed { path }
+            | Self::Extra { path } => path,
+        }
+    }
+}
+
+impl Action {
+    /// returns what performing this action would change. Commands, package
+    /// installs, created directories and kept local changes change nothing
+    /// that can be shown as a difference.
+    pub fn changes(&self) -> io::Result<Vec<Change>> {
+        let change = match self.operation() {
+            Operation::Copy { source, dest, .. } if source.is_dir() => {
+                return tree_changes(dest, Some(source));
+            }
+            Operation::Copy { source, dest, .. } => Change::Content {
+                path: dest.clone(),
+                old: dest.exists().then(|| Content::File(dest.clone())),
+                new: Some(Content::File(source.clone())),
+            },
+            Operation::Merge { source, dest, base } => Change::Content {
+                path: dest.clone(),
+                old: Some(Content::File(dest.clone())),
+                new: Some(Content::Text(
+                    merged_content(source, dest, base).map_err(io::Error::other)?,
+                )),
+            },
+            Operation::Symlink { source, dest }
+            | Operation::ReplaceWithSymlink { source, dest } => Change::Link {
+                path: dest.clone(),
+                old: fs::read_link(dest).ok(),
+                new: source.clone(),
+            },
+            Operation::Remove { .. } if self.cause() == Cause::Cleanup => return Ok(Vec::new()),
+            Operation::Remove { path } => {
+                let file_type = fs::symlink_metadata(path)?.file_type();
+                if file_type.is_dir() {
+                    return tree_changes(path, None);
+                } else if file_type.is_file() {
+                    Change::Content {
+                        path: path.clone(),
+                        old: Some(Content::File(path.clone())),
+                        new: None,
+                    }
+                } else {
+                    Change::Removed { path: path.clone() }
+                }
+            }
+            Operation::Chmod { path, mode } => {
+                let old = fs::symlink_metadata(path).ok().map(|m| m.mode() & 0o7777);
+                if old == Some(mode & 0o7777) {
+                    return Ok(Vec::new());
+                }
+                Change::Mode {
+                    path: path.clone(),
+                    old,
+                    new: mode & 0o7777,
+                }
+            }
+            Operation::Chown {
+                path, display_arg, ..
+            } => Change::Owner {
+                path: path.clone(),
+                old: fs::symlink_metadata(path).ok().map(|m| owner(&m)),
+                new: display_arg.clone(),
+            },
+            Operation::SetAcl { path, entries } => Change::Attribute {
+                path: path.clone(),
+                description: format!("ACL {}", acl_arg(entries)),
+            },
+            Operation::SetXattr { path, name, value } => Change::Attribute {
+                path: path.clone(),
+                description: format!("xattr {name}={value}"),
+            },
+            Operation::CreateDir { .. } | Operation::Command { .. } => return Ok(Vec::new()),
+        };
+        Ok(vec![change])
+    }
+}
+
+fn owner(metadata: &fs::Metadata) -> String {
+    let user = uzers::get_user_by_uid(metadata.uid()).map_or_else(
+        || metadata.uid().to_string(),
+        |user| user.name().to_string_lossy().into_owned(),
+    );
+    let group = uzers::get_group_by_gid(metadata.gid()).map_or_else(
+        || metadata.gid().to_string(),
+        |group| group.name().to_string_lossy().into_owned(),
+    );
+    format!("{user}:{group}")
+}
+
+/// compares the files below `dest` with those below `source`, or lists them
+/// all as removed without `source`.
+fn tree_changes(dest: &Path, source: Option<&Path>) -> io::Result<Vec<Change>> {
+    let files = |root: &Path| {
+        WalkDir::new(root)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| !entry.file_type().is_dir())
+            .filter_map(|entry| entry.path().strip_prefix(root).ok().map(Path::to_path_buf))
+            .collect::<BTreeSet<_>>()
+    };
+    let old = if dest.is_dir() {
+        files(dest)
+    } else {
+        BTreeSet::new()
+    };
+    let new = source.map(files).unwrap_or_default();
+
+    let mut changes = Vec::new();
+    for relative in old.union(&new) {
+        let path = dest.join(relative);
+        let old = old.contains(relative).then(|| Content::File(path.clone()));
+        let change = match (old, source) {
+            (Some(_), Some(_)) if !new.contains(relative) => Change::Extra { path },
+            (old, Some(source)) => {
+                let new = Content::File(source.join(relative));
+                if let Some(old) = &old {
+                    if old.size()? == new.size()? && old.sha256()? == new.sha256()? {
+                        continue;
+                    }
+                }
+                Change::Content {
+                    path,
+                    old,
+                    new: Some(new),
+                }
+            }
+            (old, None) => Change::Content {
+                path,
+                old,
+                new: None,
+            },
+        };
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+/// compares `old` and `new` content, `None` where a file does not exist, and
+/// returns the lines of a unified diff between them labelled `from` and
+/// `to`. Binary content and content over `limit` bytes is described instead.
+pub fn content_diff(
+    from: &str,
+    to: &str,
+    old: Option<&Content>,
+    new: Option<&Content>,
+    limit: u64,
+) -> io::Result<Vec<String>> {
+    let (from, to) = (
+        if old.is_some() { from } else { "/dev/null" },
+        if new.is_some() { to } else { "/dev/null" },
+    );
+    let size = |content: Option<&Content>| content.map_or(Ok(0), Content::size);
+    let (old_size, new_size) = (size(old)?, size(new)?);
+    if old_size.max(new_size) > limit {
+        return Ok(vec![
+            format!("Files {from} and {to} differ, too large to diff (limit {limit} bytes)"),
+            format!("size {old_size} → {new_size} bytes"),
+            format!("sha256 {} → {}", sha256(old)?, sha256(new)?),
+        ]);
+    }
+
+    let read = |content: Option<&Content>| content.map_or(Ok(Vec::new()), Content::read);
+    let (old_bytes, new_bytes) = (read(old)?, read(new)?);
+    let texts = match (text(&old_bytes), text(&new_bytes)) {
+        (Some(old), Some(new)) => Some((old.to_string(), new.to_string(), None)),
+        _ => match (
+            textconv(old, &old_bytes, limit),
+            textconv(new, &new_bytes, limit),
+        ) {
+            (Some((old, format)), Some((new, _))) => Some((old, new, Some(format))),
+            _ => None,
+        },
+    };
+    let Some((old_text, new_text, format)) = texts else {
+        let mut lines = vec![
+            format!("Binary files {from} and {to} differ"),
+            format!("size {old_size} → {new_size} bytes"),
+            format!("sha256 {} → {}", sha256(old)?, sha256(new)?),
+        ];
+        lines.extend(hexdump(&old_bytes, &new_bytes));
+        return Ok(lines);
+    };
+
+    let note = format.map_or(String::new(), |format| format!(" (as text by {format})"));
+    let diff = TextDiff::from_lines(&old_text, &new_text)
+        .unified_diff()
+        .header(&format!("{from}{note}"), &format!("{to}{note}"))
+        .to_string();
+    Ok(diff.lines().map(ToString::to_string).collect())
+}
+
+/// returns `old` and `new` as text, missing content as empty, or `None` for
+/// binary content and content over `limit` bytes.
+pub fn texts(
+    old: Option<&Content>,
+    new: Option<&Content>,
+    limit: u64,
+) -> io::Result<Option<(String, String)>> {
+    let size = |content: Option<&Content>| content.map_or(Ok(0), Content::size);
+    if size(old)?.max(size(new)?) > limit {
+        return Ok(None);
+    }
+    let read = |content: Option<&Content>| content.map_or(Ok(Vec::new()), Content::read);
+    let (old, new) = (read(old)?, read(new)?);
+    Ok(match (text(&old), text(&new)) {
+        (Some(old), Some(new)) => Some((old.to_string(), new.to_string())),
+        _ => None,
+    })
+}
+
+/// returns the number of inserted and deleted lines between `old` and
+/// `new`, or `None` for binary content and content over `limit` bytes.
+pub fn line_counts(
+    old: Option<&Content>,
+    new: Option<&Content>,
+    limit: u64,
+) -> io::Result<Option<(usize, usize)>> {
+    let Some((old, new)) = texts(old, new, limit)? else {
+        return Ok(None);
+    };
+    let diff = TextDiff::from_lines(&old, &new);
+    let count = |tag| {
+        diff.iter_all_changes()
+            .filter(|change| change.tag() == tag)
+            .count()
+    };
+    Ok(Some((
+        count(similar::ChangeTag::Insert),
+        count(similar::ChangeTag::Delete),
+    )))
+}
+
+fn text(content: &[u8]) -> Option<&str> {
+    if content.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(content).ok()
+}
+
+fn sha256(content: Option<&Content>) -> io::Result<String> {
+    content.map_or(Ok("(none)".to_string()), Content::sha256)
+}
+
+/// converts `bytes` of a known binary format to text, returning the text and
+/// the format. Missing content converts to nothing, and text over `limit`
+/// bytes, e.g. from a zip bomb, is not converted.
+fn textconv(content: Option<&Content>, bytes: &[u8], limit: u64) -> Option<(String, String)> {
+    let Some(content) = content else {
+        return Some((String::new(), String::new()));
+    };
+    let Content::File(path) = content else {
+        return None;
+    };
+    let (name, _, default) = TEXTCONV
+        .iter()
+        .find(|(_, magic, _)| bytes.starts_with(magic))?;
+    let command = env::var(format!("PETS_TEXTCONV_{}", name.to_uppercase()))
+        .unwrap_or_else(|_| (*default).to_string());
+    if command.is_empty() {
+        return None;
+    }
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .arg("sh")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            log::debug!("cannot run '{command}' for {}: {err}", path.display());
+            return None;
+        }
+    };
+    let mut text = Vec::new();
+    let read = child
+        .stdout
+        .take()
+        .map(|stdout| stdout.take(limit.saturating_add(1)).read_to_end(&mut text));
+    if text.len() as u64 > limit {
+        log::debug!(
+            "'{command}' output for {} is over {limit} bytes",
+            path.display()
+        );
+        let _ = child.kill();
+        let _ = child.wait();
+        return None;
+    }
+    match (read, child.wait()) {
+        (Some(Ok(_)), Ok(status)) if status.success() => String::from_utf8(text)
+            .ok()
+            .map(|text| (text, (*name).to_string())),
+        (_, Ok(status)) => {
+            log::debug!("'{command}' failed for {}: {status}", path.display());
+            None
+        }
+        (_, Err(err)) => {
+            log::debug!("cannot run '{command}' for {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// dumps the rows around the first byte that differs between `old` and
+/// `new` the way `xxd` does, old rows prefixed with '-' and new ones '+'.
+fn hexdump(old: &[u8], new: &[u8]) -> Vec<String> {
+    let first = old
+        .iter()
+        .zip(new)
+        .position(|(a, b)| a != b)
+        .unwrap_or(old.len().min(new.len()));
+    let start = first & !0xf;
+    let mut lines = vec![format!(
+        "@@ first difference at byte {first} (0x{first:x}) @@"
+    )];
+    for (sign, bytes) in [('-', old), ('+', new)] {
+        for row in bytes[start.min(bytes.len())..]
+            .chunks(16)
+            .take(HEXDUMP_ROWS)
+            .enumerate()
+            .map(|(i, chunk)| (start + i * 16, chunk))
+        {
+            let (offset, chunk) = row;
+            let hex = chunk
+                .chunks(2)
+                .map(|pair| {
+                    pair.iter().fold(String::new(), |mut hex, byte| {
+                        let _ = write!(hex, "{byte:02x}");
+                        hex
+                    })
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        char::from(byte)
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            lines.push(format!("{sign}{offset:08x}: {hex:<39}  {ascii}"));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn copy_of_a_tree_compares_every_file() -> io::Result<()> {
+        let dir = tempdir()?;
+        let (source, dest) = (dir.path().join("source"), dir.path().join("dest"));
+        for root in [&source, &dest] {
+            fs::create_dir_all(root.join("sub"))?;
+            fs::write(root.join("same"), "same")?;
+        }
+        fs::write(source.join("sub/changed"), "new")?;
+        fs::write(dest.join("sub/changed"), "old")?;
+        fs::write(source.join("added"), "added")?;
+        fs::write(dest.join("extra"), "extra")?;
+
+        let changes = Action::copy_file(Cause::Update, source.clone(), dest.clone()).changes()?;
+        assert_eq!(
+            changes,
+            [
+                Change::Content {
+                    path: dest.join("added"),
+                    old: None,
+                    new: Some(Content::File(source.join("added"))),
+                },
+                Change::Extra {
+                    path: dest.join("extra"),
+                },
+                Change::Content {
+                    path: dest.join("sub/changed"),
+                    old: Some(Content::File(dest.join("sub/changed"))),
+                    new: Some(Content::File(source.join("sub/changed"))),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn chmod_to_the_current_mode_changes_nothing() -> io::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("file");
+        fs::write(&path, "")?;
+        let mode = fs::metadata(&path)?.mode() & 0o7777;
+
+        assert!(
+            Action::chmod(Cause::Mode, path.clone(), mode)
+                .changes()?
+                .is_empty()
+        );
+        assert_eq!(
+            Action::chmod(Cause::Mode, path.clone(), 0o600).changes()?,
+            [Change::Mode {
+                path,
+                old: Some(mode),
+                new: 0o600,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn content_diff_describes_binary_and_large_files() -> io::Result<()> {
+        let old = Content::Text("ab\0".repeat(8));
+        let mut changed = "ab\0".repeat(8);
+        changed.replace_range(20..21, "X");
+        let new = Content::Text(changed);
+
+        let lines = content_diff("old", "new", Some(&old), Some(&new), DEFAULT_DIFF_LIMIT)?;
+        assert_eq!(lines[0], "Binary files old and new differ");
+        assert_eq!(lines[1], "size 24 → 24 bytes");
+        assert!(lines[2].starts_with("sha256 ") && lines[2].contains(" → "));
+        assert_eq!(lines[3], "@@ first difference at byte 20 (0x14) @@");
+        assert_eq!(
+            lines[4],
+            format!("-00000010: {:<39}  b.ab.ab.", "6200 6162 0061 6200")
+        );
+        assert_eq!(
+            lines[5],
+            format!("+00000010: {:<39}  b.abXab.", "6200 6162 5861 6200")
+        );
+
+        let lines = content_diff("old", "new", Some(&old), None, 10)?;
+        assert_eq!(
+            lines[..2],
+            [
+                "Files old and /dev/null differ, too large to diff (limit 10 bytes)",
+                "size 24 → 0 bytes",
+            ]
+        );
+        assert_eq!(line_counts(Some(&old), None, 10)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn content_diff_converts_gzip_to_text() -> io::Result<()> {
+        let dir = tempdir()?;
+        let mut compressed = Vec::new();
+        for (name, text) in [("old", "one\ntwo\n"), ("new", "one\nthree\n")] {
+            let path = dir.path().join(name);
+            fs::write(&path, text)?;
+            let status = Command::new("gzip").arg(&path).status()?;
+            assert!(status.success());
+            compressed.push(Content::File(dir.path().join(format!("{name}.gz"))));
+        }
+
+        let lines = content_diff("a", "b", Some(&compressed[0]), Some(&compressed[1]), 1024)?;
+        assert_eq!(lines[0], "--- a (as text by gzip)");
+        assert!(lines.contains(&"-two".to_string()));
+        assert!(lines.contains(&"+three".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn content_diff_does_not_convert_past_the_limit() -> io::Result<()> {
+        let dir = tempdir()?;
+        let mut compressed = Vec::new();
+        for (name, line) in [("old", "a\n"), ("new", "b\n")] {
+            let path = dir.path().join(name);
+            fs::write(&path, line.repeat(10_000))?;
+            let status = Command::new("gzip").arg(&path).status()?;
+            assert!(status.success());
+            compressed.push(Content::File(dir.path().join(format!("{name}.gz"))));
+        }
+        assert!(compressed[0].size()? < 1024);
+
+        let lines = content_diff("a", "b", Some(&compressed[0]), Some(&compressed[1]), 1024)?;
+        assert_eq!(lines[0], "Binary files a and b differ");
+        Ok(())
+    }
+}
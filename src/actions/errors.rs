@@ -9,6 +9,12 @@ pub enum ActionError {
     IoError(#[from] std::io::Error),
     #[error("Package manger not installed")]
     NoPackageManager,
+    #[error("{0} changed both in the repo and locally and cannot be merged, leaving it alone")]
+    MergeConflict(String),
+    #[error("unknown user or group '{0}'")]
+    UnknownAccount(String),
+    #[error("privileged action failed: {0}")]
+    Escalation(String),
     #[error("Package not found {0} in {1}")]
     PackageNotFound(String, PackageManager),
 }
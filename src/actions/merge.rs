@@ -0,0 +1,147 @@
+// Line based three-way merge. Both sides are diffed against their common
+// ancestor; changes to separate regions are combined, identical changes are
+// taken once, and anything else is a conflict.
+
+use similar::{Algorithm, DiffOp, capture_diff_slices};
+
+/// A change to `base[start..end]`, replaced by `lines`.
+#[derive(Debug)]
+struct Hunk<'a> {
+    start: usize,
+    end: usize,
+    lines: &'a [&'a str],
+}
+
+/// merges `ours` and `theirs`, both derived from `base`. Returns `None` if
+/// they change the same region differently.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    let base = base.split_inclusive('\n').collect::<Vec<_>>();
+    let ours = ours.split_inclusive('\n').collect::<Vec<_>>();
+    let theirs = theirs.split_inclusive('\n').collect::<Vec<_>>();
+    let mut ours = hunks(&base, &ours).into_iter().peekable();
+    let mut theirs = hunks(&base, &theirs).into_iter().peekable();
+
+    let mut merged = String::new();
+    let mut pos = 0;
+    loop {
+        let start = match (ours.peek(), theirs.peek()) {
+            (Some(a), Some(b)) => a.start.min(b.start),
+            (Some(a), None) => a.start,
+            (None, Some(b)) => b.start,
+            (None, None) => break,
+        };
+
+        // Collect every hunk overlapping or touching the region.
+        let mut end = start;
+        let (mut group_ours, mut group_theirs) = (Vec::new(), Vec::new());
+        loop {
+            if let Some(hunk) = ours.next_if(|h| h.start <= end) {
+                end = end.max(hunk.end);
+                group_ours.push(hunk);
+            } else if let Some(hunk) = theirs.next_if(|h| h.start <= end) {
+                end = end.max(hunk.end);
+                group_theirs.push(hunk);
+            } else {
+                break;
+            }
+        }
+
+        merged.extend(base[pos..start].iter().copied());
+        let resolved = match (group_ours.is_empty(), group_theirs.is_empty()) {
+            (false, true) => apply(&base, start, end, &group_ours),
+            (true, false) => apply(&base, start, end, &group_theirs),
+            _ => {
+                let ours = apply(&base, start, end, &group_ours);
+                if ours != apply(&base, start, end, &group_theirs) {
+                    return None;
+                }
+                ours
+            }
+        };
+        merged.push_str(&resolved);
+        pos = end;
+    }
+    merged.extend(base[pos..].iter().copied());
+
+    Some(merged)
+}
+
+fn hunks<'a>(base: &[&str], other: &'a [&'a str]) -> Vec<Hunk<'a>> {
+    capture_diff_slices(Algorithm::Myers, base, other)
+        .into_iter()
+        .filter_map(|op| match op {
+            DiffOp::Equal { .. } => None,
+            DiffOp::Delete {
+                old_index, old_len, ..
+            } => Some(Hunk {
+                start: old_index,
+                end: old_index + old_len,
+                lines: &[],
+            }),
+            DiffOp::Insert {
+                old_index,
+                new_index,
+                new_len,
+            } => Some(Hunk {
+                start: old_index,
+                end: old_index,
+                lines: &other[new_index..new_index + new_len],
+            }),
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => Some(Hunk {
+                start: old_index,
+                end: old_index + old_len,
+                lines: &other[new_index..new_index + new_len],
+            }),
+        })
+        .collect()
+}
+
+/// returns `base[start..end]` with the given hunks of one side applied.
+fn apply(base: &[&str], start: usize, end: usize, hunks: &[Hunk]) -> String {
+    let mut out = String::new();
+    let mut pos = start;
+    for hunk in hunks {
+        out.extend(base[pos..hunk.start].iter().copied());
+        out.extend(hunk.lines.iter().copied());
+        pos = hunk.end;
+    }
+    out.extend(base[pos..end].iter().copied());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "a\nb\nc\nd\ne\n";
+
+    #[test]
+    fn merge3_combines_changes_to_separate_lines() {
+        let ours = "a\nB\nc\nd\ne\n";
+        let theirs = "a\nb\nc\nd\nE\nf\n";
+        assert_eq!(merge3(BASE, ours, theirs).unwrap(), "a\nB\nc\nd\nE\nf\n");
+    }
+
+    #[test]
+    fn merge3_takes_identical_changes_once() {
+        let both = "a\nb\nC\nd\ne\n";
+        assert_eq!(merge3(BASE, both, both).unwrap(), both);
+    }
+
+    #[test]
+    fn merge3_conflicts_on_same_line() {
+        assert_eq!(merge3(BASE, "a\nb\nX\nd\ne\n", "a\nb\nY\nd\ne\n"), None);
+    }
+
+    #[test]
+    fn merge3_keeps_one_sided_changes() {
+        let theirs = "a\nc\nd\ne\nnew\n";
+        assert_eq!(merge3(BASE, BASE, theirs).unwrap(), theirs);
+        assert_eq!(merge3(BASE, theirs, BASE).unwrap(), theirs);
+    }
+}
@@ -84,6 +84,24 @@ impl PackageManager {
     }
 }
 
+/// returns the default package manager for an operating system and
+/// distribution family, without probing the local machine.
+pub fn for_system<'a>(
+    os: Option<&str>,
+    mut distro_family: impl Iterator<Item = &'a str>,
+) -> Option<PackageManager> {
+    if matches!(os, Some("macos" | "darwin")) {
+        return Some(PackageManager::Homebrew);
+    }
+    distro_family.find_map(|id| match id {
+        "debian" | "ubuntu" => Some(PackageManager::Apt),
+        "fedora" | "rhel" | "centos" | "rocky" | "almalinux" => Some(PackageManager::Yum),
+        "alpine" => Some(PackageManager::Apk),
+        "arch" | "manjaro" => Some(PackageManager::Pacman),
+        _ => None,
+    })
+}
+
 #[cfg(target_os = "linux")]
 pub fn which() -> Result<PackageManager, ParseError> {
     let commands = [
@@ -267,4 +285,25 @@ mod tests {
         assert!(!PackageManager::Homebrew.requires_sudo());
         assert!(!PackageManager::Pip.requires_sudo());
     }
+
+    #[test]
+    fn test_for_system_maps_distro_family() {
+        assert_eq!(
+            for_system(Some("linux"), ["ubuntu", "debian"].into_iter()),
+            Some(PackageManager::Apt)
+        );
+        assert_eq!(
+            for_system(Some("linux"), ["rocky", "rhel"].into_iter()),
+            Some(PackageManager::Yum)
+        );
+        assert_eq!(
+            for_system(Some("linux"), ["postmarketos", "alpine"].into_iter()),
+            Some(PackageManager::Apk)
+        );
+        assert_eq!(
+            for_system(Some("macos"), std::iter::empty()),
+            Some(PackageManager::Homebrew)
+        );
+        assert_eq!(for_system(Some("linux"), ["gentoo"].into_iter()), None);
+    }
 }
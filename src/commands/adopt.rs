@@ -0,0 +1,337 @@
+use crate::actions;
+use std::{
+    env,
+    fmt::Write,
+    fs, io,
+    os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AdoptError {
+    #[error("{0} is not a regular file")]
+    NotRegularFile(String),
+    #[error("{0} is not a text file")]
+    NotText(String),
+    #[error("{0} already exists in the configuration directory")]
+    AlreadyAdopted(String),
+    #[error("{0} has no comment syntax to hold a modeline, choose one with --comment")]
+    NoCommentSyntax(String),
+    #[error("--into {0} is not a directory inside the configuration directory")]
+    OutsideConfDir(String),
+    #[error("IO error adopting file: {0}")]
+    IoError(#[from] io::Error),
+}
+
+pub fn adopt(
+    conf_dir: &str,
+    path: &Path,
+    symlink: bool,
+    into: Option<&Path>,
+    comment: Option<&str>,
+) -> ExitCode {
+    match adopt_file(Path::new(conf_dir), path, symlink, into, comment) {
+        Ok(source) => {
+            log::info!("adopted {} as {}", path.display(), source.display());
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            log::error!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// copies `path` into the configuration directory with a modeline capturing
+/// its destination, owner, group and mode, commented out with `comment` or
+/// else the comment style of its file type. The destination is updated to
+/// match the new source, so the adopted file is in sync immediately.
+fn adopt_file(
+    conf_dir: &Path,
+    path: &Path,
+    symlink: bool,
+    into: Option<&Path>,
+    comment: Option<&str>,
+) -> Result<PathBuf, AdoptError> {
+    if let Some(into) = into {
+        if !into
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(AdoptError::OutsideConfDir(into.display().to_string()));
+        }
+    }
+
+    let dest = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    };
+    let display = dest.display().to_string();
+
+    let metadata = fs::symlink_metadata(&dest)?;
+    if !metadata.file_type().is_file() {
+        return Err(AdoptError::NotRegularFile(display));
+    }
+    let content = fs::read_to_string(&dest).map_err(|err| match err.kind() {
+        io::ErrorKind::InvalidData => AdoptError::NotText(display.clone()),
+        _ => err.into(),
+    })?;
+    let prefix = comment
+        .or_else(|| comment_prefix(&dest))
+        .ok_or_else(|| AdoptError::NoCommentSyntax(display.clone()))?;
+
+    let file_name = dest
+        .file_name()
+        .ok_or_else(|| AdoptError::NotRegularFile(display.clone()))?;
+    let target_dir = into.map_or_else(|| conf_dir.to_path_buf(), |into| conf_dir.join(into));
+    let source = target_dir.join(file_name);
+    if source.exists() {
+        return Err(AdoptError::AlreadyAdopted(source.display().to_string()));
+    }
+
+    let adopted = with_modeline(&content, prefix, &modeline(&dest, &metadata, symlink));
+    fs::create_dir_all(&target_dir)?;
+    fs::write(&source, &adopted)?;
+
+    if symlink {
+        fs::set_permissions(
+            &source,
+            fs::Permissions::from_mode(metadata.mode() & 0o7777),
+        )?;
+        if let Err(err) = unix_fs::chown(&source, Some(metadata.uid()), Some(metadata.gid())) {
+            log::warn!("cannot preserve ownership on {}: {err}", source.display());
+        }
+        let source = fs::canonicalize(&source)?;
+        fs::remove_file(&dest)?;
+        unix_fs::symlink(&source, &dest)?;
+    } else {
+        actions::write_securely(
+            &dest,
+            &mut adopted.as_bytes(),
+            metadata.mode() & 0o7777,
+            Some(metadata.uid()),
+            Some(metadata.gid()),
+        )?;
+    }
+
+    Ok(source)
+}
+
+/// builds the directive lines for an adopted file, using `~` for paths in
+/// the home directory.
+fn modeline(dest: &Path, metadata: &fs::Metadata, symlink: bool) -> Vec<String> {
+    let kind = if symlink { "symlink" } else { "destfile" };
+    let dest_display = match env::var("HOME") {
+        Ok(home) if !home.is_empty() => match dest.strip_prefix(&home) {
+            Ok(rest) => format!("~/{}", rest.display()),
+            Err(_) => dest.display().to_string(),
+        },
+        _ => dest.display().to_string(),
+    };
+
+    let mut ownership = Vec::new();
+    if let Some(user) = uzers::get_user_by_uid(metadata.uid()) {
+        ownership.push(format!("owner={}", user.name().to_string_lossy()));
+    }
+    if let Some(group) = uzers::get_group_by_gid(metadata.gid()) {
+        ownership.push(format!("group={}", group.name().to_string_lossy()));
+    }
+    ownership.push(format!("mode={:04o}", metadata.mode() & 0o7777));
+
+    vec![format!("{kind}={dest_display}"), ownership.join(", ")]
+}
+
+/// returns the comment prefix conventionally used by files named like `path`,
+/// or `None` for formats without comments.
+fn comment_prefix(path: &Path) -> Option<&'static str> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "json" | "ipynb" | "geojson" => None,
+        "ini" | "cfg" | "desktop" | "service" | "timer" | "socket" | "mount" => Some(";"),
+        "lua" | "sql" | "hs" => Some("--"),
+        "vim" => Some("\""),
+        "js" | "jsonc" | "c" | "h" | "cpp" | "rs" | "go" | "kdl" | "scss" => Some("//"),
+        _ if name.ends_with("vimrc") => Some("\""),
+        _ => Some("#"),
+    }
+}
+
+/// prepends `directives` as pets modelines, after a shebang if there is one.
+fn with_modeline(content: &str, prefix: &str, directives: &[String]) -> String {
+    let header = directives
+        .iter()
+        .fold(String::new(), |mut header, directive| {
+            let _ = writeln!(header, "{prefix} pets: {directive}");
+            header
+        });
+
+    if !content.starts_with("#!") {
+        return format!("{header}{content}");
+    }
+    match content.split_once('\n') {
+        Some((shebang, rest)) => format!("{shebang}\n{header}{rest}"),
+        None => format!("{content}\n{header}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{facts::Facts, pet_files};
+    use tempfile::tempdir;
+
+    fn sync_status(conf_dir: &Path) -> pet_files::SyncStatus {
+        let conf_dir = conf_dir.to_str().unwrap();
+        let facts = Facts::for_host(conf_dir, None).unwrap();
+        let files = pet_files::load(conf_dir, &facts).unwrap();
+        assert_eq!(files.len(), 1);
+        files[0].sync_status()
+    }
+
+    #[test]
+    fn adopt_copies_file_with_modeline_and_is_in_sync() {
+        let conf = tempdir().unwrap();
+        let system = tempdir().unwrap();
+        let dest = system.path().join("app.ini");
+        fs::write(&dest, "[main]\nkey = value\n").unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let source = adopt_file(conf.path(), &dest, false, Some(Path::new("web")), None).unwrap();
+
+        assert_eq!(source, conf.path().join("web/app.ini"));
+        let content = fs::read_to_string(&source).unwrap();
+        let mut lines = content.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            format!("; pets: destfile={}", dest.display())
+        );
+        assert!(lines.next().unwrap().ends_with("mode=0640"));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), content);
+        assert_eq!(fs::metadata(&dest).unwrap().mode() & 0o777, 0o640);
+        assert_eq!(sync_status(conf.path()), pet_files::SyncStatus::InSync);
+    }
+
+    #[test]
+    fn adopt_symlink_replaces_original_with_link() {
+        let conf = tempdir().unwrap();
+        let system = tempdir().unwrap();
+        let dest = system.path().join("init.lua");
+        fs::write(&dest, "print('hi')\n").unwrap();
+
+        let source = adopt_file(conf.path(), &dest, true, None, None).unwrap();
+
+        assert!(
+            fs::symlink_metadata(&dest)
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        assert_eq!(
+            fs::read_link(&dest).unwrap(),
+            fs::canonicalize(&source).unwrap()
+        );
+        assert!(
+            fs::read_to_string(&source)
+                .unwrap()
+                .starts_with(&format!("-- pets: symlink={}\n", dest.display()))
+        );
+        assert_eq!(sync_status(conf.path()), pet_files::SyncStatus::InSync);
+    }
+
+    #[test]
+    fn adopt_refuses_existing_source() {
+        let conf = tempdir().unwrap();
+        let system = tempdir().unwrap();
+        let dest = system.path().join("motd");
+        fs::write(&dest, "hello\n").unwrap();
+        fs::write(conf.path().join("motd"), "other\n").unwrap();
+
+        assert!(matches!(
+            adopt_file(conf.path(), &dest, false, None, None),
+            Err(AdoptError::AlreadyAdopted(_))
+        ));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn adopt_refuses_directories() {
+        let conf = tempdir().unwrap();
+        let system = tempdir().unwrap();
+        assert!(matches!(
+            adopt_file(conf.path(), system.path(), false, None, None),
+            Err(AdoptError::NotRegularFile(_))
+        ));
+    }
+
+    #[test]
+    fn adopt_refuses_files_without_comment_syntax_unless_given() {
+        let conf = tempdir().unwrap();
+        let system = tempdir().unwrap();
+        let dest = system.path().join("settings.json");
+        fs::write(&dest, "{}\n").unwrap();
+
+        assert!(matches!(
+            adopt_file(conf.path(), &dest, false, None, None),
+            Err(AdoptError::NoCommentSyntax(_))
+        ));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "{}\n");
+
+        let source = adopt_file(conf.path(), &dest, false, None, Some("//")).unwrap();
+        assert!(
+            fs::read_to_string(source)
+                .unwrap()
+                .starts_with("// pets: destfile=")
+        );
+    }
+
+    #[test]
+    fn adopt_refuses_into_outside_conf_dir() {
+        let conf = tempdir().unwrap();
+        let system = tempdir().unwrap();
+        let dest = system.path().join("motd");
+        fs::write(&dest, "hello\n").unwrap();
+
+        for into in ["/tmp", "../elsewhere", "web/../../elsewhere"] {
+            assert!(matches!(
+                adopt_file(conf.path(), &dest, false, Some(Path::new(into)), None),
+                Err(AdoptError::OutsideConfDir(_))
+            ));
+        }
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn with_modeline_keeps_shebang_first() {
+        let directives = vec!["destfile=/usr/local/bin/hello".to_string()];
+        assert_eq!(
+            with_modeline("#!/bin/sh\necho hi\n", "#", &directives),
+            "#!/bin/sh\n# pets: destfile=/usr/local/bin/hello\necho hi\n"
+        );
+    }
+
+    #[test]
+    fn comment_prefix_by_file_name() {
+        assert_eq!(
+            comment_prefix(Path::new("/etc/nginx/nginx.conf")),
+            Some("#")
+        );
+        assert_eq!(comment_prefix(Path::new("/etc/php/php.ini")), Some(";"));
+        assert_eq!(comment_prefix(Path::new("/home/me/.vimrc")), Some("\""));
+        assert_eq!(
+            comment_prefix(Path::new("/etc/app/config.jsonc")),
+            Some("//")
+        );
+        assert_eq!(comment_prefix(Path::new("/etc/app/config.json")), None);
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{actions, lock, summary::RunSummary};
+use crate::{
+    actions, lock,
+    planner::Validation,
+    privilege::{self, RootHelper},
+    summary::RunSummary,
+};
 use std::{process::ExitCode, time::Instant};
 
 use super::plan::load_and_plan;
@@ -6,6 +11,7 @@ use super::plan::load_and_plan;
 fn execute_actions(
     actions: Vec<actions::Action>,
     config: &actions::RunConfig,
+    mut helper: Option<&mut RootHelper>,
 ) -> (ExitCode, RunSummary) {
     let mut summary = RunSummary::default();
     if actions.is_empty() {
@@ -16,7 +22,14 @@ fn execute_actions(
     let mut exit_code = ExitCode::SUCCESS;
     for action in actions {
         let cause = action.cause();
-        match action.perform(config) {
+        let result = match helper.as_deref_mut() {
+            Some(helper) if action.is_privileged() => {
+                log::info!("{action} (as root)");
+                helper.perform(&action)
+            }
+            _ => action.perform(config),
+        };
+        match result {
             Ok(0) => summary.record(cause),
             Ok(_) => {
                 summary.record_error();
@@ -35,7 +48,15 @@ fn execute_actions(
     (exit_code, summary)
 }
 
-pub fn apply(conf_dir: &str, dry_run: bool, backup: bool) -> ExitCode {
+pub fn apply(
+    conf_dir: &str,
+    dry_run: bool,
+    diff_limit: u64,
+    backup: Option<actions::BackupStore>,
+    force_links: bool,
+    become_method: Option<privilege::Method>,
+    users: &[String],
+) -> ExitCode {
     let _lock = if dry_run {
         None
     } else {
@@ -50,7 +71,7 @@ pub fn apply(conf_dir: &str, dry_run: bool, backup: bool) -> ExitCode {
 
     let start_time = Instant::now();
 
-    let actions = match load_and_plan(conf_dir) {
+    let actions = match load_and_plan(conf_dir, force_links, users, Validation::Full) {
         Ok(a) => a,
         Err(code) => return code,
     };
@@ -59,9 +80,27 @@ pub fn apply(conf_dir: &str, dry_run: bool, backup: bool) -> ExitCode {
         log::info!("User requested dry-run mode, not applying any changes");
     }
 
-    let config = actions::RunConfig { dry_run, backup };
+    // Authenticate once, before changing anything.
+    let needs_root = actions.iter().any(actions::Action::is_privileged);
+    let mut helper = match become_method {
+        Some(method) if needs_root && !dry_run && uzers::get_current_uid() != 0 => {
+            match RootHelper::start(method, backup.as_ref(), diff_limit) {
+                Ok(helper) => Some(helper),
+                Err(err) => {
+                    log::error!("cannot become root: {err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => None,
+    };
 
-    let (exit_code, summary) = execute_actions(actions, &config);
+    let config = actions::RunConfig {
+        dry_run,
+        diff_limit,
+        backup,
+    };
+    let (exit_code, summary) = execute_actions(actions, &config, helper.as_mut());
     summary.log();
 
     log::info!(
@@ -75,7 +114,7 @@ pub fn apply(conf_dir: &str, dry_run: bool, backup: bool) -> ExitCode {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::actions::{Action, Cause, RunConfig};
+    use crate::actions::{Action, Cause, RunConfig, diff::DEFAULT_DIFF_LIMIT};
     use std::fs;
     use tempfile::tempdir;
 
@@ -90,20 +129,22 @@ mod tests {
     fn dry_run_config() -> RunConfig {
         RunConfig {
             dry_run: true,
-            backup: false,
+            diff_limit: DEFAULT_DIFF_LIMIT,
+            backup: None,
         }
     }
 
     fn real_config() -> RunConfig {
         RunConfig {
             dry_run: false,
-            backup: false,
+            diff_limit: DEFAULT_DIFF_LIMIT,
+            backup: None,
         }
     }
 
     #[test]
     fn execute_empty_actions_returns_success_with_skipped() {
-        let (code, summary) = execute_actions(vec![], &dry_run_config());
+        let (code, summary) = execute_actions(vec![], &dry_run_config(), None);
         assert!(is_success(code));
         assert_eq!(summary.as_parts(), vec!["1 already in sync"]);
     }
@@ -116,7 +157,7 @@ mod tests {
         fs::write(&src, b"content").unwrap();
 
         let action = Action::copy_file(Cause::Create, src, dest);
-        let (code, summary) = execute_actions(vec![action], &dry_run_config());
+        let (code, summary) = execute_actions(vec![action], &dry_run_config(), None);
         assert!(is_success(code));
         assert_eq!(summary.as_parts(), vec!["1 created"]);
     }
@@ -135,7 +176,7 @@ mod tests {
             Action::copy_file(Cause::Create, src1, dest1),
             Action::copy_file(Cause::Create, src2, dest2),
         ];
-        let (code, summary) = execute_actions(actions, &dry_run_config());
+        let (code, summary) = execute_actions(actions, &dry_run_config(), None);
         assert!(is_success(code));
         assert_eq!(summary.as_parts(), vec!["2 created"]);
     }
@@ -143,7 +184,7 @@ mod tests {
     #[test]
     fn execute_failing_command_records_error_and_stops() {
         let action = Action::command(Cause::Post, vec!["false".to_string()]);
-        let (code, summary) = execute_actions(vec![action], &real_config());
+        let (code, summary) = execute_actions(vec![action], &real_config(), None);
         assert!(is_failure(code));
         assert_eq!(summary.as_parts(), vec!["1 errors"]);
     }
@@ -156,7 +197,7 @@ mod tests {
         fs::write(&src, b"hello").unwrap();
 
         let action = Action::copy_file(Cause::Create, src, dest.clone());
-        let (code, _) = execute_actions(vec![action], &real_config());
+        let (code, _) = execute_actions(vec![action], &real_config(), None);
         assert!(is_success(code));
         assert_eq!(fs::read_to_string(&dest).unwrap(), "hello");
     }
@@ -171,7 +212,15 @@ mod tests {
         let mut f = fs::File::create(&file).unwrap();
         writeln!(f, "# pets: destfile={dest}").unwrap();
 
-        let code = apply(dir.path().to_str().unwrap(), true, false);
+        let code = apply(
+            dir.path().to_str().unwrap(),
+            true,
+            DEFAULT_DIFF_LIMIT,
+            None,
+            false,
+            None,
+            &[],
+        );
         assert!(is_success(code));
         assert!(!std::path::Path::new(dest).exists());
     }
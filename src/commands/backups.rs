@@ -0,0 +1,207 @@
+use crate::{
+    actions::{BackupStore, backup::Version},
+    facts::Facts,
+    lock, pet_files,
+};
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{self, Path, PathBuf},
+    process::ExitCode,
+};
+
+/// lists the backups of `dest`, or of every managed destination.
+pub fn list_backups(conf_dir: &str, store: &BackupStore, dest: Option<&Path>) -> ExitCode {
+    let dests = match dest {
+        Some(dest) => absolute(dest).map(|dest| vec![dest]),
+        None => managed_destinations(conf_dir),
+    };
+    let dests = match dests {
+        Ok(dests) => dests,
+        Err(code) => return code,
+    };
+
+    let mut found = false;
+    for dest in dests {
+        let versions = match store.versions(&dest) {
+            Ok(versions) => versions,
+            Err(err) => {
+                log::error!("cannot list backups of {}: {err}", dest.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        if versions.is_empty() {
+            continue;
+        }
+        found = true;
+        println!("{}", dest.display());
+        for version in versions {
+            println!("    {}", describe(&version));
+        }
+    }
+    if !found {
+        println!("No backups found");
+    }
+    ExitCode::SUCCESS
+}
+
+/// prints the content of a backup of `dest`, the newest unless `version`
+/// names one.
+pub fn show_backup(store: &BackupStore, dest: &Path, version: Option<&str>) -> ExitCode {
+    let version = match absolute(dest).and_then(|dest| find_version(store, &dest, version)) {
+        Ok(version) => version,
+        Err(code) => return code,
+    };
+    match fs::read(&version.path) {
+        Ok(content) => {
+            print!("{}", String::from_utf8_lossy(&content));
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            log::error!("cannot read {}: {err}", version.path.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// puts a backup of `dest` back in place, the newest unless `version` names
+/// one. What is at `dest` is backed up first.
+pub fn restore_backup(
+    store: &BackupStore,
+    dest: &Path,
+    version: Option<&str>,
+    dry_run: bool,
+) -> ExitCode {
+    let dest = match absolute(dest) {
+        Ok(dest) => dest,
+        Err(code) => return code,
+    };
+    let version = match find_version(store, &dest, version) {
+        Ok(version) => version,
+        Err(code) => return code,
+    };
+    log::info!(
+        "restoring {} from {}",
+        dest.display(),
+        version.path.display()
+    );
+    if dry_run {
+        return ExitCode::SUCCESS;
+    }
+
+    let _lock = match lock::Lock::acquire() {
+        Ok(lock) => lock,
+        Err(msg) => {
+            log::error!("{msg}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match store.restore(&dest, &version) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("cannot restore {}: {err}", dest.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn find_version(store: &BackupStore, dest: &Path, name: Option<&str>) -> Result<Version, ExitCode> {
+    match store.version(dest, name) {
+        Ok(Some(version)) => Ok(version),
+        Ok(None) => {
+            match name {
+                Some(name) => log::error!("no backup {name} of {}", dest.display()),
+                None => log::error!("no backups of {}", dest.display()),
+            }
+            Err(ExitCode::FAILURE)
+        }
+        Err(err) => {
+            log::error!("cannot list backups of {}: {err}", dest.display());
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn absolute(dest: &Path) -> Result<PathBuf, ExitCode> {
+    path::absolute(dest).map_err(|err| {
+        log::error!("{}: {err}", dest.display());
+        ExitCode::FAILURE
+    })
+}
+
+fn managed_destinations(conf_dir: &str) -> Result<Vec<PathBuf>, ExitCode> {
+    let facts = Facts::for_host(conf_dir, None).map_err(|err| {
+        log::error!("{err}");
+        ExitCode::FAILURE
+    })?;
+    let files = pet_files::load(conf_dir, &facts).map_err(|err| {
+        log::error!("{err}");
+        ExitCode::FAILURE
+    })?;
+    Ok(files
+        .iter()
+        .map(|pf| PathBuf::from(pf.destination()))
+        .collect())
+}
+
+/// describes a backup as its name, owner, mode and size.
+fn describe(version: &Version) -> String {
+    let metadata = match fs::symlink_metadata(&version.path) {
+        Ok(metadata) => metadata,
+        Err(err) => return format!("{}  ({err})", version.name),
+    };
+    let user = uzers::get_user_by_uid(metadata.uid()).map_or_else(
+        || metadata.uid().to_string(),
+        |user| user.name().to_string_lossy().into_owned(),
+    );
+    let group = uzers::get_group_by_gid(metadata.gid()).map_or_else(
+        || metadata.gid().to_string(),
+        |group| group.name().to_string_lossy().into_owned(),
+    );
+    let kind = if metadata.is_symlink() {
+        "link".to_string()
+    } else if metadata.is_dir() {
+        "directory".to_string()
+    } else {
+        format!("{} bytes", metadata.len())
+    };
+    format!(
+        "{}  {user}:{group} {:o}  {kind}",
+        version.name,
+        metadata.mode() & 0o7777
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::backup::Location;
+    use std::io;
+    use tempfile::tempdir;
+
+    fn is_success(code: ExitCode) -> bool {
+        format!("{code:?}") == format!("{:?}", ExitCode::SUCCESS)
+    }
+
+    #[test]
+    fn restore_backup_needs_a_matching_version() -> io::Result<()> {
+        let dir = tempdir()?;
+        let store = BackupStore::new(Location::Central(dir.path().join("store")), None, None);
+        let dest = dir.path().join("config");
+        assert!(!is_success(restore_backup(&store, &dest, None, true)));
+
+        fs::write(&dest, "old")?;
+        let saved = store.save(&dest)?;
+        fs::write(&dest, "new")?;
+        let name = saved.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(!is_success(restore_backup(
+            &store,
+            &dest,
+            Some("nope"),
+            true
+        )));
+        assert!(is_success(restore_backup(&store, &dest, Some(&name), true)));
+        assert_eq!(fs::read_to_string(&dest)?, "new");
+        Ok(())
+    }
+}
@@ -1,12 +1,23 @@
+use crate::planner::Validation;
 use std::process::ExitCode;
 
 use super::plan::load_and_plan;
 
-pub fn check(conf_dir: &str) -> ExitCode {
-    let actions = match load_and_plan(conf_dir) {
+pub fn check(
+    conf_dir: &str,
+    force_links: bool,
+    users: &[String],
+    validation: Validation,
+) -> ExitCode {
+    let actions = match load_and_plan(conf_dir, force_links, users, validation) {
         Ok(a) => a,
         Err(code) => return code,
     };
+    if validation == Validation::InspectOnly {
+        log::info!(
+            "Check mode: package searches, 'pre' validation commands, fact scripts and 'cmd:' probes were skipped"
+        );
+    }
 
     if actions.is_empty() {
         log::info!("Check mode: configuration is in sync");
@@ -18,7 +29,10 @@ pub fn check(conf_dir: &str) -> ExitCode {
         actions.len()
     );
     for action in &actions {
-        log::info!("{action}");
+        match action.gate() {
+            Some(gate) => log::info!("{action} (gated: {gate})"),
+            None => log::info!("{action}"),
+        }
     }
     ExitCode::FAILURE
 }
@@ -26,7 +40,7 @@ pub fn check(conf_dir: &str) -> ExitCode {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{fs, io::Write};
+    use std::{fs, io::Write, os::unix::fs::PermissionsExt};
     use tempfile::tempdir;
 
     fn is_success(code: ExitCode) -> bool {
@@ -40,7 +54,7 @@ mod tests {
     #[test]
     fn check_empty_dir_returns_success() {
         let dir = tempdir().unwrap();
-        let code = check(dir.path().to_str().unwrap());
+        let code = check(dir.path().to_str().unwrap(), false, &[], Validation::Full);
         assert!(is_success(code));
     }
 
@@ -51,9 +65,71 @@ mod tests {
         let mut f = fs::File::create(&file).unwrap();
         writeln!(f, "# pets: destfile=/tmp/pets-test-check-drift").unwrap();
 
-        let code = check(dir.path().to_str().unwrap());
+        let code = check(dir.path().to_str().unwrap(), false, &[], Validation::Full);
         assert!(is_failure(code));
 
         let _ = fs::remove_file("/tmp/pets-test-check-drift");
     }
+
+    #[test]
+    fn check_inspect_only_does_not_run_pre() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        let dest = out.path().join("dest");
+        let marker = out.path().join("pre-ran");
+        fs::write(
+            dir.path().join("test.conf"),
+            format!(
+                "# pets: destfile={}, pre=touch {}\n",
+                dest.display(),
+                marker.display()
+            ),
+        )
+        .unwrap();
+        let conf_dir = dir.path().to_str().unwrap();
+
+        assert!(is_failure(check(
+            conf_dir,
+            false,
+            &[],
+            Validation::InspectOnly
+        )));
+        assert!(!marker.exists());
+
+        assert!(is_failure(check(conf_dir, false, &[], Validation::Full)));
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn check_inspect_only_does_not_run_probes_or_fact_scripts() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        let probed = out.path().join("probed");
+        let script = dir.path().join(crate::facts::FACTS_DIR).join("rack");
+        fs::create_dir(script.parent().unwrap()).unwrap();
+        fs::write(
+            &script,
+            format!("#!/bin/sh\ntouch {}\necho rack=b2\n", probed.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(
+            dir.path().join("test.conf"),
+            format!(
+                "# pets: destfile={}, when=fact:rack=b2 | cmd:touch {}\n",
+                out.path().join("dest").display(),
+                probed.display()
+            ),
+        )
+        .unwrap();
+        let conf_dir = dir.path().to_str().unwrap();
+
+        assert!(is_failure(check(
+            conf_dir,
+            false,
+            &[],
+            Validation::InspectOnly
+        )));
+        assert!(!probed.exists());
+    }
 }
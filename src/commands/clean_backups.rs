@@ -1,43 +1,342 @@
-use crate::pet_files;
-use std::{fs, path::Path, process::ExitCode};
+use crate::{
+    actions::{BackupStore, backup::Version},
+    facts::Facts,
+    lock,
+    pet_files::{self, PetsFile},
+};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
 
-pub fn clean_backups(conf_dir: &str) -> ExitCode {
-    let files = match pet_files::load(conf_dir) {
+// Suffixes of the files pets leaves next to destinations
+const LEFTOVER_SUFFIXES: [&str; 2] = [".pets-backup", ".pets-tmp"];
+
+/// removes the backups of every managed destination, or with a retention
+/// policy only those the policy no longer keeps.
+pub fn clean_backups(conf_dir: &str, store: &BackupStore) -> ExitCode {
+    let files = match load_files(conf_dir) {
         Ok(files) => files,
-        Err(err) => {
-            log::error!("{err}");
-            return ExitCode::FAILURE;
-        }
+        Err(code) => return code,
     };
 
-    let removed = files
+    let mut removed = 0;
+    for pf in &files {
+        let dest = pf.destination();
+        match store.clean(Path::new(&dest)) {
+            Ok(backups) => {
+                for backup in &backups {
+                    log::info!("removed {}", backup.display());
+                }
+                removed += backups.len();
+            }
+            Err(e) => log::error!("failed to remove backups of {dest}: {e}"),
+        }
+    }
+
+    if removed == 0 {
+        log::info!("no backup files found");
+    } else {
+        log::info!("removed {removed} backup files");
+    }
+    ExitCode::SUCCESS
+}
+
+/// finds every `.pets-backup` and `.pets-tmp` below `paths`, or next to all
+/// destinations in the configuration, including those of files excluded by
+/// `when` conditions, and inside copied trees. Backups in the central
+/// `store` are included. Leftovers older than `older_than` are removed
+/// unless `dry_run`, asking first if `interactive`. Without `older_than`
+/// only temporary files, orphaned backups and the backups of managed
+/// destinations the retention policy of `store` no longer keeps are.
+pub fn scan_backups(
+    conf_dir: &str,
+    store: &BackupStore,
+    paths: &[PathBuf],
+    older_than: Option<Duration>,
+    dry_run: bool,
+    interactive: bool,
+) -> ExitCode {
+    let files = match load_files(conf_dir) {
+        Ok(files) => files,
+        Err(code) => return code,
+    };
+    let managed = files
         .iter()
-        .map(|pf| format!("{}.pets-backup", pf.destination()))
-        .filter(|backup| Path::new(backup).exists())
-        .filter(|backup| match fs::remove_file(backup) {
+        .map(|pf| PathBuf::from(pf.destination()))
+        .collect::<HashSet<_>>();
+    let mut leftovers = if paths.is_empty() {
+        destination_leftovers(&managed)
+    } else {
+        paths
+            .iter()
+            .flat_map(|path| find_leftovers(path, true))
+            .collect()
+    };
+    match store.all_versions() {
+        Ok(versions) => leftovers.extend(
+            versions
+                .into_iter()
+                .map(|(dest, version)| Leftover::Stored(dest, version)),
+        ),
+        Err(e) => log::error!("cannot read the backup store: {e}"),
+    }
+
+    let now = SystemTime::now();
+    if let Some(age) = older_than {
+        leftovers.retain(|leftover| is_older(leftover.path(), now, age));
+    } else {
+        let expired = expired_backups(store, &managed);
+        leftovers.retain(|leftover| {
+            is_temporary(leftover.path())
+                || !managed.contains(&leftover.destination())
+                || expired.contains(leftover.path())
+        });
+    }
+    if leftovers.is_empty() {
+        log::info!("no backup files found");
+        return ExitCode::SUCCESS;
+    }
+
+    // Nothing may write a temporary file or backup while they are removed.
+    let _lock = if dry_run {
+        None
+    } else {
+        match lock::Lock::acquire() {
+            Ok(lock) => Some(lock),
+            Err(msg) => {
+                log::error!("{msg}");
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+
+    let mut stdin = io::stdin().lock();
+    let (mut removed, mut freed, mut exit_code) = (0, 0, ExitCode::SUCCESS);
+    for leftover in &leftovers {
+        let path = leftover.path();
+        let size = disk_size(path);
+        let orphaned = !managed.contains(&leftover.destination());
+        println!(
+            "{}  {}{}",
+            path.display(),
+            format_size(size),
+            if orphaned { "  (orphaned)" } else { "" }
+        );
+        if dry_run {
+            continue;
+        }
+        let question = format!("remove {}?", path.display());
+        if interactive && !confirm(&mut stdin, &question) {
+            continue;
+        }
+        let result = match leftover {
+            Leftover::Sibling(path) => remove(path),
+            Leftover::Stored(dest, version) => store.remove(dest, version),
+        };
+        match result {
             Ok(()) => {
-                log::info!("removed {backup}");
-                true
+                removed += 1;
+                freed += size;
             }
             Err(e) => {
-                log::error!("failed to remove {backup}: {e}");
-                false
+                log::error!("failed to remove {}: {e}", path.display());
+                exit_code = ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if dry_run {
+        let total = leftovers
+            .iter()
+            .map(|leftover| disk_size(leftover.path()))
+            .sum();
+        log::info!(
+            "found {} backup files ({}), not removing them in dry-run mode",
+            leftovers.len(),
+            format_size(total)
+        );
+    } else {
+        log::info!("removed {removed} backup files ({})", format_size(freed));
+    }
+    exit_code
+}
+
+/// A backup or temporary file found by `scan_backups`.
+enum Leftover {
+    // Next to its destination, see `LEFTOVER_SUFFIXES`
+    Sibling(PathBuf),
+    // In the central backup store, with the destination it was taken of
+    Stored(PathBuf, Version),
+}
+
+impl Leftover {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Sibling(path) => path,
+            Self::Stored(_, version) => &version.path,
+        }
+    }
+
+    fn destination(&self) -> PathBuf {
+        match self {
+            Self::Sibling(path) => strip_suffix(path),
+            Self::Stored(dest, _) => dest.clone(),
+        }
+    }
+}
+
+fn load_files(conf_dir: &str) -> Result<Vec<PetsFile>, ExitCode> {
+    let facts = Facts::for_host(conf_dir, None).map_err(|err| {
+        log::error!("{err}");
+        ExitCode::FAILURE
+    })?;
+    pet_files::load(conf_dir, &facts).map_err(|err| {
+        log::error!("{err}");
+        ExitCode::FAILURE
+    })
+}
+
+/// returns the leftovers next to `destinations`, looking only into the
+/// directories holding them, and inside destinations that are copied trees.
+fn destination_leftovers(destinations: &HashSet<PathBuf>) -> Vec<Leftover> {
+    let mut dirs = destinations
+        .iter()
+        .filter_map(|dest| dest.parent())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    let trees = destinations
+        .iter()
+        .filter(|dest| fs::symlink_metadata(dest).is_ok_and(|metadata| metadata.is_dir()));
+    let mut leftovers = dirs
+        .iter()
+        .flat_map(|dir| find_leftovers(dir, false))
+        .chain(trees.flat_map(|tree| find_leftovers(tree, true)))
+        .collect::<Vec<_>>();
+    leftovers.sort_by(|a, b| a.path().cmp(b.path()));
+    leftovers.dedup_by(|a, b| a.path() == b.path());
+    leftovers
+}
+
+/// returns the backups of `destinations` the retention policy of `store`
+/// no longer keeps, in the store and next to them.
+fn expired_backups(store: &BackupStore, destinations: &HashSet<PathBuf>) -> HashSet<PathBuf> {
+    let mut expired = HashSet::new();
+    for dest in destinations {
+        for store in [store, &store.sibling()] {
+            match store.expired(dest) {
+                Ok(versions) => expired.extend(versions.into_iter().map(|version| version.path)),
+                Err(e) => log::error!("cannot read the backups of {}: {e}", dest.display()),
             }
+        }
+    }
+    expired
+}
+
+fn is_temporary(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(".pets-tmp")
+}
+
+fn is_leftover(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    LEFTOVER_SUFFIXES
+        .iter()
+        .any(|suffix| name.len() > suffix.len() && name.ends_with(suffix))
+}
+
+/// walks `root`, or with `recursive` unset only its entries, without
+/// following symlinks. Backed up directories are returned as a whole.
+fn find_leftovers(root: &Path, recursive: bool) -> Vec<Leftover> {
+    let mut leftovers = Vec::new();
+    let walk = WalkDir::new(root);
+    let mut entries = if recursive { walk } else { walk.max_depth(1) }.into_iter();
+    while let Some(entry) = entries.next() {
+        let Ok(entry) = entry else { continue };
+        if !is_leftover(entry.file_name()) {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            entries.skip_current_dir();
+        }
+        leftovers.push(Leftover::Sibling(entry.into_path()));
+    }
+    leftovers
+}
+
+fn strip_suffix(path: &Path) -> PathBuf {
+    let path = path.to_string_lossy();
+    LEFTOVER_SUFFIXES
+        .iter()
+        .find_map(|suffix| path.strip_suffix(suffix))
+        .map_or_else(|| PathBuf::from(path.as_ref()), PathBuf::from)
+}
+
+fn is_older(path: &Path, now: SystemTime, age: Duration) -> bool {
+    fs::symlink_metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| {
+            now.duration_since(modified)
+                .is_ok_and(|elapsed| elapsed > age)
         })
-        .count();
+}
 
-    if removed == 0 {
-        log::info!("no backup files found");
+/// returns the size of a file, or of everything below a directory.
+fn disk_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| !metadata.is_dir())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// asks `question` on stderr and reads the answer from `input`. Anything
+/// but yes means no.
+fn confirm(input: &mut impl BufRead, question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    input.read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
     } else {
-        log::info!("removed {removed} backup files");
+        fs::remove_file(path)
     }
-    ExitCode::SUCCESS
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{io::Write, path::PathBuf};
+    use crate::actions::backup::Location;
+    use std::io::Cursor;
     use tempfile::tempdir;
 
     fn is_success(code: ExitCode) -> bool {
@@ -47,7 +346,7 @@ mod tests {
     #[test]
     fn clean_backups_no_backups_returns_success() {
         let dir = tempdir().unwrap();
-        let code = clean_backups(dir.path().to_str().unwrap());
+        let code = clean_backups(dir.path().to_str().unwrap(), &BackupStore::default());
         assert!(is_success(code));
     }
 
@@ -64,8 +363,129 @@ mod tests {
         fs::write(&backup, b"old content").unwrap();
         assert!(backup.exists());
 
-        let code = clean_backups(dir.path().to_str().unwrap());
+        let code = clean_backups(dir.path().to_str().unwrap(), &BackupStore::default());
         assert!(is_success(code));
         assert!(!backup.exists());
     }
+
+    // Removing leftovers takes the pets lock, which only one test may hold.
+    static PETS_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn paths(leftovers: &[Leftover]) -> Vec<&Path> {
+        let mut paths = leftovers.iter().map(Leftover::path).collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn scan_backups_finds_leftovers_below_paths() {
+        let _lock = PETS_LOCK.lock().unwrap();
+        let conf = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+        let nested = dir.path().join("nested");
+        fs::create_dir_all(nested.join("tree.pets-backup")).unwrap();
+        fs::write(nested.join("tree.pets-backup/file"), b"old").unwrap();
+        fs::write(dir.path().join("gone.pets-backup"), b"old").unwrap();
+        fs::write(dir.path().join("config.pets-tmp"), b"partial").unwrap();
+        fs::write(dir.path().join("config"), b"current").unwrap();
+
+        let conf_dir = conf.path().to_str().unwrap();
+        let store = BackupStore::default();
+        let paths_arg = [dir.path().to_path_buf()];
+        assert_eq!(
+            paths(&find_leftovers(dir.path(), true)),
+            [
+                dir.path().join("config.pets-tmp"),
+                dir.path().join("gone.pets-backup"),
+                nested.join("tree.pets-backup"),
+            ]
+        );
+        assert_eq!(disk_size(&nested.join("tree.pets-backup")), 3);
+
+        let old_only = Some(Duration::from_secs(3600));
+        assert!(is_success(scan_backups(
+            conf_dir, &store, &paths_arg, old_only, false, false
+        )));
+        assert!(is_success(scan_backups(
+            conf_dir, &store, &paths_arg, None, true, false
+        )));
+        assert_eq!(find_leftovers(dir.path(), true).len(), 3);
+
+        assert!(is_success(scan_backups(
+            conf_dir, &store, &paths_arg, None, false, false
+        )));
+        assert!(find_leftovers(dir.path(), true).is_empty());
+        assert!(dir.path().join("config").exists());
+    }
+
+    #[test]
+    fn destination_leftovers_stay_next_to_destinations() {
+        let dir = tempdir().unwrap();
+        let etc = dir.path().join("etc");
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(etc.join("deep")).unwrap();
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(etc.join("hosts.pets-backup"), b"old").unwrap();
+        fs::write(etc.join("deep/other.pets-backup"), b"old").unwrap();
+        fs::write(tree.join("sub/file.pets-tmp"), b"partial").unwrap();
+
+        let dests = [etc.join("hosts"), tree.clone()].into_iter().collect();
+        assert_eq!(
+            paths(&destination_leftovers(&dests)),
+            [
+                etc.join("hosts.pets-backup"),
+                tree.join("sub/file.pets-tmp")
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_backups_includes_the_central_store() {
+        let _lock = PETS_LOCK.lock().unwrap();
+        let conf = tempdir().unwrap();
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("app.conf");
+        fs::write(&dest, b"current").unwrap();
+        fs::write(
+            conf.path().join("app.conf"),
+            format!("# pets: destfile={}\n", dest.display()),
+        )
+        .unwrap();
+        let gone = dir.path().join("gone.conf");
+        fs::write(&gone, b"unmanaged").unwrap();
+        let store = BackupStore::new(Location::Central(dir.path().join("store")), Some(2), None);
+        for _ in 0..3 {
+            store.save(&dest).unwrap();
+        }
+        store.save(&gone).unwrap();
+        // One more version than the policy keeps
+        let no_policy = BackupStore::new(Location::Central(dir.path().join("store")), None, None);
+        no_policy.save(&dest).unwrap();
+        let versions = store.all_versions().unwrap();
+        assert_eq!(versions.len(), 4);
+        assert_eq!(versions[0].0, dest);
+
+        let conf_dir = conf.path().to_str().unwrap();
+        assert!(is_success(scan_backups(
+            conf_dir,
+            &store,
+            &[],
+            None,
+            false,
+            false
+        )));
+        let kept = store.all_versions().unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|(backed_up, _)| *backed_up == dest));
+        assert!(dest.exists());
+    }
+
+    #[test]
+    fn confirm_accepts_only_yes() {
+        assert!(confirm(&mut Cursor::new("y\n"), "remove?"));
+        assert!(confirm(&mut Cursor::new("Yes\n"), "remove?"));
+        assert!(!confirm(&mut Cursor::new("\n"), "remove?"));
+        assert!(!confirm(&mut Cursor::new("nope\n"), "remove?"));
+        assert_eq!(format_size(1536), "1.5 KiB");
+    }
 }
@@ -0,0 +1,490 @@
+use crate::{
+    actions::{
+        Cause, Change,
+        diff::{self, Content},
+    },
+    planner::Validation,
+};
+use colored::Colorize;
+use similar::{DiffOp, DiffTag, TextDiff};
+use std::{
+    collections::HashSet,
+    env,
+    fmt::Write as _,
+    io::{self, IsTerminal, Write},
+    path::{self, Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+};
+
+use super::plan::load_and_plan;
+
+// Exit status of diff(1) when it runs into trouble
+const TROUBLE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    Unified,
+    SideBySide,
+    Stat,
+}
+
+impl DiffFormat {
+    /// picks the format from the `--stat` and `--side-by-side` flags.
+    pub fn new(stat: bool, side_by_side: bool) -> Self {
+        if stat {
+            Self::Stat
+        } else if side_by_side {
+            Self::SideBySide
+        } else {
+            Self::Unified
+        }
+    }
+}
+
+/// prints what applying the configuration would change below `paths`, or
+/// everywhere without `paths`. Files over `limit` bytes are not diffed. Like
+/// diff(1), exits with 0 if nothing would change, 1 if something would and 2
+/// on errors.
+pub fn diff(
+    conf_dir: &str,
+    paths: &[PathBuf],
+    format: DiffFormat,
+    pager: bool,
+    limit: u64,
+    force_links: bool,
+    users: &[String],
+) -> ExitCode {
+    // Like --check --no-exec, a diff never runs 'pre' commands.
+    let Ok(actions) = load_and_plan(conf_dir, force_links, users, Validation::InspectOnly) else {
+        return ExitCode::from(TROUBLE);
+    };
+    let paths = match paths
+        .iter()
+        .map(path::absolute)
+        .collect::<io::Result<Vec<_>>>()
+    {
+        Ok(paths) => paths,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::from(TROUBLE);
+        }
+    };
+
+    let mut trouble = false;
+    let mut changes = Vec::new();
+    for action in &actions {
+        match action.changes() {
+            Ok(found) => changes.extend(found),
+            Err(err) => {
+                log::error!("cannot compare {action}: {err}");
+                trouble = true;
+            }
+        }
+    }
+    changes.retain(|change| paths.is_empty() || paths.iter().any(|p| change.path().starts_with(p)));
+    // Packages and accounts have no path, but applying would change them.
+    let mut pending = String::new();
+    for action in actions.iter().filter(|_| paths.is_empty()) {
+        if matches!(action.cause(), Cause::Pkg | Cause::Group | Cause::User) {
+            let _ = writeln!(pending, "{}", action.to_string().yellow());
+        }
+    }
+
+    let output = match format {
+        DiffFormat::Stat => stat(&changes, limit),
+        DiffFormat::Unified | DiffFormat::SideBySide => changes
+            .iter()
+            .map(|change| render(change, format == DiffFormat::SideBySide, limit))
+            .collect(),
+    };
+    match output {
+        Ok(output) => show(&(pending.clone() + &output), pager),
+        Err(err) => {
+            log::error!("cannot compare: {err}");
+            trouble = true;
+        }
+    }
+
+    if trouble {
+        ExitCode::from(TROUBLE)
+    } else if changes.is_empty() && pending.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// renders a single change as a unified or side-by-side diff, or as a line
+/// describing it for anything but file content.
+fn render(change: &Change, side_by_side: bool, limit: u64) -> io::Result<String> {
+    let mut out = String::new();
+    let path = change.path();
+    match change {
+        Change::Content { old, new, .. } => {
+            let (old, new) = (old.as_ref(), new.as_ref());
+            let from = path.display().to_string();
+            let to = format!("{} (pets)", path.display());
+            match side_by_side
+                .then(|| diff::texts(old, new, limit))
+                .transpose()?
+            {
+                Some(Some((old_text, new_text))) => {
+                    let from = old.map_or("/dev/null", |_| &from);
+                    let to = new.map_or("/dev/null", |_| &to);
+                    let _ = writeln!(out, "{}", format!("--- {from}\n+++ {to}").bold());
+                    let diff = TextDiff::from_lines(&old_text, &new_text);
+                    out.push_str(&side_by_side_diff(&diff, width()));
+                }
+                _ => {
+                    for line in diff::content_diff(&from, &to, old, new, limit)? {
+                        let _ = writeln!(out, "{}", colorize(&line));
+                    }
+                }
+            }
+        }
+        Change::Link { old, new, .. } => {
+            let old = old
+                .as_ref()
+                .map_or("(not a link)".into(), |old| old.display().to_string());
+            let _ = writeln!(
+                out,
+                "{}: link {} → {}",
+                path.display(),
+                old.red(),
+                new.display().to_string().green()
+            );
+        }
+        Change::Mode { old, new, .. } => {
+            let old = old.map_or("(new)".into(), |old| format!("{old:o}"));
+            let new = format!("{new:o}");
+            let _ = writeln!(
+                out,
+                "{}: mode {} → {}",
+                path.display(),
+                old.red(),
+                new.green()
+            );
+        }
+        Change::Owner { old, new, .. } => {
+            let old = old.clone().unwrap_or_else(|| "(new)".into());
+            let _ = writeln!(
+                out,
+                "{}: owner {} → {}",
+                path.display(),
+                old.red(),
+                new.green()
+            );
+        }
+        Change::Attribute { description, .. } => {
+            let _ = writeln!(out, "{}: {}", path.display(), description.yellow());
+        }
+        Change::Removed { .. } => {
+            let _ = writeln!(out, "{}: {}", path.display(), "removed".red());
+        }
+        Change::Extra { .. } => {
+            let parent = path.parent().unwrap_or(Path::new("/"));
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let line = format!("Only in {}: {name}", parent.display());
+            let _ = writeln!(out, "{}", line.dimmed());
+        }
+    }
+    Ok(out)
+}
+
+fn colorize(line: &str) -> String {
+    if line.starts_with("---") || line.starts_with("+++") {
+        line.bold().to_string()
+    } else if line.starts_with("@@") {
+        line.cyan().to_string()
+    } else if line.starts_with('-') {
+        line.red().to_string()
+    } else if line.starts_with('+') {
+        line.green().to_string()
+    } else {
+        line.to_string()
+    }
+}
+
+/// returns the terminal width from $COLUMNS, or the default of diff(1).
+fn width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 20)
+        .unwrap_or(130)
+}
+
+/// puts old and new lines next to each other the way `diff -y` does, marking
+/// changed lines with '|', removed ones with '<' and added ones with '>'.
+fn side_by_side_diff(diff: &TextDiff<str>, width: usize) -> String {
+    let half = (width - 3) / 2;
+    let cell = |line: Option<&str>| {
+        let line = line.map_or("", |line| line.trim_end_matches(['\n', '\r']));
+        let line = line.replace('\t', "    ");
+        let line = line.chars().take(half).collect::<String>();
+        format!("{line:<half$}")
+    };
+
+    let mut out = String::new();
+    for (i, group) in diff.grouped_ops(3).iter().enumerate() {
+        if i > 0 {
+            let _ = writeln!(out, "{}", "...".cyan());
+        }
+        for op in group {
+            let (tag, old_range, new_range) = DiffOp::as_tag_tuple(op);
+            let rows = old_range.len().max(new_range.len());
+            for row in 0..rows {
+                let left = old_range.clone().nth(row).and_then(|i| diff.old_slice(i));
+                let right = new_range.clone().nth(row).and_then(|i| diff.new_slice(i));
+                let line = match (tag, left.is_some(), right.is_some()) {
+                    (DiffTag::Equal, ..) => format!("{}   {}", cell(left), cell(right)),
+                    (_, true, true) => format!(
+                        "{} {} {}",
+                        cell(left).red(),
+                        "|".yellow(),
+                        cell(right).green()
+                    ),
+                    (_, true, false) => format!("{} {}", cell(left).red(), "<".red()),
+                    (_, false, _) => {
+                        format!("{} {} {}", cell(None), ">".green(), cell(right).green())
+                    }
+                };
+                let _ = writeln!(out, "{}", line.trim_end());
+            }
+        }
+    }
+    out
+}
+
+/// summarizes changes like `git diff --stat`.
+fn stat(changes: &[Change], limit: u64) -> io::Result<String> {
+    let rows = changes
+        .iter()
+        .map(|change| {
+            let path = change.path().display().to_string();
+            Ok(match change {
+                Change::Content { old, new, .. } => {
+                    let (old, new) = (old.as_ref(), new.as_ref());
+                    if let Some(counts) = diff::line_counts(old, new, limit)? {
+                        (path, Some(counts), None)
+                    } else {
+                        let size = |content: Option<&Content>| content.map_or(Ok(0), Content::size);
+                        let (old, new) = (size(old)?, size(new)?);
+                        let kind = if old.max(new) > limit {
+                            "Too large"
+                        } else {
+                            "Bin"
+                        };
+                        let note = format!("{kind} {old} → {new} bytes");
+                        (path, None, Some(note))
+                    }
+                }
+                Change::Link { .. } => (path, None, Some("link".to_string())),
+                Change::Mode { old, new, .. } => {
+                    let old = old.map_or("new".into(), |old| format!("{old:o}"));
+                    (path, None, Some(format!("mode {old} → {new:o}")))
+                }
+                Change::Owner { new, .. } => (path, None, Some(format!("owner → {new}"))),
+                Change::Attribute { description, .. } => (path, None, Some(description.clone())),
+                Change::Removed { .. } => (path, None, Some("removed".to_string())),
+                Change::Extra { .. } => (path, None, Some("only at destination".to_string())),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let name_width = rows.iter().map(|(path, ..)| path.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    let (mut insertions, mut deletions) = (0, 0);
+    for (path, lines, note) in &rows {
+        let summary = match (lines, note) {
+            (Some((added, removed)), _) => {
+                insertions += added;
+                deletions += removed;
+                let scale = |n: usize| {
+                    if added + removed > 40 {
+                        n * 40 / (added + removed)
+                    } else {
+                        n
+                    }
+                };
+                format!(
+                    "{} {}{}",
+                    added + removed,
+                    "+".repeat(scale(*added)).green(),
+                    "-".repeat(scale(*removed)).red()
+                )
+            }
+            (None, note) => note.clone().unwrap_or_default(),
+        };
+        let _ = writeln!(out, " {path:<name_width$} | {summary}");
+    }
+    let files = rows
+        .iter()
+        .map(|(path, ..)| path)
+        .collect::<HashSet<_>>()
+        .len();
+    let _ = writeln!(
+        out,
+        " {}, {}, {}",
+        plural(files, "file changed", "files changed"),
+        plural(insertions, "insertion(+)", "insertions(+)"),
+        plural(deletions, "deletion(-)", "deletions(-)")
+    );
+    Ok(out)
+}
+
+/// returns `count` followed by the `one` or `many` form of a noun.
+fn plural(count: usize, one: &str, many: &str) -> String {
+    format!("{count} {}", if count == 1 { one } else { many })
+}
+
+/// writes `output` through $PAGER when `pager` is set and stdout is a
+/// terminal, or straight to stdout.
+fn show(output: &str, pager: bool) {
+    if output.is_empty() {
+        return;
+    }
+    if pager && io::stdout().is_terminal() {
+        let pager = env::var("PAGER")
+            .ok()
+            .filter(|pager| !pager.is_empty())
+            .unwrap_or_else(|| "less".to_string());
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&pager)
+            .env(
+                "LESS",
+                env::var("LESS").unwrap_or_else(|_| "FRX".to_string()),
+            )
+            .stdin(Stdio::piped())
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                if let Some(mut stdin) = child.stdin.take() {
+                    // The pager may quit before reading everything.
+                    let _ = stdin.write_all(output.as_bytes());
+                }
+                let _ = child.wait();
+                return;
+            }
+            Err(err) => log::warn!("cannot run pager '{pager}': {err}"),
+        }
+    }
+    print!("{output}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::diff::DEFAULT_DIFF_LIMIT;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn content(old: Option<&str>, new: Option<&str>) -> Change {
+        Change::Content {
+            path: PathBuf::from("/etc/pets-test"),
+            old: old.map(|old| Content::Text(old.to_string())),
+            new: new.map(|new| Content::Text(new.to_string())),
+        }
+    }
+
+    #[test]
+    fn render_shows_unified_and_side_by_side_diffs() {
+        let change = content(Some("a\nb\nc\n"), Some("a\nB\nc\nd\n"));
+        let unified = render(&change, false, DEFAULT_DIFF_LIMIT).unwrap();
+        assert!(unified.contains("--- /etc/pets-test"));
+        assert!(unified.contains("+++ /etc/pets-test (pets)"));
+        assert!(unified.contains("-b"));
+        assert!(unified.contains("+B"));
+
+        let side_by_side = render(&change, true, DEFAULT_DIFF_LIMIT).unwrap();
+        let lines = side_by_side.lines().skip(2).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with('b') && lines[1].contains(" | ") && lines[1].ends_with('B'));
+        assert!(lines[3].contains(" > ") && lines[3].ends_with('d'));
+
+        let created = render(&content(None, Some("x\n")), false, DEFAULT_DIFF_LIMIT).unwrap();
+        assert!(created.contains("--- /dev/null"));
+        let binary = render(
+            &content(Some("\0"), Some("\0\0")),
+            false,
+            DEFAULT_DIFF_LIMIT,
+        )
+        .unwrap();
+        assert!(binary.starts_with("Binary files"));
+    }
+
+    #[test]
+    fn stat_counts_insertions_and_deletions() {
+        let changes = [
+            content(Some("a\nb\n"), Some("a\nc\nd\n")),
+            Change::Mode {
+                path: PathBuf::from("/etc/pets-test"),
+                old: Some(0o644),
+                new: 0o600,
+            },
+        ];
+        let summary = stat(&changes, DEFAULT_DIFF_LIMIT).unwrap();
+        assert!(summary.contains("| 3 "));
+        assert!(summary.contains("| mode 644 → 600"));
+        assert!(summary.ends_with(" 1 file changed, 2 insertions(+), 1 deletion(-)\n"));
+
+        let changes = [
+            content(Some("a\n"), Some("b\n")),
+            Change::Mode {
+                path: PathBuf::from("/etc/pets-other"),
+                old: Some(0o644),
+                new: 0o600,
+            },
+            content(Some("a\n"), Some("b\n")),
+        ];
+        let summary = stat(&changes, DEFAULT_DIFF_LIMIT).unwrap();
+        assert!(summary.ends_with(" 2 files changed, 2 insertions(+), 2 deletions(-)\n"));
+    }
+
+    #[test]
+    fn diff_exits_like_diff_1() {
+        let dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let dest = dest_dir.path().join("dest");
+        let source = format!("# pets: destfile={}\nvalue=1\n", dest.display());
+        fs::write(dir.path().join("test.conf"), &source).unwrap();
+        let conf_dir = dir.path().to_str().unwrap();
+        let code = |paths: &[PathBuf]| {
+            format!(
+                "{:?}",
+                diff(
+                    conf_dir,
+                    paths,
+                    DiffFormat::Stat,
+                    false,
+                    DEFAULT_DIFF_LIMIT,
+                    false,
+                    &[],
+                )
+            )
+        };
+
+        assert_eq!(code(&[]), format!("{:?}", ExitCode::FAILURE));
+        assert_eq!(
+            code(&[dest_dir.path().join("other")]),
+            format!("{:?}", ExitCode::SUCCESS)
+        );
+        fs::write(&dest, &source).unwrap();
+        assert_eq!(code(&[]), format!("{:?}", ExitCode::SUCCESS));
+
+        // a pending account is a difference even though no file changes
+        let account = dest_dir.path().join("account");
+        let source = format!(
+            "# pets: destfile={}, create_group=pets_test_diff_group system\n",
+            account.display()
+        );
+        fs::write(dir.path().join("account.conf"), &source).unwrap();
+        fs::write(&account, &source).unwrap();
+        assert_eq!(code(&[]), format!("{:?}", ExitCode::FAILURE));
+        assert_eq!(
+            code(std::slice::from_ref(&dest)),
+            format!("{:?}", ExitCode::SUCCESS)
+        );
+    }
+}
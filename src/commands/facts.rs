@@ -0,0 +1,21 @@
+use crate::facts::Facts;
+use std::process::ExitCode;
+
+/// prints every fact and inventory variable known for this machine.
+pub fn facts(conf_dir: &str) -> ExitCode {
+    let facts = match Facts::for_host(conf_dir, None) {
+        Ok(facts) => facts,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for (key, value) in facts.iter() {
+        println!("{key}={value}");
+    }
+    for (key, value) in facts.vars() {
+        println!("var.{key}={value}");
+    }
+    ExitCode::SUCCESS
+}
@@ -1,9 +1,29 @@
-use crate::pet_files;
+use crate::{
+    facts::Facts,
+    pet_files::{self, AspectStatus, FileStatus},
+};
 use colored::Colorize;
-use std::process::ExitCode;
+use std::{fmt::Write as _, process::ExitCode};
 
-pub fn list(conf_dir: &str) -> ExitCode {
-    let files = match pet_files::load(conf_dir) {
+/// lists the managed files with the status of each aspect pets manages.
+/// With `only_drifted` files without drift are left out. `aspects` limits
+/// the columns, and the aspects drift is looked for in, to the ones named.
+pub fn list(
+    conf_dir: &str,
+    host: Option<&str>,
+    users: &[String],
+    only_drifted: bool,
+    aspects: &[String],
+) -> ExitCode {
+    let facts = match Facts::for_host(conf_dir, host) {
+        Ok(facts) => facts,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let files = match pet_files::load_for_users(conf_dir, &facts, users) {
         Ok(files) => files,
         Err(err) => {
             log::error!("{err}");
@@ -16,37 +36,179 @@ pub fn list(conf_dir: &str) -> ExitCode {
         return ExitCode::SUCCESS;
     }
 
-    let mut all_in_sync = true;
-    for pf in &files {
-        let dest = pf.destination();
-        let kind = if pf.is_symlink_config() {
-            "symlink"
-        } else {
-            "destfile"
-        };
-
-        match pf.sync_status() {
-            pet_files::SyncStatus::InSync => {
-                println!("{} {} ({kind}, in sync)", "✓".green(), dest);
-            }
-            pet_files::SyncStatus::Missing | pet_files::SyncStatus::LinkMissing => {
-                println!("{} {} ({kind}, missing)", "✗".red(), dest);
-                all_in_sync = false;
-            }
-            pet_files::SyncStatus::Modified => {
-                println!("{} {} ({kind}, modified)", "~".yellow(), dest);
-                all_in_sync = false;
-            }
-            pet_files::SyncStatus::LinkWrong => {
-                println!("{} {} ({kind}, wrong target)", "~".yellow(), dest);
-                all_in_sync = false;
-            }
-        }
+    if let Some(host) = host {
+        list_for_host(host, &files, &facts);
+        return ExitCode::SUCCESS;
     }
 
+    let shown = |name: &str| aspects.is_empty() || aspects.iter().any(|aspect| aspect == name);
+    let rows = files
+        .iter()
+        .map(|pf| {
+            let kind = if pf.is_absent_config() {
+                "absent"
+            } else if pf.is_symlink_config() {
+                "symlink"
+            } else {
+                "destfile"
+            };
+            let status = pf.status();
+            let cells = status
+                .aspects()
+                .into_iter()
+                .filter(|(name, _)| shown(name))
+                .map(|(_, aspect)| aspect.clone())
+                .collect::<Vec<_>>();
+            (pf.destination(), kind, cells)
+        })
+        .collect::<Vec<_>>();
+    let all_in_sync = rows
+        .iter()
+        .all(|(.., cells)| !cells.iter().any(AspectStatus::is_drifted));
+    print_table(&rows, &shown, only_drifted);
+
     if all_in_sync {
         ExitCode::SUCCESS
     } else {
         ExitCode::FAILURE
     }
 }
+
+/// prints a row per file and a column per shown aspect: 'ok', '-' where the
+/// aspect is not configured, or what drifted.
+fn print_table(
+    rows: &[(String, &str, Vec<AspectStatus>)],
+    shown: &dyn Fn(&str) -> bool,
+    only_drifted: bool,
+) {
+    let names = FileStatus::ASPECTS
+        .iter()
+        .filter(|name| shown(name))
+        .collect::<Vec<_>>();
+    let text = |aspect: &AspectStatus| match aspect {
+        AspectStatus::Unmanaged => "-".to_string(),
+        AspectStatus::InSync => "ok".to_string(),
+        AspectStatus::Drifted(description) => description.clone(),
+    };
+    let dest_width = rows
+        .iter()
+        .map(|(dest, ..)| dest.chars().count())
+        .chain(["DESTINATION".len()])
+        .max()
+        .unwrap_or(0);
+    let widths = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            rows.iter()
+                .map(|(.., cells)| text(&cells[i]).chars().count())
+                .chain([name.len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+
+    let mut header = format!("  {:<dest_width$}  {:<8}", "DESTINATION", "KIND");
+    for (name, width) in names.iter().zip(&widths) {
+        let _ = write!(header, "  {:<width$}", name.to_uppercase());
+    }
+    println!("{}", header.trim_end().bold());
+
+    for (dest, kind, cells) in rows {
+        let drifted = cells.iter().any(AspectStatus::is_drifted);
+        if only_drifted && !drifted {
+            continue;
+        }
+        let symbol = match cells.first() {
+            _ if !drifted => "✓".green(),
+            Some(AspectStatus::Drifted(content))
+                if names.first() == Some(&&"content")
+                    && ["missing", "present"].contains(&content.as_str()) =>
+            {
+                "✗".red()
+            }
+            _ => "~".yellow(),
+        };
+        let mut line = format!("{symbol} {dest:<dest_width$}  {kind:<8}");
+        for (cell, width) in cells.iter().zip(&widths) {
+            let padded = format!("{:<width$}", text(cell));
+            let colored = match cell {
+                AspectStatus::Unmanaged => padded.dimmed(),
+                AspectStatus::InSync => padded.green(),
+                AspectStatus::Drifted(_) => padded.yellow(),
+            };
+            let _ = write!(line, "  {colored}");
+        }
+        println!("{}", line.trim_end());
+    }
+}
+
+/// prints the files `host` would receive. Destination state is local to this
+/// machine, so no sync status is shown.
+fn list_for_host(host: &str, files: &[pet_files::PetsFile], facts: &Facts) {
+    let roles = facts.list("roles").collect::<Vec<_>>().join(", ");
+    let groups = facts.list("groups").collect::<Vec<_>>().join(", ");
+    println!("Files for host {host} (roles: {roles}; groups: {groups})");
+
+    for pf in files {
+        match pf.unmet_condition(facts) {
+            None => println!("{} {} ({})", "→".green(), pf.destination(), pf.source()),
+            Some(condition) => log::debug!(
+                "{host} would not receive '{}': 'when' condition '{condition}' not met",
+                pf.source()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use tempfile::tempdir;
+
+    fn is_success(code: ExitCode) -> bool {
+        format!("{code:?}") == format!("{:?}", ExitCode::SUCCESS)
+    }
+
+    #[test]
+    fn list_for_host_uses_inventory_roles() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join(crate::inventory::INVENTORY_FILE),
+            "[web*]\nroles = webserver\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("nginx.conf"),
+            "# pets: destfile=/tmp/pets-test-list-host-nginx, when=role:webserver\n",
+        )
+        .unwrap();
+
+        let facts = Facts::for_host(dir.path().to_str().unwrap(), Some("web1")).unwrap();
+        let files = pet_files::load(dir.path(), &facts).unwrap();
+        assert!(files[0].matches_conditions(&facts));
+
+        let other = Facts::for_host(dir.path().to_str().unwrap(), Some("db1")).unwrap();
+        assert!(!files[0].matches_conditions(&other));
+
+        let code = list(dir.path().to_str().unwrap(), Some("web1"), &[], false, &[]);
+        assert!(is_success(code));
+    }
+
+    #[test]
+    fn list_fails_on_mode_drift_unless_aspect_is_left_out() {
+        let dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        let dest = dest_dir.path().join("dest");
+        let source = format!("# pets: destfile={}, mode=0600\n", dest.display());
+        fs::write(dir.path().join("test.conf"), &source).unwrap();
+        fs::write(&dest, &source).unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let conf_dir = dir.path().to_str().unwrap();
+        assert!(!is_success(list(conf_dir, None, &[], true, &[])));
+        let content = ["content".to_string()];
+        assert!(is_success(list(conf_dir, None, &[], true, &content)));
+    }
+}
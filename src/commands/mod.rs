@@ -1,10 +1,21 @@
+mod adopt;
 mod apply;
+mod backups;
 mod check;
 mod clean_backups;
+mod diff;
+mod facts;
 mod list;
 mod plan;
+mod pull;
 
+pub use adopt::adopt;
 pub use apply::apply;
+pub use backups::{list_backups, restore_backup, show_backup};
 pub use check::check;
-pub use clean_backups::clean_backups;
+pub use clean_backups::{clean_backups, scan_backups};
+pub use diff::{DiffFormat, diff};
+pub use facts::facts;
 pub use list::list;
+pub use plan::plan;
+pub use pull::pull;
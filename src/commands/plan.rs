@@ -1,11 +1,117 @@
-use crate::{actions, pet_files, planner};
+use crate::{actions, facts::Facts, pet_files, planner, state::StateDir};
+use colored::Colorize;
 use std::process::ExitCode;
 
-pub fn load_and_plan(conf_dir: &str) -> Result<Vec<actions::Action>, ExitCode> {
-    let files = pet_files::load(conf_dir).map_err(|err| {
+/// prints the files and steps that would apply to this machine, or to one
+/// described by `overrides` layered on its facts. Checks against live
+/// destination state are skipped.
+pub fn plan(conf_dir: &str, overrides: &[(String, String)]) -> ExitCode {
+    let facts = match Facts::simulate(conf_dir, overrides) {
+        Ok(facts) => facts,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let files = match pet_files::load(conf_dir, &facts) {
+        Ok(files) => files,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("Plan for {}", describe(&facts));
+    let selected = select(&files, &facts);
+
+    for pf in &selected {
+        println!("{} {} ({})", "→".green(), pf.destination(), pf.source());
+        if pf.has_live_conditions() {
+            println!(
+                "    {} 'when' uses exists/cmd probes, evaluated on this machine",
+                "!".yellow()
+            );
+        }
+        for step in pf.static_plan() {
+            println!("    {step}");
+        }
+    }
+
+    if let Err(err) = planner::check_global_constraints(&files) {
+        log::error!("{err}");
+        return ExitCode::FAILURE;
+    }
+
+    println!(
+        "{} {} files selected; skipped checks against live state: content and link \
+         comparison, ownership, mode, installed packages, pre validation",
+        "Summary:".bold(),
+        selected.len()
+    );
+    ExitCode::SUCCESS
+}
+
+/// returns the facts identifying the machine planned for.
+fn describe(facts: &Facts) -> String {
+    [
+        "hostname",
+        "os",
+        "distro",
+        "arch",
+        "user",
+        "package_manager",
+    ]
+    .iter()
+    .filter_map(|key| facts.get(key).map(|value| format!("{key}={value}")))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// returns the files whose `when` conditions hold, printing the others.
+fn select<'a>(files: &'a [pet_files::PetsFile], facts: &Facts) -> Vec<&'a pet_files::PetsFile> {
+    files
+        .iter()
+        .filter(|pf| match pf.unmet_condition(facts) {
+            Some(condition) => {
+                println!(
+                    "{} {} (skipped: 'when' condition '{condition}' not met)",
+                    "-".dimmed(),
+                    pf.destination()
+                );
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+pub fn load_and_plan(
+    conf_dir: &str,
+    force_links: bool,
+    users: &[String],
+    validation: planner::Validation,
+) -> Result<Vec<actions::Action>, ExitCode> {
+    let mut facts = Facts::for_host(conf_dir, None).map_err(|err| {
+        log::error!("{err}");
+        ExitCode::FAILURE
+    })?;
+    if validation == planner::Validation::InspectOnly {
+        facts = facts.without_commands();
+    }
+
+    let mut files = pet_files::load_for_users(conf_dir, &facts, users).map_err(|err| {
         log::error!("{err}");
         ExitCode::FAILURE
     })?;
+    if force_links {
+        files.iter_mut().for_each(pet_files::PetsFile::force_links);
+    }
+    if let Some(state) = StateDir::from_env() {
+        for pf in &mut files {
+            pf.track_deployed(&state);
+        }
+    }
 
     log::info!("Found {} pets configuration files", files.len());
     if files.is_empty() {
@@ -17,8 +123,12 @@ pub fn load_and_plan(conf_dir: &str) -> Result<Vec<actions::Action>, ExitCode> {
         log::error!("{err}");
         ExitCode::FAILURE
     })?;
+    planner::check_accounts(&files).map_err(|err| {
+        log::error!("{err}");
+        ExitCode::FAILURE
+    })?;
 
-    Ok(planner::plan_actions(files))
+    Ok(planner::plan_actions(files, &facts, validation))
 }
 
 #[cfg(test)]
@@ -27,16 +137,72 @@ mod tests {
     use std::{fs, io::Write};
     use tempfile::tempdir;
 
+    fn is_success(code: ExitCode) -> bool {
+        format!("{code:?}") == format!("{:?}", ExitCode::SUCCESS)
+    }
+
+    #[test]
+    fn plan_simulates_other_host() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("test.conf");
+        let mut f = fs::File::create(&file).unwrap();
+        writeln!(
+            f,
+            "# pets: destfile=/tmp/pets-test-simulate, when=hostname:web1"
+        )
+        .unwrap();
+
+        let overrides = vec![
+            ("hostname".to_string(), "web1".to_string()),
+            ("distro".to_string(), "debian".to_string()),
+        ];
+        let code = plan(dir.path().to_str().unwrap(), &overrides);
+        assert!(is_success(code));
+        assert!(!std::path::Path::new("/tmp/pets-test-simulate").exists());
+    }
+
+    #[test]
+    fn plan_without_overrides_uses_local_facts() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("test.conf"),
+            format!(
+                "# pets: destfile=/tmp/pets-test-plan-local, when=os:{}\n",
+                std::env::consts::OS
+            ),
+        )
+        .unwrap();
+        let conf_dir = dir.path().to_str().unwrap();
+
+        let facts = Facts::simulate(conf_dir, &[]).unwrap();
+        let hostname = hostname::get().unwrap().into_string().unwrap();
+        assert!(describe(&facts).starts_with(&format!("hostname={hostname}, os=")));
+        let files = pet_files::load(conf_dir, &facts).unwrap();
+        assert_eq!(select(&files, &facts).len(), 1);
+        assert!(is_success(plan(conf_dir, &[])));
+    }
+
     #[test]
     fn load_and_plan_empty_dir_returns_empty() {
         let dir = tempdir().unwrap();
-        let actions = load_and_plan(dir.path().to_str().unwrap()).unwrap();
+        let actions = load_and_plan(
+            dir.path().to_str().unwrap(),
+            false,
+            &[],
+            planner::Validation::Full,
+        )
+        .unwrap();
         assert!(actions.is_empty());
     }
 
     #[test]
     fn load_and_plan_nonexistent_dir_returns_empty() {
-        let result = load_and_plan("/tmp/pets-definitely-does-not-exist-xyz");
+        let result = load_and_plan(
+            "/tmp/pets-definitely-does-not-exist-xyz",
+            false,
+            &[],
+            planner::Validation::Full,
+        );
         assert!(result.is_ok());
         assert!(result.unwrap().is_empty());
     }
@@ -48,7 +214,13 @@ mod tests {
         let mut f = fs::File::create(&file).unwrap();
         writeln!(f, "# pets: destfile=/tmp/pets-test-load-plan-output").unwrap();
 
-        let actions = load_and_plan(dir.path().to_str().unwrap()).unwrap();
+        let actions = load_and_plan(
+            dir.path().to_str().unwrap(),
+            false,
+            &[],
+            planner::Validation::Full,
+        )
+        .unwrap();
         assert!(!actions.is_empty());
 
         let _ = fs::remove_file("/tmp/pets-test-load-plan-output");
@@ -0,0 +1,377 @@
+use crate::{
+    facts::Facts,
+    pet_files::{self, PetsFile, SyncStatus},
+};
+use similar::TextDiff;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PullError {
+    #[error("{0} is not a managed destfile")]
+    NotManaged(String),
+    #[error("{0} has uncommitted changes, use --force to overwrite it")]
+    UncommittedChanges(String),
+    #[error("cannot tell whether {0} has uncommitted changes ({1}), use --force to overwrite it")]
+    GitStatus(String, String),
+    #[error("{0} is a copied .petsfile directory, which cannot be pulled")]
+    DirectoryTree(String),
+    #[error("{0} is not a text file")]
+    NotText(String),
+    #[error("IO error pulling file: {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// copies edited destinations back over their source files. Without `dests`
+/// every modified `destfile` is pulled.
+pub fn pull(conf_dir: &str, dests: &[PathBuf], force: bool, dry_run: bool) -> ExitCode {
+    let facts = match Facts::for_host(conf_dir, None) {
+        Ok(facts) => facts,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let files = match pet_files::load(conf_dir, &facts) {
+        Ok(files) => files,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let files = files
+        .iter()
+        .filter(|pf| !pf.is_symlink_config() && pf.matches_conditions(&facts))
+        .collect::<Vec<_>>();
+    let selected = match select(&files, dests) {
+        Ok(selected) => selected,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut pulled = 0;
+    for pf in selected {
+        if pf.is_tree_config() {
+            if dests.is_empty() {
+                log::debug!("skipping .petsfile directory {}", pf.destination());
+            } else {
+                log::error!("{}", PullError::DirectoryTree(pf.destination()));
+                exit_code = ExitCode::FAILURE;
+            }
+            continue;
+        }
+        if pf.sync_status() != SyncStatus::Modified {
+            log::info!("{} is not modified, nothing to pull", pf.destination());
+            continue;
+        }
+        match pull_file(pf, force, dry_run) {
+            Ok(()) => pulled += 1,
+            Err(err) => {
+                log::error!("{err}");
+                exit_code = ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if dry_run {
+        log::info!("User requested dry-run mode, not pulling any changes");
+    } else if pulled > 0 {
+        log::info!("pulled {pulled} files into {conf_dir}");
+    }
+    exit_code
+}
+
+/// returns the files deployed to `dests`, or all of them if none are given.
+fn select<'a>(files: &[&'a PetsFile], dests: &[PathBuf]) -> Result<Vec<&'a PetsFile>, PullError> {
+    if dests.is_empty() {
+        return Ok(files.to_vec());
+    }
+
+    let cwd = env::current_dir()?;
+    dests
+        .iter()
+        .map(|dest| {
+            let dest = cwd.join(dest);
+            files
+                .iter()
+                .find(|pf| Path::new(&pf.destination()) == dest)
+                .copied()
+                .ok_or_else(|| PullError::NotManaged(dest.display().to_string()))
+        })
+        .collect()
+}
+
+fn pull_file(pf: &PetsFile, force: bool, dry_run: bool) -> Result<(), PullError> {
+    let source = Path::new(pf.source());
+    let dest = pf.destination();
+    if !force {
+        match has_uncommitted_changes(source) {
+            Ok(false) => {}
+            Ok(true) => {
+                return Err(PullError::UncommittedChanges(source.display().to_string()));
+            }
+            Err(err) => return Err(PullError::GitStatus(source.display().to_string(), err)),
+        }
+    }
+
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidData => PullError::NotText(path.display().to_string()),
+            _ => err.into(),
+        })
+    };
+    let current = read(source)?;
+    let pulled = with_source_modelines(&current, &read(Path::new(&dest))?);
+
+    for line in pull_diff(pf.source(), &dest, &current, &pulled).lines() {
+        log::info!("{line}");
+    }
+
+    if !dry_run {
+        fs::write(source, pulled)?;
+        log::info!("pulled {dest} into {}", source.display());
+    }
+    Ok(())
+}
+
+/// returns the change pulling makes to the source, from its `current`
+/// content in the repository to the `pulled` one from the destination.
+fn pull_diff(source: &str, dest: &str, current: &str, pulled: &str) -> String {
+    TextDiff::from_lines(current, pulled)
+        .unified_diff()
+        .header(source, dest)
+        .to_string()
+}
+
+/// returns `dest` with the modelines of `source` restored at their original
+/// line numbers, so edits to the destination never drop or alter directives.
+fn with_source_modelines(source: &str, dest: &str) -> String {
+    let mut lines = dest
+        .lines()
+        .filter(|line| !line.contains("pets:"))
+        .collect::<Vec<_>>();
+    for (index, line) in source.lines().enumerate() {
+        if line.contains("pets:") {
+            lines.insert(index.min(lines.len()), line);
+        }
+    }
+
+    let mut pulled = lines.join("\n");
+    if dest.ends_with('\n') || dest.is_empty() {
+        pulled.push('\n');
+    }
+    pulled
+}
+
+/// returns true if git reports changes to `path`, including never committed
+/// files. Sources outside a git repository are never considered dirty. An
+/// error is returned when git cannot tell, as for a repository without git
+/// installed.
+fn has_uncommitted_changes(path: &Path) -> Result<bool, String> {
+    let Some(dir) = path.parent() else {
+        return Ok(false);
+    };
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["status", "--porcelain", "--"])
+        .arg(path)
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null())
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => Ok(!output.stdout.is_empty()),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("not a git repository") {
+                Ok(false)
+            } else {
+                Err(format!("git status failed: {}", stderr.trim()))
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if dir.ancestors().any(|dir| dir.join(".git").exists()) {
+                Err("git is not installed".to_string())
+            } else {
+                Ok(false)
+            }
+        }
+        Err(err) => Err(format!("cannot run git: {err}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn is_success(code: ExitCode) -> bool {
+        format!("{code:?}") == format!("{:?}", ExitCode::SUCCESS)
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=pets", "-c", "user.email=pets@example.com"])
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn setup() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempdir().unwrap();
+        let conf = dir.path().join("conf");
+        fs::create_dir(&conf).unwrap();
+        let dest = dir.path().join("foo.conf");
+        let source = conf.join("foo.conf");
+        fs::write(
+            &source,
+            format!("# pets: destfile={}\nport = 80\n", dest.display()),
+        )
+        .unwrap();
+        (dir, source, dest)
+    }
+
+    #[test]
+    fn with_source_modelines_keeps_header() {
+        let source = "#!/bin/sh\n# pets: destfile=/usr/bin/x\necho old\n";
+        let dest = "#!/bin/sh\necho new\n";
+        assert_eq!(
+            with_source_modelines(source, dest),
+            "#!/bin/sh\n# pets: destfile=/usr/bin/x\necho new\n"
+        );
+    }
+
+    #[test]
+    fn with_source_modelines_ignores_edited_directives() {
+        let source = "# pets: destfile=/etc/x, mode=0644\na\n";
+        let dest = "# pets: destfile=/etc/x, mode=0777\nb\n";
+        assert_eq!(
+            with_source_modelines(source, dest),
+            "# pets: destfile=/etc/x, mode=0644\nb\n"
+        );
+    }
+
+    #[test]
+    fn pull_copies_destination_back_into_source() {
+        let (dir, source, dest) = setup();
+        fs::write(&dest, "port = 8080\n").unwrap();
+
+        let code = pull(dir.path().join("conf").to_str().unwrap(), &[], false, false);
+        assert!(is_success(code));
+        assert_eq!(
+            fs::read_to_string(&source).unwrap(),
+            format!("# pets: destfile={}\nport = 8080\n", dest.display())
+        );
+    }
+
+    #[test]
+    fn pull_dry_run_leaves_source_alone() {
+        let (dir, source, dest) = setup();
+        let before = fs::read_to_string(&source).unwrap();
+        fs::write(&dest, "port = 8080\n").unwrap();
+
+        let code = pull(dir.path().join("conf").to_str().unwrap(), &[], false, true);
+        assert!(is_success(code));
+        assert_eq!(fs::read_to_string(&source).unwrap(), before);
+    }
+
+    #[test]
+    fn pull_refuses_uncommitted_source_unless_forced() {
+        let (dir, source, dest) = setup();
+        let conf = dir.path().join("conf");
+        git(&conf, &["init", "-q"]);
+        git(&conf, &["add", "."]);
+        git(&conf, &["commit", "-q", "-m", "init"]);
+        fs::write(&source, fs::read_to_string(&source).unwrap() + "# wip\n").unwrap();
+        fs::write(&dest, "port = 8080\n").unwrap();
+
+        assert_eq!(has_uncommitted_changes(&source), Ok(true));
+        let code = pull(conf.to_str().unwrap(), &[], false, false);
+        assert!(!is_success(code));
+        assert!(fs::read_to_string(&source).unwrap().ends_with("# wip\n"));
+
+        let code = pull(conf.to_str().unwrap(), &[], true, false);
+        assert!(is_success(code));
+        assert!(
+            fs::read_to_string(&source)
+                .unwrap()
+                .ends_with("port = 8080\n")
+        );
+    }
+
+    #[test]
+    fn has_uncommitted_changes_fails_when_git_cannot_tell() {
+        let (dir, source, _) = setup();
+        let conf = dir.path().join("conf");
+        assert_eq!(has_uncommitted_changes(&source), Ok(false));
+
+        git(&conf, &["init", "-q"]);
+        fs::write(conf.join(".git/index"), "garbage\n").unwrap();
+        assert!(has_uncommitted_changes(&source).is_err());
+    }
+
+    #[test]
+    fn pull_diff_labels_source_as_old_side() {
+        let diff = pull_diff(
+            "/conf/app.conf",
+            "/etc/app.conf",
+            "port = 80\n",
+            "port = 8080\n",
+        );
+        assert!(diff.starts_with("--- /conf/app.conf\n+++ /etc/app.conf\n"));
+        assert!(diff.contains("-port = 80\n+port = 8080"));
+    }
+
+    #[test]
+    fn pull_skips_petsfile_directories() {
+        let dir = tempdir().unwrap();
+        let conf = dir.path().join("conf");
+        let tree = conf.join("tree");
+        fs::create_dir_all(&tree).unwrap();
+        let dest = dir.path().join("tree-dest");
+        fs::write(
+            tree.join(".petsfile"),
+            format!("# pets: destfile={}\n", dest.display()),
+        )
+        .unwrap();
+        fs::write(tree.join("a"), "a\n").unwrap();
+        fs::create_dir(&dest).unwrap();
+        fs::write(dest.join("a"), "edited\n").unwrap();
+
+        let conf = conf.to_str().unwrap();
+        assert!(is_success(pull(conf, &[], false, false)));
+        assert!(!is_success(pull(
+            conf,
+            std::slice::from_ref(&dest),
+            false,
+            false
+        )));
+        assert_eq!(fs::read_to_string(tree.join("a")).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn pull_unknown_destination_fails() {
+        let (dir, _, _) = setup();
+        let code = pull(
+            dir.path().join("conf").to_str().unwrap(),
+            &[PathBuf::from("/etc/pets-not-managed")],
+            false,
+            false,
+        );
+        assert!(!is_success(code));
+    }
+}
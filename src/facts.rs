@@ -0,0 +1,646 @@
+use crate::{
+    actions::package_manager,
+    inventory::{HostEntry, Inventory, InventoryError},
+};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Arc, OnceLock},
+    thread,
+};
+use uzers::os::unix::UserExt;
+
+/// Directory in the configuration directory holding custom fact scripts.
+pub const FACTS_DIR: &str = "facts.d";
+
+/// Names of the built-in facts. Fact scripts cannot override them.
+const BUILTIN_FACTS: &[&str] = &[
+    "hostname",
+    "os",
+    "arch",
+    "user",
+    "kernel",
+    "distro",
+    "distro_like",
+    "distro_version",
+    "cpu_count",
+    "memory",
+    "virtualisation",
+    "roles",
+    "groups",
+    "package_manager",
+];
+
+/// Facts describe the system pets is running on. They are gathered once per
+/// run and consulted when evaluating `when` conditions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Facts {
+    values: BTreeMap<String, String>,
+    // Per-host variables assigned by the inventory.
+    vars: BTreeMap<String, String>,
+    // Environment consulted by `env:` conditions.
+    env: BTreeMap<String, String>,
+    deferred: Option<Deferred>,
+    // Deferred facts stay unknown rather than run commands to gather them.
+    no_commands: bool,
+}
+
+/// Facts that take running commands to gather: `virtualisation` and the
+/// output of fact scripts. They are gathered the first time one of them is
+/// looked up, so commands that never need them never run them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Deferred {
+    // Directory holding fact scripts, if any.
+    scripts: Option<PathBuf>,
+    values: Arc<OnceLock<BTreeMap<String, String>>>,
+}
+
+impl Deferred {
+    fn values(&self) -> &BTreeMap<String, String> {
+        self.values.get_or_init(|| {
+            let mut values = BTreeMap::new();
+            values.insert("virtualisation".to_string(), virtualisation());
+            if let Some(dir) = &self.scripts {
+                values.extend(run_fact_scripts(dir));
+            }
+            log::debug!("gathered deferred facts: {values:?}");
+            values
+        })
+    }
+}
+
+impl Facts {
+    /// gathers local facts and applies the inventory entry for `hostname`,
+    /// or for the local hostname when none is given.
+    pub fn for_host(conf_dir: &str, hostname: Option<&str>) -> Result<Self, InventoryError> {
+        let mut facts = Self::gather();
+        facts.deferred = Some(Deferred {
+            scripts: Some(Path::new(conf_dir).join(FACTS_DIR)),
+            ..Deferred::default()
+        });
+        if let Some(hostname) = hostname {
+            facts.insert("hostname", hostname.to_string());
+        }
+        facts.apply_inventory(conf_dir)?;
+        Ok(facts)
+    }
+
+    pub fn gather() -> Self {
+        let mut facts = Self::default();
+
+        if let Some(host) = hostname::get().ok().and_then(|h| h.into_string().ok()) {
+            facts.insert("hostname", host);
+        }
+        facts.insert("os", env::consts::OS.to_string());
+        facts.insert("arch", env::consts::ARCH.to_string());
+        if let Some(user) = uzers::get_current_username() {
+            facts.insert("user", user.to_string_lossy().into_owned());
+        }
+        if let Some(kernel) = kernel_release() {
+            facts.insert("kernel", kernel);
+        }
+        if let Ok(content) = fs::read_to_string("/etc/os-release") {
+            facts.extend_from_os_release(&content);
+        }
+        if let Ok(cpus) = thread::available_parallelism() {
+            facts.insert("cpu_count", cpus.to_string());
+        }
+        if let Some(memory) = memory_mib() {
+            facts.insert("memory", memory.to_string());
+        }
+        facts.deferred = Some(Deferred::default());
+        facts.env = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+
+        log::debug!("gathered facts: {:?}", facts.values);
+        facts
+    }
+
+    /// describes this machine with `overrides` layered on top, to plan for
+    /// another one. Overriding the OS or distribution drops the local
+    /// distribution details that are not given, and the package manager is
+    /// derived from the simulated distribution and `~` from the simulated
+    /// user, unless given.
+    pub fn simulate(
+        conf_dir: &str,
+        overrides: &[(String, String)],
+    ) -> Result<Self, InventoryError> {
+        let given = |key: &str| overrides.iter().any(|(name, _)| name == key);
+        let hostname = overrides
+            .iter()
+            .find(|(key, _)| key == "hostname")
+            .map(|(_, hostname)| hostname.as_str());
+        let mut facts = Self::for_host(conf_dir, hostname)?;
+        if given("os") || given("distro") {
+            for local in ["distro", "distro_like", "distro_version"] {
+                facts.values.remove(local);
+            }
+        }
+        for (key, value) in overrides {
+            match key.strip_prefix("env.") {
+                Some(name) => {
+                    facts.env.insert(name.to_string(), value.clone());
+                }
+                None => facts.insert(key, value.clone()),
+            }
+        }
+        facts.apply_inventory(conf_dir)?;
+
+        if facts.get("package_manager").is_none() && (given("os") || given("distro")) {
+            if let Some(pkg_manager) =
+                package_manager::for_system(facts.get("os"), facts.distro_family())
+            {
+                facts.insert("package_manager", pkg_manager.to_string());
+            }
+        }
+
+        if let Some(user) = overrides.iter().find(|(key, _)| key == "user") {
+            let user = user.1.clone();
+            facts.vars.entry("HOME".to_string()).or_insert_with(|| {
+                uzers::get_user_by_name(&user).map_or_else(
+                    || {
+                        if user == "root" {
+                            "/root".to_string()
+                        } else {
+                            format!("/home/{user}")
+                        }
+                    },
+                    |u| u.home_dir().to_string_lossy().into_owned(),
+                )
+            });
+            facts.vars.entry("USER".to_string()).or_insert(user);
+        }
+
+        Ok(facts)
+    }
+
+    /// returns these facts without running commands: fact scripts and
+    /// `systemd-detect-virt` are not run, and `cmd:` probes are not either,
+    /// so the facts and conditions that need them are unknown.
+    pub fn without_commands(mut self) -> Self {
+        self.no_commands = true;
+        self
+    }
+
+    /// returns false if commands may not run to gather facts or probe.
+    pub fn runs_commands(&self) -> bool {
+        !self.no_commands
+    }
+
+    /// returns true if `key` could only be known by running a command these
+    /// facts may not run.
+    pub fn is_unknown(&self, key: &str) -> bool {
+        self.no_commands
+            && self.deferred.is_some()
+            && !self.values.contains_key(key)
+            && (key == "virtualisation" || !BUILTIN_FACTS.contains(&key))
+    }
+
+    /// returns these facts as seen by `user`, for files deployed into that
+    /// user's home.
+    pub fn for_user(&self, user: &str) -> Self {
+        let mut facts = self.clone();
+        facts.insert("user", user.to_string());
+        facts
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        if let Some(value) = self.values.get(key) {
+            return Some(value);
+        }
+        if self.no_commands || (key != "virtualisation" && BUILTIN_FACTS.contains(&key)) {
+            return None;
+        }
+        self.deferred
+            .as_ref()?
+            .values()
+            .get(key)
+            .map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: &str, value: String) {
+        self.values.insert(key.to_string(), value);
+    }
+
+    /// returns the value of environment variable `name` on this host.
+    pub fn env(&self, name: &str) -> Option<&str> {
+        self.env.get(name).map(String::as_str)
+    }
+
+    /// returns the whitespace separated entries of a list-valued fact.
+    pub fn list(&self, key: &str) -> impl Iterator<Item = &str> {
+        self.get(key).into_iter().flat_map(str::split_whitespace)
+    }
+
+    /// returns the distribution ID followed by every ID in `ID_LIKE`.
+    pub fn distro_family(&self) -> impl Iterator<Item = &str> {
+        self.get("distro")
+            .into_iter()
+            .chain(self.list("distro_like"))
+    }
+
+    /// returns every fact in name order, gathering deferred ones.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        let deferred = self
+            .deferred
+            .iter()
+            .filter(|_| !self.no_commands)
+            .flat_map(Deferred::values);
+        deferred
+            .filter(|(key, _)| !self.values.contains_key(*key))
+            .chain(&self.values)
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+    }
+
+    pub fn vars(&self) -> &BTreeMap<String, String> {
+        &self.vars
+    }
+
+    /// assigns the roles, groups and variables the inventory in `conf_dir`
+    /// gives to the host named by the `hostname` fact.
+    fn apply_inventory(&mut self, conf_dir: &str) -> Result<(), InventoryError> {
+        let inventory = Inventory::load(conf_dir)?;
+        let entry = inventory.host(self.get("hostname").unwrap_or_default());
+        self.apply_inventory_entry(entry);
+        Ok(())
+    }
+
+    fn apply_inventory_entry(&mut self, entry: HostEntry) {
+        self.insert("roles", entry.roles.join(" "));
+        self.insert("groups", entry.groups.join(" "));
+        self.vars = entry.vars;
+    }
+
+    fn extend_from_os_release(&mut self, content: &str) {
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let fact = match key.trim() {
+                "ID" => "distro",
+                "ID_LIKE" => "distro_like",
+                "VERSION_ID" => "distro_version",
+                _ => continue,
+            };
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            self.insert(fact, value.to_ascii_lowercase());
+        }
+    }
+}
+
+/// runs every executable in `dir`, in name order, and merges the facts they
+/// print. A failing script is reported and skipped, and so are facts named
+/// like a built-in one.
+fn run_fact_scripts(dir: &Path) -> BTreeMap<String, String> {
+    let mut facts = BTreeMap::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return facts;
+    };
+    let mut scripts = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            fs::metadata(path)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+        .collect::<Vec<_>>();
+    scripts.sort();
+
+    for script in scripts {
+        let output = match Command::new(&script).stdin(Stdio::null()).output() {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                log::warn!(
+                    "fact script {} failed with {}, skipping",
+                    script.display(),
+                    output.status
+                );
+                continue;
+            }
+            Err(err) => {
+                log::warn!("cannot run fact script {}: {err}", script.display());
+                continue;
+            }
+        };
+
+        match parse_fact_output(&String::from_utf8_lossy(&output.stdout)) {
+            Ok(pairs) => {
+                for (key, value) in pairs {
+                    if BUILTIN_FACTS.contains(&key.as_str()) {
+                        log::warn!(
+                            "fact script {}: ignoring '{key}', it is a built-in fact",
+                            script.display()
+                        );
+                    } else {
+                        facts.insert(key, value);
+                    }
+                }
+            }
+            Err(err) => log::warn!("fact script {}: {err}", script.display()),
+        }
+    }
+    facts
+}
+
+/// parses fact script output, either a JSON object or `key=value` lines.
+/// Nested JSON objects are flattened into dotted keys.
+fn parse_fact_output(output: &str) -> Result<Vec<(String, String)>, String> {
+    let output = output.trim();
+    if output.starts_with('{') {
+        let value: serde_json::Value =
+            serde_json::from_str(output).map_err(|err| format!("invalid JSON output: {err}"))?;
+        let mut pairs = Vec::new();
+        flatten_json("", &value, &mut pairs);
+        return Ok(pairs);
+    }
+
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(format!("invalid output line '{line}'")),
+        })
+        .collect()
+}
+
+fn flatten_json(prefix: &str, value: &serde_json::Value, pairs: &mut Vec<(String, String)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten_json(&key, value, pairs);
+            }
+        }
+        serde_json::Value::String(s) => pairs.push((prefix.to_string(), s.clone())),
+        serde_json::Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(|item| match item {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>();
+            pairs.push((prefix.to_string(), items.join(" ")));
+        }
+        other => pairs.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+/// returns total memory in MiB from `/proc/meminfo`.
+fn memory_mib() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map(|kib| kib / 1024)
+}
+
+/// returns the virtualisation or container technology pets runs under, or
+/// `none` on bare metal.
+fn virtualisation() -> String {
+    if let Ok(output) = Command::new("systemd-detect-virt").output() {
+        let detected = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !detected.is_empty() {
+            return detected;
+        }
+    }
+    if Path::new("/.dockerenv").exists() {
+        return "docker".to_string();
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return "podman".to_string();
+    }
+    let hypervisor = fs::read_to_string("/proc/cpuinfo").is_ok_and(|cpuinfo| {
+        cpuinfo.lines().any(|line| {
+            line.starts_with("flags") && line.split_whitespace().any(|f| f == "hypervisor")
+        })
+    });
+    if hypervisor {
+        "vm".to_string()
+    } else {
+        "none".to_string()
+    }
+}
+
+fn kernel_release() -> Option<String> {
+    if let Ok(release) = fs::read_to_string("/proc/sys/kernel/osrelease") {
+        return Some(release.trim().to_string());
+    }
+    Command::new("uname")
+        .arg("-r")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gather_includes_builtin_facts() {
+        let facts = Facts::gather();
+        assert_eq!(facts.get("os"), Some(env::consts::OS));
+        assert_eq!(facts.get("arch"), Some(env::consts::ARCH));
+        assert_eq!(facts.env("HOME"), env::var("HOME").ok().as_deref());
+    }
+
+    #[test]
+    fn extend_from_os_release_parses_distro_fields() {
+        let mut facts = Facts::default();
+        facts.extend_from_os_release(
+            "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\nVERSION_ID=\"22.04\"\n",
+        );
+        assert_eq!(facts.get("distro"), Some("ubuntu"));
+        assert_eq!(facts.get("distro_like"), Some("debian"));
+        assert_eq!(facts.get("distro_version"), Some("22.04"));
+    }
+
+    #[test]
+    fn distro_family_chains_id_and_id_like() {
+        let mut facts = Facts::default();
+        facts.extend_from_os_release("ID=rocky\nID_LIKE=\"rhel centos fedora\"\n");
+        assert_eq!(
+            facts.distro_family().collect::<Vec<_>>(),
+            vec!["rocky", "rhel", "centos", "fedora"]
+        );
+    }
+
+    #[test]
+    fn for_host_applies_inventory_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(crate::inventory::INVENTORY_FILE),
+            "[web*]\nroles = webserver, proxy\ngroups = servers\napp = nginx\n",
+        )
+        .unwrap();
+
+        let facts = Facts::for_host(dir.path().to_str().unwrap(), Some("web1")).unwrap();
+        assert_eq!(facts.get("hostname"), Some("web1"));
+        assert_eq!(
+            facts.list("roles").collect::<Vec<_>>(),
+            vec!["webserver", "proxy"]
+        );
+        assert_eq!(facts.list("groups").collect::<Vec<_>>(), vec!["servers"]);
+        assert_eq!(facts.vars().get("app").unwrap(), "nginx");
+    }
+
+    #[test]
+    fn simulate_overrides_facts_and_derives_package_manager() {
+        let dir = tempfile::tempdir().unwrap();
+        let overrides = vec![
+            ("hostname".to_string(), "web1".to_string()),
+            ("os".to_string(), "linux".to_string()),
+            ("distro".to_string(), "debian".to_string()),
+            ("user".to_string(), "pets-test-nobody".to_string()),
+        ];
+        let facts = Facts::simulate(dir.path().to_str().unwrap(), &overrides).unwrap();
+        assert_eq!(facts.get("hostname"), Some("web1"));
+        assert_eq!(facts.get("distro"), Some("debian"));
+        assert_eq!(facts.get("package_manager"), Some("apt"));
+        assert_eq!(facts.get("distro_like"), None, "local distribution leaked");
+        let local = Facts::gather();
+        for key in ["arch", "kernel", "cpu_count"] {
+            assert_eq!(facts.get(key), local.get(key), "local fact '{key}' missing");
+        }
+        assert_eq!(facts.env("HOME"), local.env("HOME"));
+        assert_eq!(facts.vars().get("HOME").unwrap(), "/home/pets-test-nobody");
+        assert_eq!(facts.vars().get("USER").unwrap(), "pets-test-nobody");
+    }
+
+    #[test]
+    fn simulate_without_overrides_describes_this_machine() {
+        let dir = tempfile::tempdir().unwrap();
+        let facts = Facts::simulate(dir.path().to_str().unwrap(), &[]).unwrap();
+        let local = Facts::gather();
+        for key in ["hostname", "os", "distro", "arch", "user"] {
+            assert_eq!(facts.get(key), local.get(key), "fact '{key}' differs");
+        }
+        assert_eq!(facts.get("package_manager"), None);
+    }
+
+    #[test]
+    fn simulate_overrides_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let overrides = vec![
+            ("env.PETS_TEST_DEPLOY".to_string(), "prod".to_string()),
+            ("env.HOME".to_string(), "/srv".to_string()),
+        ];
+        let facts = Facts::simulate(dir.path().to_str().unwrap(), &overrides).unwrap();
+        assert_eq!(facts.env("PETS_TEST_DEPLOY"), Some("prod"));
+        assert_eq!(facts.env("HOME"), Some("/srv"));
+        assert_eq!(facts.get("env.HOME"), None);
+    }
+
+    #[test]
+    fn parse_fact_output_key_value_lines() {
+        let pairs = parse_fact_output("gpu_vendor=nvidia\n# comment\n\nrack = b2\n").unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("gpu_vendor".to_string(), "nvidia".to_string()),
+                ("rack".to_string(), "b2".to_string())
+            ]
+        );
+        assert!(parse_fact_output("not a fact").is_err());
+    }
+
+    #[test]
+    fn parse_fact_output_flattens_json() {
+        let pairs =
+            parse_fact_output(r#"{"gpu": {"vendor": "nvidia", "count": 2}, "tags": ["a", "b"]}"#)
+                .unwrap();
+        assert!(pairs.contains(&("gpu.vendor".to_string(), "nvidia".to_string())));
+        assert!(pairs.contains(&("gpu.count".to_string(), "2".to_string())));
+        assert!(pairs.contains(&("tags".to_string(), "a b".to_string())));
+        assert!(parse_fact_output("{not json").is_err());
+    }
+
+    #[test]
+    fn for_host_merges_fact_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let facts_dir = dir.path().join(FACTS_DIR);
+        fs::create_dir(&facts_dir).unwrap();
+
+        let script = facts_dir.join("10-gpu");
+        fs::write(&script, "#!/bin/sh\necho gpu_vendor=nvidia\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let failing = facts_dir.join("20-broken");
+        fs::write(&failing, "#!/bin/sh\nexit 1\n").unwrap();
+        fs::set_permissions(&failing, fs::Permissions::from_mode(0o755)).unwrap();
+
+        fs::write(facts_dir.join("README"), "not executable=ignored\n").unwrap();
+
+        let facts = Facts::for_host(dir.path().to_str().unwrap(), None).unwrap();
+        assert_eq!(facts.get("gpu_vendor"), Some("nvidia"));
+        assert_eq!(facts.get("not executable"), None);
+    }
+
+    #[test]
+    fn fact_scripts_run_on_first_use_and_cannot_shadow_builtins() {
+        let dir = tempfile::tempdir().unwrap();
+        let facts_dir = dir.path().join(FACTS_DIR);
+        fs::create_dir(&facts_dir).unwrap();
+        let marker = dir.path().join("ran");
+        let script = facts_dir.join("10-shadow");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\ntouch {}\necho hostname=evil\necho rack=b2\n",
+                marker.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let facts = Facts::for_host(dir.path().to_str().unwrap(), None).unwrap();
+        assert_ne!(facts.get("hostname"), Some("evil"));
+        assert_eq!(facts.get("package_manager"), None);
+        assert!(
+            !marker.exists(),
+            "fact scripts ran before any fact needed them"
+        );
+
+        assert_eq!(facts.for_user("root").get("rack"), Some("b2"));
+        assert!(marker.exists());
+        assert_ne!(facts.get("hostname"), Some("evil"));
+        fs::remove_file(&marker).unwrap();
+        assert_eq!(facts.get("rack"), Some("b2"));
+        assert!(!marker.exists(), "fact scripts ran twice");
+
+        let facts = Facts::for_host(dir.path().to_str().unwrap(), None)
+            .unwrap()
+            .without_commands();
+        assert_eq!(facts.get("rack"), None);
+        assert_eq!(facts.get("virtualisation"), None);
+        assert!(facts.is_unknown("rack") && facts.is_unknown("virtualisation"));
+        assert!(!facts.is_unknown("hostname"));
+        assert_eq!(facts.iter().find(|(key, _)| *key == "rack"), None);
+        assert!(!marker.exists(), "fact scripts ran without commands");
+    }
+
+    #[test]
+    fn gather_includes_hardware_facts() {
+        let facts = Facts::gather();
+        assert!(facts.get("cpu_count").is_some());
+        assert!(facts.get("virtualisation").is_some());
+    }
+}
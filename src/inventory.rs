@@ -0,0 +1,209 @@
+// The inventory maps hostnames (or globs) to roles, groups and per-host
+// variables. It lives in the configuration directory and looks like:
+//
+// [web*]
+// roles = webserver, proxy
+// groups = servers
+// app_port = 8080
+//
+// Every section matching a host is merged in file order: roles and groups
+// accumulate, later variables override earlier ones.
+
+use crate::pet_files::compile_pattern;
+use regex::Regex;
+use std::{collections::BTreeMap, fs, io, path::Path};
+use thiserror::Error;
+
+pub const INVENTORY_FILE: &str = "inventory";
+
+#[derive(Debug, Error)]
+pub enum InventoryError {
+    #[error("Error reading inventory: {0}")]
+    FileError(#[from] io::Error),
+    #[error("invalid inventory line {0}: '{1}'")]
+    InvalidLine(usize, String),
+    #[error("invalid host pattern '{0}' in inventory")]
+    InvalidPattern(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostEntry {
+    pub roles: Vec<String>,
+    pub groups: Vec<String>,
+    pub vars: BTreeMap<String, String>,
+}
+
+impl HostEntry {
+    fn merge(&mut self, other: &HostEntry) {
+        for role in &other.roles {
+            if !self.roles.contains(role) {
+                self.roles.push(role.clone());
+            }
+        }
+        for group in &other.groups {
+            if !self.groups.contains(group) {
+                self.groups.push(group.clone());
+            }
+        }
+        self.vars
+            .extend(other.vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+}
+
+#[derive(Debug)]
+struct Section {
+    matcher: Regex,
+    entry: HostEntry,
+}
+
+#[derive(Debug, Default)]
+pub struct Inventory {
+    sections: Vec<Section>,
+}
+
+impl Inventory {
+    /// loads the inventory from `conf_dir`. A missing inventory is empty.
+    pub fn load<P: AsRef<Path>>(conf_dir: P) -> Result<Self, InventoryError> {
+        let path = conf_dir.as_ref().join(INVENTORY_FILE);
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                log::debug!("using inventory '{}'", path.display());
+                Self::parse(&content)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self, InventoryError> {
+        let mut sections: Vec<Section> = Vec::new();
+
+        for (index, raw) in content.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let invalid = || InventoryError::InvalidLine(index + 1, raw.to_string());
+
+            if let Some(pattern) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let pattern = pattern.trim();
+                let matcher = compile_pattern(pattern)
+                    .map_err(|_| InventoryError::InvalidPattern(pattern.to_string()))?;
+                sections.push(Section {
+                    matcher,
+                    entry: HostEntry::default(),
+                });
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            let entry = &mut sections.last_mut().ok_or_else(invalid)?.entry;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "roles" => entry.roles.extend(split_list(value)),
+                "groups" => entry.groups.extend(split_list(value)),
+                "" => return Err(invalid()),
+                _ => {
+                    entry.vars.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+
+        Ok(Self { sections })
+    }
+
+    /// returns the merged entry of every section matching `hostname`.
+    pub fn host(&self, hostname: &str) -> HostEntry {
+        let mut entry = HostEntry::default();
+        for section in &self.sections {
+            if section.matcher.is_match(hostname) {
+                entry.merge(&section.entry);
+            }
+        }
+        entry
+    }
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const SAMPLE: &str = "
+# all web servers
+[web*]
+roles = webserver, proxy
+groups = servers
+app_port = 8080
+
+[web1]
+roles = webserver, primary
+app_port = 9090
+
+[laptop-?]
+groups = laptops
+";
+
+    #[test]
+    fn host_merges_matching_sections_in_order() {
+        let inventory = Inventory::parse(SAMPLE).unwrap();
+        let entry = inventory.host("web1");
+        assert_eq!(entry.roles, vec!["webserver", "proxy", "primary"]);
+        assert_eq!(entry.groups, vec!["servers"]);
+        assert_eq!(entry.vars.get("app_port").unwrap(), "9090");
+    }
+
+    #[test]
+    fn host_without_match_is_empty() {
+        let inventory = Inventory::parse(SAMPLE).unwrap();
+        assert_eq!(inventory.host("db1"), HostEntry::default());
+        assert_eq!(inventory.host("laptop-1").groups, vec!["laptops"]);
+    }
+
+    #[test]
+    fn parse_rejects_key_outside_section() {
+        assert!(matches!(
+            Inventory::parse("roles = web\n"),
+            Err(InventoryError::InvalidLine(1, _))
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_line_without_equals() {
+        assert!(matches!(
+            Inventory::parse("[web]\nroles web\n"),
+            Err(InventoryError::InvalidLine(2, _))
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_pattern() {
+        assert!(matches!(
+            Inventory::parse("[/web(/]\n"),
+            Err(InventoryError::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn load_missing_inventory_is_empty() {
+        let dir = tempdir().unwrap();
+        let inventory = Inventory::load(dir.path()).unwrap();
+        assert!(inventory.sections.is_empty());
+    }
+
+    #[test]
+    fn load_reads_inventory_from_conf_dir() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join(INVENTORY_FILE), SAMPLE).unwrap();
+        let inventory = Inventory::load(dir.path()).unwrap();
+        assert_eq!(inventory.host("web2").roles, vec!["webserver", "proxy"]);
+    }
+}
@@ -1,14 +1,19 @@
 #![warn(clippy::pedantic)]
 
+use actions::{BackupStore, backup};
 use clap::{CommandFactory, Parser, Subcommand};
 use clap_complete::{Shell, generate};
-use std::{env, io, process::ExitCode};
+use std::{env, io, path::PathBuf, process::ExitCode, time::Duration};
 
 mod actions;
 mod commands;
+mod facts;
+mod inventory;
 mod lock;
 mod pet_files;
 mod planner;
+mod privilege;
+mod state;
 mod summary;
 
 #[derive(Parser, Debug)]
@@ -35,26 +40,186 @@ struct Args {
     #[arg(long, default_value_t = false)]
     check: bool,
 
+    /// With --check, skip package searches, 'pre' commands, fact scripts and 'cmd:' probes, only inspecting state
+    #[arg(long, default_value_t = false, requires = "check")]
+    no_exec: bool,
+
     /// Disable backup creation before overwriting files
     #[arg(long, default_value_t = false)]
     no_backup: bool,
 
+    /// Describe files larger than this many bytes instead of diffing them
+    #[arg(long, value_name = "BYTES", env = "PETS_DIFF_MAX_SIZE", default_value_t = actions::diff::DEFAULT_DIFF_LIMIT)]
+    diff_max_size: u64,
+
+    /// Keep timestamped backups in this directory instead of next to the destination
+    #[arg(long, value_name = "DIR", env = "PETS_BACKUP_DIR")]
+    backup_dir: Option<PathBuf>,
+
+    /// Number of backups to keep per destination
+    #[arg(long, value_name = "N", env = "PETS_BACKUP_KEEP", value_parser = clap::value_parser!(u16).range(1..))]
+    backup_keep: Option<u16>,
+
+    /// Remove backups older than this, e.g. 30d, 12h or 2w
+    #[arg(long, value_name = "AGE", env = "PETS_BACKUP_MAX_AGE", value_parser = backup::parse_age)]
+    backup_max_age: Option<Duration>,
+
+    /// Replace files or wrong links in the way of symlinks, backing them up first
+    #[arg(long, default_value_t = false)]
+    force_links: bool,
+
+    /// Perform changes to system-scope files as root, authenticating once up front
+    #[arg(long = "become", value_name = "METHOD", num_args = 0..=1, default_missing_value = "sudo")]
+    become_method: Option<privilege::Method>,
+
+    /// Deploy files below the home directory for each of these users instead
+    #[arg(long, value_name = "USER", value_delimiter = ',', env = "PETS_USERS")]
+    users: Vec<String>,
+
     #[command(subcommand)]
     command: Option<SubCmd>,
 }
 
 #[derive(Subcommand, Debug)]
 enum SubCmd {
-    /// Remove all .pets-backup files from destination directories
-    CleanBackups,
+    /// Copy an existing file into the configuration directory and manage it
+    Adopt {
+        /// File to adopt, e.g. /etc/nginx/nginx.conf
+        path: PathBuf,
+        /// Replace the original with a symlink to the adopted copy
+        #[arg(long, default_value_t = false)]
+        symlink: bool,
+        /// Subdirectory of the configuration directory to place the file in
+        #[arg(long, value_name = "DIR")]
+        into: Option<PathBuf>,
+        /// Comment prefix for the modeline, e.g. // for JSON read by a lenient parser
+        #[arg(long, value_name = "PREFIX")]
+        comment: Option<String>,
+    },
+    /// List, show or restore backups of destinations
+    Backups {
+        #[command(subcommand)]
+        command: BackupsCmd,
+    },
+    /// Remove backups of managed files, or only those beyond --backup-keep and --backup-max-age
+    CleanBackups {
+        /// Find every .pets-backup and .pets-tmp below these paths
+        paths: Vec<PathBuf>,
+        /// Find every .pets-backup and .pets-tmp next to all destinations and in --backup-dir
+        #[arg(long, default_value_t = false)]
+        scan: bool,
+        /// Remove every leftover older than this, e.g. 30d, not only the stray ones; implies --scan
+        #[arg(long, value_name = "AGE", value_parser = backup::parse_age)]
+        older_than: Option<Duration>,
+        /// Ask before removing each leftover; implies --scan
+        #[arg(long, default_value_t = false)]
+        interactive: bool,
+    },
+    /// Show what applying would change, exiting 1 if anything would like diff(1)
+    Diff {
+        /// Only show changes to these destinations, or below these directories
+        paths: Vec<PathBuf>,
+        /// Show a summary of changed lines per file
+        #[arg(long, default_value_t = false, conflicts_with = "side_by_side")]
+        stat: bool,
+        /// Show old and new content next to each other
+        #[arg(short = 'y', long, default_value_t = false)]
+        side_by_side: bool,
+        /// Do not pipe the output through $PAGER
+        #[arg(long, default_value_t = false)]
+        no_pager: bool,
+    },
     /// Generate shell completions
     Completions {
         /// Shell to generate completions for
         shell: Shell,
     },
+    /// Show built-in and custom facts about this machine
+    Facts,
+    /// Show which files and steps would apply, optionally simulating another machine
+    Plan {
+        /// Facts to simulate, e.g. hostname=web1,os=linux,distro=debian,user=root
+        #[arg(long = "as", value_name = "FACT=VALUE", value_delimiter = ',', value_parser = parse_fact)]
+        facts: Vec<(String, String)>,
+    },
+    /// Copy edited destinations back over their source files
+    Pull {
+        /// Destinations to pull, all modified destfiles if omitted
+        dests: Vec<PathBuf>,
+        /// Overwrite sources with uncommitted git changes
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
     /// Show managed files and their sync status
     #[command(alias = "status")]
-    List,
+    List {
+        /// Show the files a given host would receive instead
+        #[arg(long)]
+        host: Option<String>,
+        /// Only show files where something drifted
+        #[arg(long, default_value_t = false)]
+        only_drifted: bool,
+        /// Only show and check these aspects
+        #[arg(long = "aspect", value_name = "ASPECT", value_delimiter = ',', value_parser = pet_files::FileStatus::ASPECTS)]
+        aspects: Vec<String>,
+    },
+    /// Perform privileged actions for a pets run started with --become
+    #[command(hide = true)]
+    RootHelper {
+        #[arg(long)]
+        socket: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BackupsCmd {
+    /// List the backups of a destination, or of every managed destination
+    List { dest: Option<PathBuf> },
+    /// Print a backup, the newest unless --version is given
+    Show {
+        dest: PathBuf,
+        /// Backup to show, as listed by `pets backups list`
+        #[arg(long)]
+        version: Option<String>,
+    },
+    /// Put a backup back in place, backing up the current file first
+    Restore {
+        dest: PathBuf,
+        /// Backup to restore, as listed by `pets backups list`
+        #[arg(long)]
+        version: Option<String>,
+    },
+}
+
+impl Args {
+    fn validation(&self) -> planner::Validation {
+        if self.no_exec {
+            planner::Validation::InspectOnly
+        } else {
+            planner::Validation::Full
+        }
+    }
+
+    fn backup_store(&self) -> BackupStore {
+        let location = match &self.backup_dir {
+            Some(dir) => backup::Location::Central(dir.clone()),
+            None => backup::Location::Sibling,
+        };
+        BackupStore::new(
+            location,
+            self.backup_keep.map(usize::from),
+            self.backup_max_age,
+        )
+    }
+}
+
+fn parse_fact(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected FACT=VALUE, got '{value}'")),
+    }
 }
 
 fn default_conf_dir() -> String {
@@ -77,14 +242,102 @@ fn main() -> ExitCode {
     let args = Args::parse();
     setup_logging(args.debug, args.quiet);
 
+    let backup = (!args.no_backup).then(|| args.backup_store());
     match &args.command {
-        Some(SubCmd::CleanBackups) => commands::clean_backups(&args.conf_dir),
+        Some(SubCmd::Adopt {
+            path,
+            symlink,
+            into,
+            comment,
+        }) => commands::adopt(
+            &args.conf_dir,
+            path,
+            *symlink,
+            into.as_deref(),
+            comment.as_deref(),
+        ),
+        Some(SubCmd::Backups { command }) => match command {
+            BackupsCmd::List { dest } => {
+                commands::list_backups(&args.conf_dir, &args.backup_store(), dest.as_deref())
+            }
+            BackupsCmd::Show { dest, version } => {
+                commands::show_backup(&args.backup_store(), dest, version.as_deref())
+            }
+            BackupsCmd::Restore { dest, version } => commands::restore_backup(
+                &args.backup_store(),
+                dest,
+                version.as_deref(),
+                args.dry_run,
+            ),
+        },
+        Some(SubCmd::CleanBackups {
+            paths,
+            scan,
+            older_than,
+            interactive,
+        }) => {
+            if *scan || !paths.is_empty() || older_than.is_some() || *interactive {
+                commands::scan_backups(
+                    &args.conf_dir,
+                    &args.backup_store(),
+                    paths,
+                    *older_than,
+                    args.dry_run,
+                    *interactive,
+                )
+            } else {
+                commands::clean_backups(&args.conf_dir, &args.backup_store())
+            }
+        }
+        Some(SubCmd::Diff {
+            paths,
+            stat,
+            side_by_side,
+            no_pager,
+        }) => commands::diff(
+            &args.conf_dir,
+            paths,
+            commands::DiffFormat::new(*stat, *side_by_side),
+            !no_pager,
+            args.diff_max_size,
+            args.force_links,
+            &args.users,
+        ),
         Some(SubCmd::Completions { shell }) => {
             generate(*shell, &mut Args::command(), "pets", &mut io::stdout());
             ExitCode::SUCCESS
         }
-        Some(SubCmd::List) => commands::list(&args.conf_dir),
-        None if args.check => commands::check(&args.conf_dir),
-        None => commands::apply(&args.conf_dir, args.dry_run, !args.no_backup),
+        Some(SubCmd::Facts) => commands::facts(&args.conf_dir),
+        Some(SubCmd::Plan { facts }) => commands::plan(&args.conf_dir, facts),
+        Some(SubCmd::Pull { dests, force }) => {
+            commands::pull(&args.conf_dir, dests, *force, args.dry_run)
+        }
+        Some(SubCmd::List {
+            host,
+            only_drifted,
+            aspects,
+        }) => commands::list(
+            &args.conf_dir,
+            host.as_deref(),
+            &args.users,
+            *only_drifted,
+            aspects,
+        ),
+        Some(SubCmd::RootHelper { socket }) => privilege::serve(socket, backup, args.diff_max_size),
+        None if args.check => commands::check(
+            &args.conf_dir,
+            args.force_links,
+            &args.users,
+            args.validation(),
+        ),
+        None => commands::apply(
+            &args.conf_dir,
+            args.dry_run,
+            args.diff_max_size,
+            backup,
+            args.force_links,
+            args.become_method,
+            &args.users,
+        ),
     }
 }
@@ -1,21 +1,44 @@
+pub mod account;
 mod condition;
 mod destination;
 mod directory_walker;
 pub mod mode;
 mod parser;
 mod pet_file;
+mod variables;
 
-use crate::actions::package_manager;
+use crate::{actions::package_manager, facts::Facts};
+pub use condition::compile_pattern;
 use directory_walker::DirectoryWalker;
 pub use parser::ParseError;
-pub use pet_file::{PetsFile, SyncStatus};
+pub use pet_file::{AspectStatus, FileStatus, PetsFile, SyncStatus};
+pub use variables::Variables;
 
-pub fn load<P: AsRef<std::path::Path>>(directory: P) -> Result<Vec<PetsFile>, ParseError> {
+pub fn load<P: AsRef<std::path::Path>>(
+    directory: P,
+    facts: &Facts,
+) -> Result<Vec<PetsFile>, ParseError> {
+    load_for_users(directory, facts, &[])
+}
+
+/// loads the configuration like `load`, deploying files below the home
+/// directory into the home directory of each of `users`.
+pub fn load_for_users<P: AsRef<std::path::Path>>(
+    directory: P,
+    facts: &Facts,
+    users: &[String],
+) -> Result<Vec<PetsFile>, ParseError> {
     log::debug!(
         "using configuration directory '{}'",
         directory.as_ref().display()
     );
 
-    let pkg_manager = package_manager::which()?;
-    DirectoryWalker::new(directory).collect(pkg_manager)
+    let pkg_manager = match facts.get("package_manager") {
+        Some(pkg_manager) => pkg_manager.parse()?,
+        None => package_manager::which()?,
+    };
+    let vars = Variables::from(facts.vars()).with_facts(facts);
+    DirectoryWalker::new(directory)
+        .for_users(users)
+        .collect(pkg_manager, &vars)
 }
@@ -0,0 +1,223 @@
+// Users and groups declared with the `user` and `create_group` directives. pets
+// creates the missing ones before deploying any file, so ownership can refer
+// to accounts that do not exist on a fresh machine yet:
+//
+// # pets: destfile=/etc/myapp.conf, owner=myapp, group=myapp
+// # pets: user=myapp system home=/var/lib/myapp shell=/usr/sbin/nologin
+//
+// Accounts that already exist are left alone.
+
+use super::parser::ParseError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    name: String,
+    system: bool,
+    home: Option<String>,
+    shell: Option<String>,
+    groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    name: String,
+    system: bool,
+}
+
+impl User {
+    /// parses a `user` directive such as
+    /// 'myapp system home=/var/lib/myapp shell=/bin/sh groups=adm:docker'.
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError::InvalidAccount(value.to_string());
+        let mut words = value.split_whitespace();
+        let name = words.next().ok_or_else(invalid)?;
+        let mut user = Self {
+            name: name.to_string(),
+            system: false,
+            home: None,
+            shell: None,
+            groups: Vec::new(),
+        };
+        for word in words {
+            match word.split_once('=') {
+                None if word == "system" => user.system = true,
+                None if word == "regular" => user.system = false,
+                Some(("home", home)) => user.home = Some(home.to_string()),
+                Some(("shell", shell)) => user.shell = Some(shell.to_string()),
+                Some(("groups", groups)) => {
+                    user.groups = groups.split(':').map(ToString::to_string).collect();
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(user)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// returns the commands creating this user, with a primary group of the
+    /// same name unless `has_group` says it exists or is created separately.
+    pub fn create_commands(&self, alpine: bool, has_group: bool) -> Vec<Vec<String>> {
+        let mut commands = Vec::new();
+        if alpine {
+            if !has_group {
+                commands.push(Group::new(&self.name, self.system).create_command(true));
+            }
+            let mut adduser = vec!["adduser".to_string(), "-D".to_string()];
+            if self.system {
+                adduser.push("-S".to_string());
+            }
+            if let Some(home) = &self.home {
+                adduser.extend(["-h".to_string(), home.clone()]);
+            }
+            if let Some(shell) = &self.shell {
+                adduser.extend(["-s".to_string(), shell.clone()]);
+            }
+            adduser.extend(["-G".to_string(), self.name.clone(), self.name.clone()]);
+            commands.push(adduser);
+            for group in &self.groups {
+                commands.push(vec![
+                    "addgroup".to_string(),
+                    self.name.clone(),
+                    group.clone(),
+                ]);
+            }
+            return commands;
+        }
+
+        let mut useradd = vec!["useradd".to_string()];
+        if self.system {
+            useradd.push("--system".to_string());
+        }
+        if !self.system || self.home.is_some() {
+            useradd.push("--create-home".to_string());
+        }
+        if let Some(home) = &self.home {
+            useradd.extend(["--home-dir".to_string(), home.clone()]);
+        }
+        if let Some(shell) = &self.shell {
+            useradd.extend(["--shell".to_string(), shell.clone()]);
+        }
+        if !self.groups.is_empty() {
+            useradd.extend(["--groups".to_string(), self.groups.join(",")]);
+        }
+        if has_group {
+            useradd.extend(["--gid".to_string(), self.name.clone()]);
+        } else {
+            useradd.push("--user-group".to_string());
+        }
+        useradd.push(self.name.clone());
+        commands.push(useradd);
+        commands
+    }
+}
+
+impl Group {
+    fn new(name: &str, system: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            system,
+        }
+    }
+
+    /// parses a `create_group` directive such as 'shared system'.
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError::InvalidAccount(value.to_string());
+        let mut words = value.split_whitespace();
+        let name = words.next().ok_or_else(invalid)?;
+        match words.collect::<Vec<_>>()[..] {
+            [] | ["regular"] => Ok(Self::new(name, false)),
+            ["system"] => Ok(Self::new(name, true)),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn create_command(&self, alpine: bool) -> Vec<String> {
+        let (command, system) = if alpine {
+            ("addgroup", "-S")
+        } else {
+            ("groupadd", "--system")
+        };
+        let mut create = vec![command.to_string()];
+        if self.system {
+            create.push(system.to_string());
+        }
+        create.push(self.name.clone());
+        create
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_parse_reads_options() {
+        let user =
+            User::parse("myapp system home=/var/lib/myapp shell=/bin/false groups=adm:docker")
+                .unwrap();
+        assert_eq!(user.name(), "myapp");
+        assert!(user.system);
+        assert_eq!(user.home.as_deref(), Some("/var/lib/myapp"));
+        assert_eq!(user.shell.as_deref(), Some("/bin/false"));
+        assert_eq!(user.groups, ["adm", "docker"]);
+        assert!(User::parse("myapp sytem").is_err());
+    }
+
+    #[test]
+    fn group_parse_defaults_to_regular() {
+        assert_eq!(Group::parse("staff").unwrap(), Group::new("staff", false));
+        assert_eq!(
+            Group::parse("shared system").unwrap(),
+            Group::new("shared", true)
+        );
+        assert!(Group::parse("shared wheel").is_err());
+        assert!(Group::parse(" ").is_err());
+    }
+
+    #[test]
+    fn create_commands_for_useradd_and_alpine() {
+        let user = User::parse("myapp system home=/var/lib/myapp groups=adm").unwrap();
+        assert_eq!(
+            user.create_commands(false, false),
+            [[
+                "useradd",
+                "--system",
+                "--create-home",
+                "--home-dir",
+                "/var/lib/myapp",
+                "--groups",
+                "adm",
+                "--user-group",
+                "myapp"
+            ]]
+        );
+        assert_eq!(
+            user.create_commands(true, false),
+            [
+                vec!["addgroup", "-S", "myapp"],
+                vec![
+                    "adduser",
+                    "-D",
+                    "-S",
+                    "-h",
+                    "/var/lib/myapp",
+                    "-G",
+                    "myapp",
+                    "myapp"
+                ],
+                vec!["addgroup", "myapp", "adm"],
+            ]
+        );
+        assert_eq!(
+            User::parse("bob").unwrap().create_commands(false, true),
+            [["useradd", "--create-home", "--gid", "bob", "bob"]]
+        );
+    }
+}
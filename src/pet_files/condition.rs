@@ -1,44 +1,378 @@
-use super::parser::ParseError;
+use super::{parser::ParseError, variables::Variables};
+use crate::facts::Facts;
+use regex::Regex;
+use std::{
+    cmp::Ordering,
+    fmt,
+    iter::Peekable,
+    path::Path,
+    process::{Command, Stdio},
+    str::CharIndices,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Condition {
+    /// Hostname matches exactly, by glob (`web*`) or by regex (`/^web\d+$/`).
     Hostname(String),
     Os(String),
+    /// Distribution `ID` or any of its `ID_LIKE` entries from `/etc/os-release`.
+    Distro(String),
+    /// Distribution `VERSION_ID`, optionally prefixed with a comparison.
+    DistroVersion(String),
+    Arch(String),
+    /// Current user matches exactly, by glob or by regex.
+    User(String),
+    /// Environment variable is set to the given value.
+    Env(String, String),
+    /// Path exists on the filesystem.
+    Exists(String),
+    /// Probe command exits with status 0.
+    Cmd(String),
+    /// Kernel release, optionally prefixed with a comparison.
+    Kernel(String),
+    /// Host has the given role in the inventory.
+    Role(String),
+    /// Host belongs to the given group in the inventory.
+    Group(String),
+    /// Built-in or custom fact has the given value.
+    Fact(String, String),
+    /// Negates the inner expression (`!os:macos`).
+    Not(Box<Condition>),
+    /// Every expression must hold (`a & b`).
+    All(Vec<Condition>),
+    /// At least one expression must hold (`a | b`).
+    Any(Vec<Condition>),
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Hostname(v) => write!(f, "hostname:{v}"),
+            Self::Os(v) => write!(f, "os:{v}"),
+            Self::Distro(v) => write!(f, "distro:{v}"),
+            Self::DistroVersion(v) => write!(f, "distro_version:{v}"),
+            Self::Arch(v) => write!(f, "arch:{v}"),
+            Self::User(v) => write!(f, "user:{v}"),
+            Self::Env(name, v) => write!(f, "env:{name}={v}"),
+            Self::Exists(v) => write!(f, "exists:{v}"),
+            Self::Cmd(v) if v.contains(['|', '&', ')']) => {
+                let quote = if v.contains('\'') { '"' } else { '\'' };
+                write!(f, "cmd:{quote}{v}{quote}")
+            }
+            Self::Cmd(v) => write!(f, "cmd:{v}"),
+            Self::Kernel(v) => write!(f, "kernel:{v}"),
+            Self::Role(v) => write!(f, "role:{v}"),
+            Self::Group(v) => write!(f, "group:{v}"),
+            Self::Fact(name, v) => write!(f, "fact:{name}={v}"),
+            Self::Not(inner) => match inner.as_ref() {
+                Self::All(_) | Self::Any(_) => write!(f, "!({inner})"),
+                _ => write!(f, "!{inner}"),
+            },
+            Self::All(items) => write_joined(f, items, " & "),
+            Self::Any(items) => write_joined(f, items, " | "),
+        }
+    }
+}
+
+fn write_joined(f: &mut fmt::Formatter, items: &[Condition], sep: &str) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, "{sep}")?;
+        }
+        match item {
+            Condition::All(_) | Condition::Any(_) => write!(f, "({item})")?,
+            _ => write!(f, "{item}")?,
+        }
+    }
+    Ok(())
 }
 
 impl Condition {
+    /// Parses a condition expression. Terms can be combined with `|` (or),
+    /// `&` (and), `!` (not) and grouped with parentheses; `&` binds tighter
+    /// than `|`.
     pub fn parse(value: &str) -> Result<Self, ParseError> {
-        let (kind, expected) = value
-            .split_once(':')
-            .ok_or_else(|| ParseError::InvalidCondition(value.to_string()))?;
+        Self::parse_with(value, &Variables::default())
+    }
+
+    /// Parses a condition expression like `parse`, expanding `exists:` paths
+    /// against `vars`, the variables of the file the condition belongs to.
+    pub fn parse_with(value: &str, vars: &Variables) -> Result<Self, ParseError> {
+        let mut parser = ExprParser {
+            source: value,
+            chars: value.char_indices().peekable(),
+            vars,
+        };
+        let expr = parser.parse_or()?;
+        parser.skip_whitespace();
+        match parser.chars.peek() {
+            None => Ok(expr),
+            Some(_) => Err(ParseError::InvalidCondition(value.to_string())),
+        }
+    }
+
+    fn parse_term(value: &str, vars: &Variables) -> Result<Self, ParseError> {
+        let invalid = || ParseError::InvalidCondition(value.to_string());
+        let (kind, expected) = value.split_once(':').ok_or_else(invalid)?;
 
         let kind = kind.trim().to_ascii_lowercase();
         let expected = expected.trim();
         if expected.is_empty() {
-            return Err(ParseError::InvalidCondition(value.to_string()));
+            return Err(invalid());
         }
 
         match kind.as_str() {
-            "hostname" => Ok(Self::Hostname(expected.to_string())),
+            "hostname" | "user" => {
+                compile_pattern(expected).map_err(|_| invalid())?;
+                if kind == "hostname" {
+                    Ok(Self::Hostname(expected.to_string()))
+                } else {
+                    Ok(Self::User(expected.to_string()))
+                }
+            }
             "os" => {
-                let normalized = normalize_os_name(expected)
-                    .ok_or_else(|| ParseError::InvalidCondition(value.to_string()))?;
+                let normalized = normalize_os_name(expected).ok_or_else(invalid)?;
                 Ok(Self::Os(normalized.to_string()))
             }
-            _ => Err(ParseError::InvalidCondition(value.to_string())),
+            "distro" => Ok(Self::Distro(expected.to_ascii_lowercase())),
+            "distro_version" => {
+                parse_requirement(expected).ok_or_else(invalid)?;
+                Ok(Self::DistroVersion(expected.to_string()))
+            }
+            "arch" => Ok(Self::Arch(normalize_arch(expected).to_string())),
+            "env" => match expected.split_once('=') {
+                Some((name, want)) if !name.trim().is_empty() => {
+                    Ok(Self::Env(name.trim().to_string(), want.trim().to_string()))
+                }
+                _ => Err(invalid()),
+            },
+            "exists" => Ok(Self::Exists(vars.expand(expected)?)),
+            "cmd" => unquote(expected)
+                .map(|probe| Self::Cmd(probe.to_string()))
+                .ok_or_else(invalid),
+            "kernel" => {
+                parse_requirement(expected).ok_or_else(invalid)?;
+                Ok(Self::Kernel(expected.to_string()))
+            }
+            "role" => Ok(Self::Role(expected.to_string())),
+            "group" => Ok(Self::Group(expected.to_string())),
+            "fact" => match expected.split_once('=') {
+                Some((name, want)) if !name.trim().is_empty() => {
+                    Ok(Self::Fact(name.trim().to_string(), want.trim().to_string()))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+
+    /// returns true if evaluating this condition probes the local machine
+    /// rather than its facts.
+    pub fn is_live(&self) -> bool {
+        match self {
+            Self::Exists(_) | Self::Cmd(_) => true,
+            Self::Not(inner) => inner.is_live(),
+            Self::All(items) | Self::Any(items) => items.iter().any(Self::is_live),
+            _ => false,
+        }
+    }
+
+    pub fn is_met(&self, facts: &Facts) -> bool {
+        self.unmet(facts).is_none()
+    }
+
+    /// returns the sub-expression responsible for this condition not being
+    /// met, or `None` when it holds.
+    pub fn unmet(&self, facts: &Facts) -> Option<&Self> {
+        match self {
+            Self::Not(inner) => inner.unmet(facts).map_or(Some(self), |_| None),
+            Self::All(items) => items.iter().find_map(|item| item.unmet(facts)),
+            Self::Any(items) => {
+                if items.iter().any(|item| item.unmet(facts).is_none()) {
+                    None
+                } else {
+                    Some(self)
+                }
+            }
+            _ if self.term_is_met(facts) => None,
+            _ => Some(self),
+        }
+    }
+
+    /// returns the first term that cannot be evaluated without running
+    /// commands, when the outcome depends on it. Facts that do not run
+    /// commands leave `cmd:` probes and the facts gathered by commands unknown.
+    pub fn unknown(&self, facts: &Facts) -> Option<&Self> {
+        if self.evaluate(facts).is_some() {
+            return None;
+        }
+        match self {
+            Self::Not(inner) => inner.unknown(facts),
+            Self::All(items) | Self::Any(items) => {
+                items.iter().find_map(|item| item.unknown(facts))
+            }
+            _ => Some(self),
+        }
+    }
+
+    /// evaluates this condition, or returns `None` when the outcome depends
+    /// on a term that cannot be evaluated without running commands.
+    pub fn evaluate(&self, facts: &Facts) -> Option<bool> {
+        match self {
+            Self::Not(inner) => inner.evaluate(facts).map(|met| !met),
+            Self::All(items) => {
+                let results = items.iter().map(|item| item.evaluate(facts));
+                match results.collect::<Vec<_>>() {
+                    r if r.contains(&Some(false)) => Some(false),
+                    r if r.contains(&None) => None,
+                    _ => Some(true),
+                }
+            }
+            Self::Any(items) => {
+                let results = items.iter().map(|item| item.evaluate(facts));
+                match results.collect::<Vec<_>>() {
+                    r if r.contains(&Some(true)) => Some(true),
+                    r if r.contains(&None) => None,
+                    _ => Some(false),
+                }
+            }
+            Self::Cmd(_) if !facts.runs_commands() => None,
+            Self::Fact(name, _) if facts.is_unknown(name) => None,
+            _ => Some(self.term_is_met(facts)),
         }
     }
 
-    pub fn is_met(&self) -> bool {
+    fn term_is_met(&self, facts: &Facts) -> bool {
         match self {
-            Self::Hostname(expected) => hostname::get()
-                .ok()
-                .and_then(|host| host.into_string().ok())
-                .is_some_and(|host| host == *expected),
-            Self::Os(expected) => {
-                normalize_os_name(std::env::consts::OS).is_some_and(|actual| actual == expected)
+            Self::Hostname(pattern) => facts
+                .get("hostname")
+                .is_some_and(|host| pattern_matches(pattern, host)),
+            Self::Os(expected) => facts
+                .get("os")
+                .and_then(normalize_os_name)
+                .is_some_and(|actual| actual == expected),
+            Self::Distro(expected) => facts.distro_family().any(|id| id == expected),
+            Self::DistroVersion(requirement) => facts
+                .get("distro_version")
+                .is_some_and(|actual| version_matches(requirement, actual)),
+            Self::Arch(expected) => facts
+                .get("arch")
+                .is_some_and(|actual| normalize_arch(actual) == expected),
+            Self::User(pattern) => facts
+                .get("user")
+                .is_some_and(|user| pattern_matches(pattern, user)),
+            Self::Env(name, expected) => facts.env(name) == Some(expected.as_str()),
+            Self::Exists(path) => Path::new(path).exists(),
+            Self::Cmd(probe) => facts.runs_commands() && run_probe(probe),
+            Self::Kernel(requirement) => facts
+                .get("kernel")
+                .is_some_and(|actual| version_matches(requirement, actual)),
+            Self::Role(role) => facts.list("roles").any(|actual| actual == role),
+            Self::Group(group) => facts.list("groups").any(|actual| actual == group),
+            Self::Fact(name, expected) => facts.get(name) == Some(expected.as_str()),
+            Self::Not(_) | Self::All(_) | Self::Any(_) => self.unmet(facts).is_none(),
+        }
+    }
+}
+
+/// Recursive descent parser for `when` expressions.
+struct ExprParser<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    vars: &'a Variables,
+}
+
+impl ExprParser<'_> {
+    fn invalid(&self) -> ParseError {
+        ParseError::InvalidCondition(self.source.to_string())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        self.chars.next_if(|&(_, c)| c == expected).is_some()
+    }
+
+    fn parse_or(&mut self) -> Result<Condition, ParseError> {
+        let mut items = vec![self.parse_and()?];
+        while self.eat('|') {
+            items.push(self.parse_and()?);
+        }
+        Ok(if items.len() == 1 {
+            items.remove(0)
+        } else {
+            Condition::Any(items)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Condition, ParseError> {
+        let mut items = vec![self.parse_unary()?];
+        while self.eat('&') {
+            items.push(self.parse_unary()?);
+        }
+        Ok(if items.len() == 1 {
+            items.remove(0)
+        } else {
+            Condition::All(items)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Condition, ParseError> {
+        if self.eat('!') {
+            return Ok(Condition::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat('(') {
+            let expr = self.parse_or()?;
+            return if self.eat(')') {
+                Ok(expr)
+            } else {
+                Err(self.invalid())
+            };
+        }
+        self.parse_term()
+    }
+
+    /// Reads a `kind:value` term up to the next top-level operator. A value
+    /// starting with `'` or `"`, or with `/` for the pattern-valued hostname
+    /// and user, is read up to the matching closing character first, so
+    /// regexes and quoted probes may contain operator characters. Paths
+    /// starting with `/` are not quoted.
+    fn parse_term(&mut self) -> Result<Condition, ParseError> {
+        self.skip_whitespace();
+        let start = self.chars.peek().map_or(self.source.len(), |&(i, _)| i);
+        let mut end = start;
+        let mut seen_colon = false;
+        let mut value_start = false;
+        let mut is_pattern = false;
+        let mut closing = None;
+
+        while let Some(&(i, c)) = self.chars.peek() {
+            if closing.is_none() && matches!(c, '|' | '&' | ')') {
+                break;
+            }
+            if closing == Some(c) {
+                closing = None;
+            } else if value_start && (matches!(c, '\'' | '"') || (c == '/' && is_pattern)) {
+                closing = Some(c);
             }
+            if c == ':' && !seen_colon {
+                let kind = self.source[start..i].trim().to_ascii_lowercase();
+                is_pattern = matches!(kind.as_str(), "hostname" | "user");
+            }
+            value_start = (c == ':' && !seen_colon) || (value_start && c.is_whitespace());
+            seen_colon |= c == ':';
+            self.chars.next();
+            end = i + c.len_utf8();
+        }
+
+        let term = self.source[start..end].trim();
+        if term.is_empty() {
+            return Err(self.invalid());
         }
+        Condition::parse_term(term, self.vars)
     }
 }
 
@@ -50,9 +384,154 @@ fn normalize_os_name(value: &str) -> Option<&'static str> {
     }
 }
 
+fn normalize_arch(value: &str) -> &str {
+    match value {
+        "amd64" | "x64" => "x86_64",
+        "arm64" => "aarch64",
+        "i386" | "i686" => "x86",
+        "armhf" | "armv7l" => "arm",
+        _ => value,
+    }
+}
+
+/// Compiles a `/regex/` or a glob (`*`, `?`, `[...]`) into an anchored regex.
+/// Patterns without glob characters match exactly.
+pub fn compile_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    if let Some(re) = pattern
+        .strip_prefix('/')
+        .and_then(|p| p.strip_suffix('/'))
+        .filter(|p| !p.is_empty())
+    {
+        return Regex::new(re);
+    }
+
+    let mut re = String::from("^");
+    let mut in_class = false;
+    for c in pattern.chars() {
+        match c {
+            '*' if !in_class => re.push_str(".*"),
+            '?' if !in_class => re.push('.'),
+            '[' if !in_class => {
+                in_class = true;
+                re.push('[');
+            }
+            '!' if in_class && re.ends_with('[') => re.push('^'),
+            ']' if in_class => {
+                in_class = false;
+                re.push(']');
+            }
+            '-' | '^' if in_class => re.push(c),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re)
+}
+
+fn pattern_matches(pattern: &str, value: &str) -> bool {
+    compile_pattern(pattern).is_ok_and(|re| re.is_match(value))
+}
+
+/// Splits a requirement like `>=6.1` into its comparison and version.
+fn parse_requirement(requirement: &str) -> Option<(&str, Vec<u64>)> {
+    let op_len = requirement
+        .find(|c: char| !matches!(c, '<' | '>' | '=' | '!'))
+        .unwrap_or(requirement.len());
+    let (op, version) = requirement.split_at(op_len);
+    if !matches!(op, "" | "=" | "==" | "!=" | "<" | "<=" | ">" | ">=") {
+        return None;
+    }
+    let version = parse_version(version.trim());
+    if version.is_empty() {
+        None
+    } else {
+        Some((op, version))
+    }
+}
+
+/// returns the leading dotted numeric components of a version string, so
+/// `6.1.0-18-amd64` becomes `[6, 1, 0]`.
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+fn version_matches(requirement: &str, actual: &str) -> bool {
+    let Some((op, wanted)) = parse_requirement(requirement) else {
+        return false;
+    };
+    let actual = parse_version(actual);
+    if actual.is_empty() {
+        return false;
+    }
+
+    // Without an explicit ordering, `6.1` matches any `6.1.x` release.
+    let ordering = if matches!(op, "" | "=" | "==" | "!=") {
+        actual
+            .iter()
+            .take(wanted.len())
+            .cmp(wanted.iter())
+            .then_with(|| {
+                if actual.len() < wanted.len() {
+                    Ordering::Less
+                } else {
+                    Ordering::Equal
+                }
+            })
+    } else {
+        let len = actual.len().max(wanted.len());
+        let pad = |v: &[u64]| (0..len).map(|i| v.get(i).copied().unwrap_or(0)).collect();
+        let (actual, wanted): (Vec<u64>, Vec<u64>) = (pad(&actual), pad(&wanted));
+        actual.cmp(&wanted)
+    };
+
+    match op {
+        "!=" => ordering != Ordering::Equal,
+        "<" => ordering == Ordering::Less,
+        "<=" => ordering != Ordering::Greater,
+        ">" => ordering == Ordering::Greater,
+        ">=" => ordering != Ordering::Less,
+        _ => ordering == Ordering::Equal,
+    }
+}
+
+/// strips the quotes around a probe given as `'...'` or `"..."`. Returns
+/// `None` if the opening quote is not closed at the very end or nothing is
+/// quoted.
+fn unquote(value: &str) -> Option<&str> {
+    let Some(quote) = value.chars().next().filter(|c| matches!(c, '\'' | '"')) else {
+        return Some(value);
+    };
+    let inner = value[1..].strip_suffix(quote)?;
+    (!inner.is_empty() && !inner.contains(quote)).then_some(inner)
+}
+
+fn run_probe(probe: &str) -> bool {
+    let status = Command::new("sh")
+        .args(["-c", probe])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+
+    match status {
+        Ok(status) => status.success(),
+        Err(err) => {
+            log::error!("cannot run condition probe '{probe}': {err}");
+            false
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::env;
 
     #[test]
     fn parse_hostname_condition() {
@@ -89,7 +568,7 @@ mod tests {
     #[test]
     fn parse_invalid_condition_kind() {
         assert!(matches!(
-            Condition::parse("env:prod"),
+            Condition::parse("colour:blue"),
             Err(ParseError::InvalidCondition(_))
         ));
     }
@@ -117,7 +596,7 @@ mod tests {
             "macos" => Condition::Os("macos".to_string()),
             _ => return,
         };
-        assert!(cond.is_met());
+        assert!(cond.is_met(&Facts::gather()));
     }
 
     #[test]
@@ -127,7 +606,7 @@ mod tests {
             "macos" => Condition::Os("linux".to_string()),
             _ => return,
         };
-        assert!(!cond.is_met());
+        assert!(!cond.is_met(&Facts::gather()));
     }
 
     #[test]
@@ -140,12 +619,360 @@ mod tests {
         };
 
         let cond = Condition::Hostname(host);
-        assert!(cond.is_met());
+        assert!(cond.is_met(&Facts::gather()));
     }
 
     #[test]
     fn hostname_condition_is_not_met_for_other_host() {
         let cond = Condition::Hostname("definitely-not-current-hostname".to_string());
-        assert!(!cond.is_met());
+        assert!(!cond.is_met(&Facts::gather()));
+    }
+
+    fn facts(pairs: &[(&str, &str)]) -> Facts {
+        let mut facts = Facts::default();
+        for (key, value) in pairs {
+            facts.insert(key, (*value).to_string());
+        }
+        facts
+    }
+
+    #[test]
+    fn parse_env_condition_requires_value() {
+        assert_eq!(
+            Condition::parse("env:DEPLOY=prod").unwrap(),
+            Condition::Env("DEPLOY".to_string(), "prod".to_string())
+        );
+        assert!(Condition::parse("env:DEPLOY").is_err());
+    }
+
+    #[test]
+    fn parse_invalid_version_requirement() {
+        assert!(Condition::parse("kernel:>=abc").is_err());
+        assert!(Condition::parse("distro_version:=>12").is_err());
+    }
+
+    #[test]
+    fn parse_invalid_hostname_regex() {
+        assert!(Condition::parse("hostname:/web(/").is_err());
+    }
+
+    #[test]
+    fn hostname_glob_and_regex_match() {
+        let facts = facts(&[("hostname", "web12")]);
+        assert!(Condition::parse("hostname:web*").unwrap().is_met(&facts));
+        assert!(Condition::parse("hostname:web?2").unwrap().is_met(&facts));
+        assert!(
+            Condition::parse("hostname:web[0-9]*")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(
+            Condition::parse(r"hostname:/^web\d+$/")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(!Condition::parse("hostname:db*").unwrap().is_met(&facts));
+        assert!(!Condition::parse("hostname:web").unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn distro_matches_id_and_id_like() {
+        let facts = facts(&[("distro", "ubuntu"), ("distro_like", "debian")]);
+        assert!(Condition::parse("distro:ubuntu").unwrap().is_met(&facts));
+        assert!(Condition::parse("distro:Debian").unwrap().is_met(&facts));
+        assert!(!Condition::parse("distro:fedora").unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn distro_version_comparisons() {
+        let facts = facts(&[("distro_version", "22.04")]);
+        assert!(
+            Condition::parse("distro_version:22.04")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(
+            Condition::parse("distro_version:22")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(
+            Condition::parse("distro_version:>=20.04")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(
+            !Condition::parse("distro_version:<22")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(
+            Condition::parse("distro_version:!=24.04")
+                .unwrap()
+                .is_met(&facts)
+        );
+    }
+
+    #[test]
+    fn kernel_comparisons_ignore_release_suffix() {
+        let facts = facts(&[("kernel", "6.1.0-18-amd64")]);
+        assert!(Condition::parse("kernel:>=6.1").unwrap().is_met(&facts));
+        assert!(Condition::parse("kernel:6.1").unwrap().is_met(&facts));
+        assert!(!Condition::parse("kernel:>6.1.0").unwrap().is_met(&facts));
+        assert!(!Condition::parse("kernel:>=6.2").unwrap().is_met(&facts));
+        assert!(!Condition::parse("kernel:6.10").unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn arch_aliases_are_normalized() {
+        let facts = facts(&[("arch", "x86_64")]);
+        assert!(Condition::parse("arch:amd64").unwrap().is_met(&facts));
+        assert!(Condition::parse("arch:x86_64").unwrap().is_met(&facts));
+        assert!(!Condition::parse("arch:arm64").unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn user_condition_matches_pattern() {
+        let facts = facts(&[("user", "deploy")]);
+        assert!(Condition::parse("user:deploy").unwrap().is_met(&facts));
+        assert!(Condition::parse("user:dep*").unwrap().is_met(&facts));
+        assert!(!Condition::parse("user:root").unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn env_condition_reads_environment_from_facts() {
+        let home = env::var("HOME").unwrap();
+        let facts = Facts::gather();
+        assert!(Condition::Env("HOME".to_string(), home.clone()).is_met(&facts));
+        assert!(!Condition::Env("HOME".to_string(), "/nowhere".to_string()).is_met(&facts));
+        assert!(!Condition::Env("HOME".to_string(), home).is_met(&Facts::default()));
+    }
+
+    #[test]
+    fn exists_condition_checks_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let facts = Facts::default();
+        let present = format!("exists:{}", dir.path().display());
+        let missing = format!("exists:{}/missing", dir.path().display());
+        assert!(Condition::parse(&present).unwrap().is_met(&facts));
+        assert!(!Condition::parse(&missing).unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn cmd_condition_uses_exit_status() {
+        let facts = Facts::default();
+        assert!(Condition::parse("cmd:true").unwrap().is_met(&facts));
+        assert!(Condition::parse("cmd:test 1 -eq 1").unwrap().is_met(&facts));
+        assert!(!Condition::parse("cmd:false").unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn parse_quoted_cmd_may_contain_operators() {
+        let facts = Facts::default();
+        let cond =
+            Condition::parse("cmd:'echo kvm | grep -q kvm' & !cmd:\"true && false\"").unwrap();
+        assert_eq!(
+            cond,
+            Condition::All(vec![
+                Condition::Cmd("echo kvm | grep -q kvm".to_string()),
+                Condition::Not(Box::new(Condition::Cmd("true && false".to_string()))),
+            ])
+        );
+        assert!(cond.is_met(&facts));
+        assert!(
+            Condition::parse("cmd:\"(exit 1) || grep -q 'a|b' /dev/null\"")
+                .unwrap()
+                .unmet(&facts)
+                .is_some()
+        );
+        assert_eq!(
+            Condition::parse(&cond.to_string()).unwrap(),
+            cond,
+            "quoted probes survive display"
+        );
+        for value in ["cmd:'true | true", "cmd:'a' | 'b'", "cmd:''", "cmd:'"] {
+            assert!(
+                Condition::parse(value).is_err(),
+                "expected '{value}' to be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_single_term_is_not_wrapped() {
+        assert_eq!(
+            Condition::parse("  os:linux ").unwrap(),
+            Condition::Os("linux".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_or_and_not_with_precedence() {
+        let cond = Condition::parse("hostname:laptop | hostname:desktop & !os:macos").unwrap();
+        assert_eq!(
+            cond,
+            Condition::Any(vec![
+                Condition::Hostname("laptop".to_string()),
+                Condition::All(vec![
+                    Condition::Hostname("desktop".to_string()),
+                    Condition::Not(Box::new(Condition::Os("macos".to_string()))),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_parentheses_group_expressions() {
+        let cond = Condition::parse("(hostname:laptop | hostname:desktop) & !os:macos").unwrap();
+        assert_eq!(
+            cond.to_string(),
+            "(hostname:laptop | hostname:desktop) & !os:macos"
+        );
+    }
+
+    #[test]
+    fn parse_exists_path_combines_with_operators() {
+        assert_eq!(
+            Condition::parse("exists:/opt & os:macos").unwrap(),
+            Condition::All(vec![
+                Condition::Exists("/opt".to_string()),
+                Condition::Os("macos".to_string()),
+            ])
+        );
+        assert_eq!(
+            Condition::parse("exists:/nonexistent/pets | exists:/").unwrap(),
+            Condition::Any(vec![
+                Condition::Exists("/nonexistent/pets".to_string()),
+                Condition::Exists("/".to_string()),
+            ])
+        );
+        assert!(
+            !Condition::parse("exists:/ & os:macos & os:linux")
+                .unwrap()
+                .is_met(&Facts::default())
+        );
+    }
+
+    #[test]
+    fn parse_regex_may_contain_operators() {
+        let cond = Condition::parse("hostname:/^(web|db)[0-9]+$/ | os:macos").unwrap();
+        let facts = facts(&[("hostname", "db3"), ("os", "linux")]);
+        assert!(matches!(cond, Condition::Any(ref items) if items.len() == 2));
+        assert!(cond.is_met(&facts));
+    }
+
+    #[test]
+    fn parse_invalid_expressions() {
+        for value in [
+            "os:linux |",
+            "(os:linux",
+            "os:linux)",
+            "!",
+            "os:linux & & os:macos",
+        ] {
+            assert!(
+                matches!(
+                    Condition::parse(value),
+                    Err(ParseError::InvalidCondition(_))
+                ),
+                "expected '{value}' to be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn expression_evaluation() {
+        let cond = Condition::parse("(hostname:laptop | hostname:desktop) & !os:macos").unwrap();
+        assert!(cond.is_met(&facts(&[("hostname", "desktop"), ("os", "linux")])));
+        assert!(!cond.is_met(&facts(&[("hostname", "laptop"), ("os", "macos")])));
+    }
+
+    #[test]
+    fn unmet_reports_excluding_sub_expression() {
+        let cond = Condition::parse("(hostname:laptop | hostname:desktop) & !os:macos").unwrap();
+        let mac = facts(&[("hostname", "laptop"), ("os", "macos")]);
+        assert_eq!(cond.unmet(&mac).unwrap().to_string(), "!os:macos");
+
+        let server = facts(&[("hostname", "server"), ("os", "linux")]);
+        assert_eq!(
+            cond.unmet(&server).unwrap().to_string(),
+            "hostname:laptop | hostname:desktop"
+        );
+    }
+
+    #[test]
+    fn role_and_group_conditions_use_inventory_facts() {
+        let facts = facts(&[("roles", "webserver proxy"), ("groups", "servers")]);
+        assert!(Condition::parse("role:webserver").unwrap().is_met(&facts));
+        assert!(Condition::parse("role:proxy").unwrap().is_met(&facts));
+        assert!(!Condition::parse("role:db").unwrap().is_met(&facts));
+        assert!(Condition::parse("group:servers").unwrap().is_met(&facts));
+        assert!(!Condition::parse("group:laptops").unwrap().is_met(&facts));
+    }
+
+    #[test]
+    fn is_live_detects_local_probes() {
+        assert!(Condition::parse("cmd:true").unwrap().is_live());
+        assert!(
+            Condition::parse("os:linux & !exists:/tmp")
+                .unwrap()
+                .is_live()
+        );
+        assert!(!Condition::parse("env:DEPLOY=prod").unwrap().is_live());
+        assert!(
+            !Condition::parse("os:linux | hostname:web*")
+                .unwrap()
+                .is_live()
+        );
+    }
+
+    #[test]
+    fn fact_condition_compares_fact_value() {
+        let facts = facts(&[("gpu_vendor", "nvidia")]);
+        assert!(
+            Condition::parse("fact:gpu_vendor=nvidia")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(
+            !Condition::parse("fact:gpu_vendor=amd")
+                .unwrap()
+                .is_met(&facts)
+        );
+        assert!(!Condition::parse("fact:missing=x").unwrap().is_met(&facts));
+        assert!(Condition::parse("fact:gpu_vendor").is_err());
+    }
+
+    #[test]
+    fn conditions_needing_commands_are_unknown_without_them() {
+        let mut facts = Facts::gather().without_commands();
+        facts.insert("hostname", "web1".to_string());
+        let unknown = |expr: &str| {
+            Condition::parse(expr)
+                .unwrap()
+                .unknown(&facts)
+                .map(ToString::to_string)
+        };
+
+        assert_eq!(unknown("cmd:true").as_deref(), Some("cmd:true"));
+        assert_eq!(
+            unknown("!fact:virtualisation=docker").as_deref(),
+            Some("fact:virtualisation=docker")
+        );
+        assert_eq!(unknown("fact:rack=b2").as_deref(), Some("fact:rack=b2"));
+        assert_eq!(
+            unknown("hostname:web* & cmd:true").as_deref(),
+            Some("cmd:true")
+        );
+        assert_eq!(unknown("hostname:db* & cmd:true"), None);
+        assert_eq!(unknown("hostname:web* | cmd:true"), None);
+        assert_eq!(unknown("fact:hostname=web1"), None);
+        assert_eq!(
+            Condition::parse("hostname:db* & cmd:true")
+                .unwrap()
+                .evaluate(&facts),
+            Some(false)
+        );
+        assert!(!Condition::parse("cmd:true").unwrap().is_met(&facts));
     }
 }
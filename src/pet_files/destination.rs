@@ -1,5 +1,8 @@
 use super::parser::ParseError;
-use crate::actions::{Action, Cause};
+use crate::{
+    actions::{Action, Cause},
+    state,
+};
 use merkle_hash::{Algorithm, MerkleTree};
 use std::{
     env, fmt, fs, io,
@@ -37,6 +40,10 @@ pub struct Destination {
     // Is this a symbolic link or an actual file to be copied?
     link: bool,
     is_dir: bool,
+    // Replace whatever is in the way of the symbolic link
+    force: bool,
+    // Copy of the content pets last deployed here, if tracked
+    deployed: Option<PathBuf>,
 }
 
 impl fmt::Display for Destination {
@@ -60,9 +67,19 @@ impl Destination {
             directory: directory.to_string_lossy().to_string(),
             link: is_symlink,
             is_dir,
+            force: false,
+            deployed: None,
         }
     }
 
+    pub fn set_deployed(&mut self, deployed: PathBuf) {
+        self.deployed = Some(deployed);
+    }
+
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
     pub fn is_symlink(&self) -> bool {
         self.link
     }
@@ -71,26 +88,71 @@ impl Destination {
         &self.directory
     }
 
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// returns the directories `needs_dir` would create, outermost first.
+    pub fn missing_dirs(&self) -> Vec<PathBuf> {
+        let mut missing = Path::new(&self.directory)
+            .ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .take_while(|dir| {
+                matches!(fs::symlink_metadata(dir), Err(err) if err.kind() == io::ErrorKind::NotFound)
+            })
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        missing.reverse();
+        missing
+    }
+
+    /// returns the path to deploy for `source`: the containing directory for
+    /// `.petsfile` directory configurations, the file itself otherwise.
+    pub fn resolve_source(&self, source: &str) -> String {
+        if self.is_dir {
+            let mut file = PathBuf::from(source);
+            file.pop();
+            file.to_string_lossy().to_string()
+        } else {
+            source.to_string()
+        }
+    }
+
     /// figures out if a symbolic link needs to be created, and returns the corresponding `Action`
     /// With `Cause::Link` and source as target and dest as link name needs to be created.
+    /// When forced, a link to the wrong target is re-pointed (`Cause::Relink`) and any
+    /// other file or directory in the way is replaced (`Cause::Replace`).
     pub fn needs_link(&self, source: &str) -> Option<Action> {
+        self.plan_link(source, self.force)
+    }
+
+    /// same as `needs_link`, with `force` overriding the configured behaviour.
+    pub fn plan_link(&self, source: &str, force: bool) -> Option<Action> {
         if !self.link || self.dest.is_empty() {
             return None;
         }
 
-        let source = if self.is_dir {
-            let mut file = PathBuf::from(source);
-            file.pop();
-            file.to_string_lossy().to_string()
-        } else {
-            source.to_string()
+        let source = self.resolve_source(source);
+        let replace = |cause| {
+            Some(Action::replace_with_symlink(
+                cause,
+                PathBuf::from(&source),
+                PathBuf::from(&self.dest),
+            ))
         };
 
         match fs::symlink_metadata(&self.dest) {
             Ok(metadata) => {
                 // Easy case first: Dest exists and it is not a symlink
                 if !metadata.file_type().is_symlink() {
-                    log::error!("{} already exists", self.dest);
+                    if force {
+                        log::debug!("{} already exists, replacing it", self.dest);
+                        return replace(Cause::Replace);
+                    }
+                    log::error!(
+                        "{} already exists (use force=true or --force-links to replace it)",
+                        self.dest
+                    );
                     return None;
                 }
 
@@ -99,6 +161,15 @@ impl Destination {
                         if source == path.to_string_lossy() {
                             // Happy path
                             log::debug!("{} is a symlink to {} already", self.dest, source);
+                            None
+                        } else if force {
+                            log::debug!(
+                                "{} is a symlink to {}, re-pointing it to {}",
+                                self.dest,
+                                path.display(),
+                                source
+                            );
+                            replace(Cause::Relink)
                         } else {
                             log::error!(
                                 "{} is a symlink to {} instead of {}",
@@ -106,8 +177,8 @@ impl Destination {
                                 path.display(),
                                 source
                             );
+                            None
                         }
-                        None
                     }
                     Err(err) => {
                         log::error!("cannot read link Dest file {}: {}", self.dest, err);
@@ -130,6 +201,31 @@ impl Destination {
         }
     }
 
+    /// returns an `Action` with `Cause::Remove` if anything exists at the
+    /// destination, without following symbolic links.
+    pub fn needs_removal(&self) -> Option<Action> {
+        match fs::symlink_metadata(&self.dest) {
+            Ok(_) => Some(Action::remove(Cause::Remove, PathBuf::from(&self.dest))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::debug!("{} is absent already", self.dest);
+                None
+            }
+            Err(err) => {
+                log::error!("cannot lstat Dest file {}: {}", self.dest, err);
+                None
+            }
+        }
+    }
+
+    /// returns an `Action` with `Cause::Cleanup` if a temporary file of an
+    /// interrupted deployment was left next to the destination.
+    pub fn needs_cleanup(&self) -> Option<Action> {
+        let tmp = PathBuf::from(format!("{}.pets-tmp", self.dest));
+        fs::symlink_metadata(&tmp).ok()?;
+        log::debug!("{} was left over", tmp.display());
+        Some(Action::remove(Cause::Cleanup, tmp))
+    }
+
     // returns Action with Cause Dir if there is no directory at Directory,
     // meaning that it has to be created.
     pub fn needs_dir(&self) -> Option<Action> {
@@ -164,31 +260,43 @@ impl Destination {
         }
     }
 
+    /// returns a copy `Action` of `source` to this destination, remembering
+    /// the deployed content when tracked.
+    fn copy(&self, cause: Cause, source: &str) -> Action {
+        let action = Action::copy_file(cause, PathBuf::from(source), PathBuf::from(&self.dest));
+        match &self.deployed {
+            Some(deployed) if !Path::new(source).is_dir() => {
+                action.record_deployed(deployed.clone())
+            }
+            _ => action,
+        }
+    }
+
+    /// returns the last deployed content of a tracked file destination.
+    fn tracked_base(&self, source: &str) -> Option<PathBuf> {
+        self.deployed
+            .clone()
+            .filter(|base| base.is_file() && !Path::new(source).is_dir())
+    }
+
     /// figures out if the given trigger represents a file that needs to
     /// be updated, and returns the corresponding `Action`.
     /// Cause Update if Source needs to be copied over Dest,
     /// Create if the Destination file does not exist yet,
     /// None otherwise.
+    /// When the last deployed content is known, local edits are told apart:
+    /// Drift if only Dest changed, which overwrites the local edit, and Merge
+    /// if both Source and Dest changed, which keeps it.
     pub fn needs_copy(&self, source: &str) -> Option<Action> {
         if self.link {
             return None;
         }
 
-        let source = if self.is_dir {
-            let mut file = PathBuf::from(source);
-            file.pop();
-            file.to_string_lossy().to_string()
-        } else {
-            source.to_string()
-        };
+        let source = self.resolve_source(source);
 
         if !Path::new(&self.dest).exists() {
             log::debug!("{} does not exist yet", self.dest);
-            return Some(Action::copy_file(
-                Cause::Create,
-                PathBuf::from(&source),
-                PathBuf::from(&self.dest),
-            ));
+            return Some(self.copy(Cause::Create, &source));
         }
 
         let sha_source = match sha256(&source) {
@@ -217,11 +325,50 @@ impl Destination {
                     self.dest,
                     sha_dest
                 );
-                Some(Action::copy_file(
-                    Cause::Update,
-                    PathBuf::from(&source),
-                    PathBuf::from(&self.dest),
-                ))
+                let Some(base) = self.tracked_base(&source) else {
+                    return Some(self.copy(Cause::Update, &source));
+                };
+                // After a merge, the deployed content differs from the source
+                // it was merged from.
+                let merged_source = state::merged_source_path(&base);
+                let base_source = if merged_source.is_file() {
+                    &merged_source
+                } else {
+                    &base
+                };
+                let (sha_base, sha_base_source) = match (
+                    sha256(&base.to_string_lossy()),
+                    sha256(&base_source.to_string_lossy()),
+                ) {
+                    (Ok(sha_base), Ok(sha_base_source)) => (sha_base, sha_base_source),
+                    (Err(err), _) | (_, Err(err)) => {
+                        log::error!(
+                            "cannot determine sha256 of deployed copy {}: {err}",
+                            base.display()
+                        );
+                        return None;
+                    }
+                };
+                match (sha_source != sha_base_source, sha_dest != sha_base) {
+                    (false, false) => {
+                        log::debug!("{} keeps local changes merged earlier", self.dest);
+                        None
+                    }
+                    (true, false) => Some(self.copy(Cause::Update, &source)),
+                    (false, true) => {
+                        log::debug!("only {} changed since it was deployed", self.dest);
+                        Some(self.copy(Cause::Drift, &source))
+                    }
+                    (true, true) => {
+                        log::debug!("both {} and {} changed since deployed", source, self.dest);
+                        Some(Action::merge(
+                            Cause::Merge,
+                            PathBuf::from(&source),
+                            PathBuf::from(&self.dest),
+                            base,
+                        ))
+                    }
+                }
             }
             Err(err) => {
                 log::error!(
@@ -347,6 +494,36 @@ mod tests {
         assert_eq!(dest.needs_link(source_path.to_str().unwrap()), None);
     }
 
+    #[test]
+    fn test_destination_forced_link_replaces_regular_file() {
+        let dir = tempdir().unwrap();
+        let dest_path = dir.path().join("dest_file");
+        let source_path = dir.path().join("source_file");
+        fs::write(&dest_path, b"already-here").unwrap();
+
+        let mut dest = Destination::new(dest_path.to_str().unwrap(), true, false);
+        dest.set_force(true);
+        assert_eq!(
+            dest.needs_link(source_path.to_str().unwrap()).unwrap(),
+            Action::replace_with_symlink(Cause::Replace, source_path, dest_path)
+        );
+    }
+
+    #[test]
+    fn test_destination_forced_link_repoints_wrong_symlink() {
+        let dir = tempdir().unwrap();
+        let dest_path = dir.path().join("link_path");
+        let source_path = dir.path().join("source_file");
+        let wrong_target = dir.path().join("wrong_target");
+        std::os::unix::fs::symlink(&wrong_target, &dest_path).unwrap();
+
+        let dest = Destination::new(dest_path.to_str().unwrap(), true, false);
+        assert_eq!(
+            dest.plan_link(source_path.to_str().unwrap(), true).unwrap(),
+            Action::replace_with_symlink(Cause::Relink, source_path, dest_path)
+        );
+    }
+
     #[test]
     fn test_destination_needs_link_none_when_not_symlink_destination() {
         let dir = tempdir().unwrap();
@@ -364,6 +541,35 @@ mod tests {
         assert_eq!(dest.needs_link("/tmp/source"), None);
     }
 
+    #[test]
+    fn test_destination_needs_removal() {
+        let dir = tempdir().unwrap();
+        let dest_path = dir.path().join("legacy.list");
+        let dest = Destination::new(dest_path.to_str().unwrap(), false, false);
+        assert_eq!(dest.needs_removal(), None);
+
+        std::os::unix::fs::symlink(dir.path().join("missing"), &dest_path).unwrap();
+        assert_eq!(
+            dest.needs_removal().unwrap(),
+            Action::remove(Cause::Remove, dest_path)
+        );
+    }
+
+    #[test]
+    fn test_destination_needs_cleanup_of_leftover_tmp() {
+        let dir = tempdir().unwrap();
+        let dest_path = dir.path().join("secret");
+        let dest = Destination::new(dest_path.to_str().unwrap(), false, false);
+        assert_eq!(dest.needs_cleanup(), None);
+
+        let tmp = dir.path().join("secret.pets-tmp");
+        std::fs::write(&tmp, "partial").unwrap();
+        assert_eq!(
+            dest.needs_cleanup().unwrap(),
+            Action::remove(Cause::Cleanup, tmp)
+        );
+    }
+
     #[test]
     fn test_destination_needs_dir_creation() {
         let dir = tempdir().unwrap();
@@ -377,6 +583,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_destination_missing_dirs_outermost_first() {
+        let dir = tempdir().unwrap();
+        let dest_path = dir.path().join("a/b/file");
+        let dest = Destination::new(dest_path.to_str().unwrap(), false, false);
+        assert_eq!(
+            dest.missing_dirs(),
+            vec![dir.path().join("a"), dir.path().join("a/b")]
+        );
+
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        assert!(dest.missing_dirs().is_empty());
+    }
+
     #[test]
     fn test_destination_needs_dir_exists() {
         let dir = tempdir().unwrap();
@@ -450,6 +670,76 @@ mod tests {
         assert_eq!(dest.needs_copy(source_file.to_str().unwrap()), None);
     }
 
+    /// writes source, dest and last deployed content into a new directory.
+    fn tracked(source: &str, dest: &str, base: &str) -> (tempfile::TempDir, Destination) {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("source"), source).unwrap();
+        fs::write(dir.path().join("dest"), dest).unwrap();
+        fs::write(dir.path().join("base"), base).unwrap();
+        let mut dest = Destination::new(dir.path().join("dest").to_str().unwrap(), false, false);
+        dest.set_deployed(dir.path().join("base"));
+        (dir, dest)
+    }
+
+    #[test]
+    fn test_destination_needs_copy_tracked_source_changed() {
+        let (dir, dest) = tracked("new\n", "old\n", "old\n");
+        let source = dir.path().join("source");
+        assert_eq!(
+            dest.needs_copy(source.to_str().unwrap()).unwrap(),
+            Action::copy_file(Cause::Update, source, dir.path().join("dest"))
+                .record_deployed(dir.path().join("base"))
+        );
+    }
+
+    #[test]
+    fn test_destination_needs_copy_tracked_destination_drifted() {
+        let (dir, dest) = tracked("old\n", "edited\n", "old\n");
+        let source = dir.path().join("source");
+        assert_eq!(
+            dest.needs_copy(source.to_str().unwrap()).unwrap(),
+            Action::copy_file(Cause::Drift, source, dir.path().join("dest"))
+                .record_deployed(dir.path().join("base"))
+        );
+    }
+
+    #[test]
+    fn test_destination_needs_copy_tracked_after_merge() {
+        // base holds the merged content, the merged source is kept next to it
+        let (dir, dest) = tracked("new\n", "new\nlocal\n", "new\nlocal\n");
+        let source = dir.path().join("source");
+        fs::write(state::merged_source_path(&dir.path().join("base")), "new\n").unwrap();
+        assert_eq!(dest.needs_copy(source.to_str().unwrap()), None);
+
+        fs::write(&source, "newer\n").unwrap();
+        assert_eq!(
+            dest.needs_copy(source.to_str().unwrap()).unwrap().cause(),
+            Cause::Update
+        );
+
+        fs::write(&source, "new\n").unwrap();
+        fs::write(dir.path().join("dest"), "new\nlocal\nmore\n").unwrap();
+        assert_eq!(
+            dest.needs_copy(source.to_str().unwrap()).unwrap().cause(),
+            Cause::Drift
+        );
+    }
+
+    #[test]
+    fn test_destination_needs_copy_tracked_both_changed() {
+        let (dir, dest) = tracked("new\n", "edited\n", "old\n");
+        let source = dir.path().join("source");
+        assert_eq!(
+            dest.needs_copy(source.to_str().unwrap()).unwrap(),
+            Action::merge(
+                Cause::Merge,
+                source,
+                dir.path().join("dest"),
+                dir.path().join("base")
+            )
+        );
+    }
+
     #[test]
     fn test_destination_needs_copy_none_when_symlink_destination() {
         let dir = tempdir().unwrap();
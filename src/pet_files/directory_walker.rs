@@ -1,4 +1,4 @@
-use super::{ParseError, PetsFile};
+use super::{ParseError, PetsFile, Variables};
 use crate::actions::package_manager::PackageManager;
 use std::{
     convert::AsRef,
@@ -9,11 +9,22 @@ use walkdir::{DirEntry, WalkDir};
 #[derive(Debug)]
 pub struct DirectoryWalker<P: AsRef<Path>> {
     directory: P,
+    // Users to deploy home directory files for instead of the invoking user
+    users: Vec<String>,
 }
 
 impl<P: AsRef<Path>> DirectoryWalker<P> {
     pub fn new(directory: P) -> Self {
-        Self { directory }
+        Self {
+            directory,
+            users: Vec::new(),
+        }
+    }
+
+    /// deploys files below the home directory once for each of `users`.
+    pub fn for_users(mut self, users: &[String]) -> Self {
+        self.users = users.to_vec();
+        self
     }
 
     fn into_iter(self) -> impl Iterator<Item = Result<PathBuf, ParseError>> {
@@ -27,16 +38,23 @@ impl<P: AsRef<Path>> DirectoryWalker<P> {
             .map(Ok)
     }
 
-    pub fn collect(self, package_manager: PackageManager) -> Result<Vec<PetsFile>, ParseError> {
+    pub fn collect(
+        self,
+        package_manager: PackageManager,
+        vars: &Variables,
+    ) -> Result<Vec<PetsFile>, ParseError> {
         log::debug!(
             "using configuration directory '{}'",
             self.directory.as_ref().display()
         );
 
-        self.into_iter()
+        let users = self.users.clone();
+        let files = self
+            .into_iter()
             .filter_map(Result::ok)
-            .filter_map(|path| process_pets_file(&path, package_manager).transpose())
-            .collect()
+            .map(|path| process_pets_file(&path, package_manager, vars, &users))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(files.into_iter().flatten().collect())
     }
 }
 
@@ -50,20 +68,60 @@ fn is_git_dir(entry: &DirEntry) -> bool {
 fn process_pets_file(
     path: &PathBuf,
     package_manager: PackageManager,
-) -> Result<Option<PetsFile>, ParseError> {
-    match PetsFile::from_path(path, package_manager) {
-        Ok(pf) => Ok(Some(pf)),
+    vars: &Variables,
+    users: &[String],
+) -> Result<Vec<PetsFile>, ParseError> {
+    match parse_for_users(path, package_manager, vars, users) {
+        Ok(files) => Ok(files),
         Err(error) => match error {
-            ParseError::NotPetsFile => Ok(None),
+            ParseError::NotPetsFile => Ok(Vec::new()),
             ParseError::MissingDestFile(_) | ParseError::UnknownDirective(_) => {
                 log::error!("{error}");
-                Ok(None)
+                Ok(Vec::new())
+            }
+            // A variable only defined where the file applies, like
+            // $HOMEBREW_PREFIX in a file for macOS, must not stop the others.
+            ParseError::UndefinedVariable(_) => {
+                log::warn!("skipping '{}': {error}", path.display());
+                Ok(Vec::new())
             }
             _ => Err(error),
         },
     }
 }
 
+/// parses the pets file at `path`. With `users`, destinations below the home
+/// directory of the invoking user are deployed below the home directory of
+/// every user in `users` instead, unless the file names its own `for_user`.
+fn parse_for_users(
+    path: &PathBuf,
+    package_manager: PackageManager,
+    vars: &Variables,
+    users: &[String],
+) -> Result<Vec<PetsFile>, ParseError> {
+    let files = PetsFile::from_path(path, package_manager, vars)?;
+    let Some(home) = vars.get("HOME").filter(|_| !users.is_empty()) else {
+        return Ok(files);
+    };
+    let (in_home, mut files): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|pf| pf.for_user().is_none() && pf.is_below(&home));
+    if in_home.is_empty() {
+        return Ok(files);
+    }
+
+    for user in users {
+        let vars = vars.for_user(user)?;
+        let home = vars.get("HOME").unwrap_or_default();
+        files.extend(
+            PetsFile::from_path(path, package_manager, &vars)?
+                .into_iter()
+                .filter(|pf| pf.is_below(&home)),
+        );
+    }
+    Ok(files)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -114,19 +172,51 @@ mod tests {
         let walker = DirectoryWalker::new(temp_dir.path());
         let pkg_manager = test_package_manager();
 
-        let result = walker.collect(pkg_manager).unwrap();
+        let result = walker.collect(pkg_manager, &Variables::default()).unwrap();
 
         // Should find exactly 2 valid .pets files
         assert_eq!(result.len(), 2);
     }
 
+    #[test]
+    fn test_directory_walker_deploys_home_files_for_users() {
+        let temp_dir = TempDir::new().unwrap();
+        for (name, content) in [
+            ("dotfile", "# pets: destfile=~/.pets-test-dotfile"),
+            ("system", "# pets: destfile=/etc/pets-test-system"),
+        ] {
+            let mut file = File::create(temp_dir.path().join(name)).unwrap();
+            writeln!(file, "{content}").unwrap();
+        }
+        let mut vars = Variables::default();
+        vars.insert("HOME", "/home/pets-test-invoker".to_string());
+
+        let mut result = DirectoryWalker::new(temp_dir.path())
+            .for_users(&["root".to_string()])
+            .collect(test_package_manager(), &vars)
+            .unwrap();
+        result.sort_by_key(PetsFile::destination);
+
+        let deployed = result
+            .iter()
+            .map(|pf| (pf.destination(), pf.for_user()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            deployed,
+            [
+                ("/etc/pets-test-system".to_string(), None),
+                ("/root/.pets-test-dotfile".to_string(), Some("root")),
+            ]
+        );
+    }
+
     #[test]
     fn test_directory_walker_handles_empty_directory() {
         let temp_dir = TempDir::new().unwrap();
         let walker = DirectoryWalker::new(temp_dir.path());
         let pkg_manager = test_package_manager();
 
-        let result = walker.collect(pkg_manager).unwrap();
+        let result = walker.collect(pkg_manager, &Variables::default()).unwrap();
         assert!(result.is_empty());
     }
 
@@ -140,11 +230,34 @@ mod tests {
 
         let walker = DirectoryWalker::new(temp_dir.path());
         let pkg_manager = test_package_manager();
-        let result = walker.collect(pkg_manager).unwrap();
+        let result = walker.collect(pkg_manager, &Variables::default()).unwrap();
 
         assert!(result.is_empty());
     }
 
+    #[test]
+    fn test_directory_walker_skips_files_with_undefined_variables() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join("brew.conf"),
+            "# pets: destfile=$PETS_TEST_UNDEFINED_PREFIX/etc/foo.conf, when=os:macos\n",
+        )
+        .unwrap();
+        fs::write(
+            temp_dir.path().join("ok.conf"),
+            "# pets: destfile=/tmp/pets-test-walker-ok\n",
+        )
+        .unwrap();
+
+        let walker = DirectoryWalker::new(temp_dir.path());
+        let result = walker
+            .collect(test_package_manager(), &Variables::default())
+            .unwrap();
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].destination(), "/tmp/pets-test-walker-ok");
+    }
+
     #[test]
     fn test_directory_walker_follows_symlinks() {
         let target_dir = TempDir::new().unwrap();
@@ -158,7 +271,7 @@ mod tests {
 
         let walker = DirectoryWalker::new(walk_dir.path());
         let pkg_manager = test_package_manager();
-        let result = walker.collect(pkg_manager).unwrap();
+        let result = walker.collect(pkg_manager, &Variables::default()).unwrap();
 
         assert_eq!(result.len(), 1);
     }
@@ -1,12 +1,45 @@
 use super::parser;
-use std::fmt;
+use std::{fmt, fs};
 
-#[derive(Debug, Default)]
-pub struct Mode(u32);
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+
+/// Permission bits whose addition deserves attention in plans and logs.
+const SENSITIVE_BITS: [(u32, &str); 4] = [
+    (0o4000, "setuid"),
+    (0o2000, "setgid"),
+    (0o1000, "sticky"),
+    (0o0002, "world-writable"),
+];
+
+/// A file mode, either octal (`0644`, `2775`) or symbolic as understood by
+/// chmod(1) (`u=rw,g=r,o=`, `+x`, `g+s`).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Mode(Spec);
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+enum Spec {
+    #[default]
+    Unset,
+    Octal(u32),
+    Symbolic(String, Vec<Clause>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Clause {
+    // Bits belonging to the classes named before the operator, 0 if none
+    // were named and the umask applies.
+    who: u32,
+    ops: Vec<(char, String)>,
+}
 
 impl fmt::Display for Mode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:o}", self.0)
+        match &self.0 {
+            Spec::Unset => Ok(()),
+            Spec::Octal(mode) => write!(f, "{mode:o}"),
+            Spec::Symbolic(spec, _) => write!(f, "{spec}"),
+        }
     }
 }
 
@@ -14,41 +47,154 @@ impl TryFrom<&String> for Mode {
     type Error = parser::ParseError;
 
     fn try_from(mode: &String) -> Result<Self, Self::Error> {
-        let perm = mode.trim_start_matches('0');
-        match u32::from_str_radix(perm, 8) {
-            // The specified 'mode' string is valid.
-            Ok(num) if num <= 0o777 => Ok(Self(num)),
-            _ => Err(Self::Error::InvalidFileMode(mode.clone())),
+        let invalid = || Self::Error::InvalidFileMode(mode.clone());
+        if mode.chars().all(|c| c.is_ascii_digit()) {
+            let perm = mode.trim_start_matches('0');
+            return match u32::from_str_radix(perm, 8) {
+                // The specified 'mode' string is valid.
+                Ok(num) if num <= 0o7777 => Ok(Self(Spec::Octal(num))),
+                _ => Err(invalid()),
+            };
         }
+
+        let clauses = mode
+            .split(',')
+            .map(|clause| parse_clause(clause).ok_or_else(invalid))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(Spec::Symbolic(mode.clone(), clauses)))
     }
 }
 
+/// parses one symbolic clause such as `u=rw`, `go-w`, `+x` or `u+r-x`.
+fn parse_clause(clause: &str) -> Option<Clause> {
+    let op_start = clause.find(['+', '-', '='])?;
+    let (who, mut rest) = clause.split_at(op_start);
+    let who = who.chars().try_fold(0, |mask, c| match c {
+        'u' => Some(mask | 0o4700),
+        'g' => Some(mask | 0o2070),
+        'o' => Some(mask | 0o1007),
+        'a' => Some(mask | 0o7777),
+        _ => None,
+    })?;
+
+    let mut ops = Vec::new();
+    while let Some(op) = rest.chars().next() {
+        let end = rest[1..]
+            .find(['+', '-', '='])
+            .map_or(rest.len(), |i| i + 1);
+        let perms = &rest[1..end];
+        if !perms.chars().all(|c| "rwxXst".contains(c)) {
+            return None;
+        }
+        ops.push((op, perms.to_string()));
+        rest = &rest[end..];
+    }
+
+    Some(Clause { who, ops })
+}
+
+/// A mode equals a raw `st_mode` if applying it would change nothing,
+/// including the setuid, setgid and sticky bits.
 impl PartialEq<u32> for Mode {
     fn eq(&self, other: &u32) -> bool {
-        self.0 == (other & 0o777)
+        self.resolve(*other) == (other & 0o7777)
     }
 }
 
 impl Mode {
     pub fn is_empty(&self) -> bool {
-        self.0 == u32::MIN
+        self.0 == Spec::Unset
+    }
+
+    /// returns the permission bits a file whose raw `st_mode` is `current`
+    /// ends up with once this mode is applied.
+    pub fn resolve(&self, current: u32) -> u32 {
+        self.resolve_with_umask(current, process_umask())
     }
 
-    pub fn as_raw(&self) -> u32 {
-        self.0
+    /// like `resolve`, masking the permissions set by clauses that name no
+    /// class, such as `+w` or `=rw`, with `umask` as chmod(1) does.
+    fn resolve_with_umask(&self, current: u32, umask: u32) -> u32 {
+        let is_dir = current & S_IFMT == S_IFDIR;
+        let mut mode = current & 0o7777;
+        match &self.0 {
+            Spec::Unset => {}
+            Spec::Octal(octal) => mode = *octal,
+            Spec::Symbolic(_, clauses) => {
+                for clause in clauses {
+                    // `=` still clears every class, only setting is masked.
+                    let (who, cleared) = match clause.who {
+                        0 => (0o7777 & !umask, 0o7777),
+                        who => (who, who),
+                    };
+                    for (op, perms) in &clause.ops {
+                        let bits = perm_bits(perms, mode, is_dir) & who;
+                        match op {
+                            '+' => mode |= bits,
+                            '-' => mode &= !bits,
+                            _ => mode = (mode & !cleared) | bits,
+                        }
+                    }
+                }
+            }
+        }
+        mode
+    }
+
+    /// names the setuid, setgid, sticky and world-writable bits this mode
+    /// adds to a file whose raw `st_mode` is `current`.
+    pub fn sensitive_changes(&self, current: u32) -> Vec<&'static str> {
+        let added = self.resolve(current) & !current;
+        SENSITIVE_BITS
+            .iter()
+            .filter(|(bit, _)| added & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
     }
 }
 
+/// returns the umask of this process, read from `/proc/self/status` as
+/// umask(2) can only be queried by changing it. Defaults to 022.
+fn process_umask() -> u32 {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Umask:"))
+                .and_then(|umask| u32::from_str_radix(umask.trim(), 8).ok())
+        })
+        .unwrap_or(0o022)
+}
+
+/// returns the bits of `perms` for all classes. `X` only counts for
+/// directories and for files someone can already execute.
+fn perm_bits(perms: &str, current: u32, is_dir: bool) -> u32 {
+    perms.chars().fold(0, |bits, c| match c {
+        'r' => bits | 0o444,
+        'w' => bits | 0o222,
+        'x' => bits | 0o111,
+        'X' if is_dir || current & 0o111 != 0 => bits | 0o111,
+        's' => bits | 0o6000,
+        't' => bits | 0o1000,
+        _ => bits,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn mode(spec: &str) -> Mode {
+        Mode::try_from(&spec.to_string()).unwrap()
+    }
+
     #[test]
     fn test_mode_try_from_valid() {
         let input = "644".to_string();
         let mode = Mode::try_from(&input);
         assert!(mode.is_ok());
-        assert_eq!(mode.unwrap().0, 0o644);
+        assert_eq!(mode.unwrap().0, Spec::Octal(0o644));
     }
 
     #[test]
@@ -75,7 +221,7 @@ mod tests {
     fn test_mode_leading_zero() {
         let mode = Mode::try_from(&"0644".to_string());
         assert!(mode.is_ok());
-        assert_eq!(mode.unwrap().0, 0o644);
+        assert_eq!(mode.unwrap().0, Spec::Octal(0o644));
     }
 
     #[test]
@@ -92,8 +238,56 @@ mod tests {
     }
 
     #[test]
-    fn test_mode_as_raw_returns_inner_value() {
+    fn test_mode_resolve_octal_ignores_current_mode() {
         let mode = Mode::try_from(&"755".to_string()).unwrap();
-        assert_eq!(mode.as_raw(), 0o755);
+        assert_eq!(mode.resolve(0o100_600), 0o755);
+    }
+
+    #[test]
+    fn test_mode_special_bits() {
+        assert_eq!(mode("2775").resolve(0o040_755), 0o2775);
+        assert_eq!(mode("1777"), 0o041_777);
+        assert_ne!(mode("0755"), 0o104_755);
+        assert_ne!(mode("4755"), 0o100_755);
+        assert!(Mode::try_from(&"17777".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_mode_symbolic() {
+        assert_eq!(mode("u=rw,g=r,o=").resolve(0o100_777), 0o640);
+        assert_eq!(mode("go-w").resolve(0o100_666), 0o644);
+        assert_eq!(mode("g+s").resolve(0o040_755), 0o2755);
+        assert_eq!(mode("u+r-x").resolve(0o100_700), 0o600);
+        assert_eq!(mode("a+X").resolve(0o100_644), 0o644);
+        assert_eq!(mode("a+X").resolve(0o040_700), 0o711);
+        assert_eq!(mode("u=rw,g=r,o=").to_string(), "u=rw,g=r,o=");
+        assert_eq!(mode("go-w"), 0o100_644);
+    }
+
+    #[test]
+    fn test_mode_symbolic_without_class_honours_umask() {
+        assert_eq!(mode("+x").resolve_with_umask(0o100_644, 0o022), 0o755);
+        assert_eq!(mode("+w").resolve_with_umask(0o100_444, 0o022), 0o644);
+        assert_eq!(mode("+w").resolve_with_umask(0o100_444, 0o000), 0o666);
+        assert_eq!(mode("=rw").resolve_with_umask(0o100_777, 0o027), 0o640);
+        assert_eq!(mode("-x").resolve_with_umask(0o100_755, 0o027), 0o645);
+        assert_eq!(mode("a+w").resolve_with_umask(0o100_444, 0o022), 0o666);
+    }
+
+    #[test]
+    fn test_mode_symbolic_invalid() {
+        for spec in ["u=rwq", "z+x", "rw", "", "u=r,"] {
+            assert!(Mode::try_from(&spec.to_string()).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn test_mode_sensitive_changes() {
+        assert_eq!(mode("4755").sensitive_changes(0o100_755), vec!["setuid"]);
+        assert_eq!(
+            mode("o+wt").sensitive_changes(0o040_755),
+            vec!["sticky", "world-writable"]
+        );
+        assert!(mode("4755").sensitive_changes(0o104_755).is_empty());
     }
 }
@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     fs::File,
     io::{self, prelude::*},
+    ops::Deref,
     path::Path,
 };
 use thiserror::Error;
@@ -13,7 +14,7 @@ pub enum ParseError {
     #[error("invalid keyword/argument: {0}")]
     InvalidKeyword(String),
     #[error(
-        "unknown directive '{0}' (known: destfile, symlink, owner, group, mode, package, pre, post, when)"
+        "unknown directive '{0}' (known: destfile, symlink, owner, group, mode, package, pre, post, when, var, force, ensure, dir_mode, dir_owner, dir_group, acl, xattr, user, create_group, scope, for_user)"
     )]
     UnknownDirective(String),
     #[error("Error opening file: {0}")]
@@ -28,8 +29,26 @@ pub enum ParseError {
     MissingDestFile(String),
     #[error("Invalid file mode: {0}")]
     InvalidFileMode(String),
+    #[error("Invalid boolean for '{0}': {1}")]
+    InvalidBoolean(String, String),
+    #[error("Invalid scope '{0}' (expected user or system)")]
+    InvalidScope(String),
+    #[error("Invalid ensure value '{0}' (expected present or absent)")]
+    InvalidEnsure(String),
+    #[error("Invalid ACL entry '{0}' (expected eg: g:adm:r-x or d:u:deploy:rwx)")]
+    InvalidAcl(String),
+    #[error("Invalid xattr '{0}' (expected eg: user.origin=pets)")]
+    InvalidXattr(String),
+    #[error("Invalid user or group declaration: {0}")]
+    InvalidAccount(String),
+    #[error("unknown user '{0}' to deploy for")]
+    UnknownUser(String),
     #[error("Invalid condition: {0}")]
     InvalidCondition(String),
+    #[error("undefined variable '{0}'")]
+    UndefinedVariable(String),
+    #[error("Invalid variable: {0}")]
+    InvalidVariable(String),
     #[error("Error hashing source file: {0}")]
     HashError(#[from] merkle_hash::error::IndexingError),
 }
@@ -40,15 +59,72 @@ pub enum ParseError {
 // # pets: destfile=/etc/ssh/sshd_config, owner=root, group=root, mode=0644
 // All modelines found are returned Key=Value pairs in a Vec.
 const KNOWN_DIRECTIVES: &[&str] = &[
-    "destfile", "symlink", "owner", "group", "mode", "package", "pre", "post", "when",
+    "destfile",
+    "symlink",
+    "owner",
+    "group",
+    "mode",
+    "package",
+    "pre",
+    "post",
+    "when",
+    "var",
+    "force",
+    "ensure",
+    "dir_mode",
+    "dir_owner",
+    "dir_group",
+    "acl",
+    "xattr",
+    "user",
+    "create_group",
+    "scope",
+    "for_user",
 ];
 
-pub fn read_modelines<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Vec<String>>, ParseError> {
+/// The directives of a pets file by name, remembering the order the
+/// `destfile` and `symlink` directives were declared in.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Modelines {
+    directives: HashMap<String, Vec<String>>,
+    destinations: Vec<String>,
+}
+
+impl Deref for Modelines {
+    type Target = HashMap<String, Vec<String>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.directives
+    }
+}
+
+impl PartialEq<HashMap<String, Vec<String>>> for Modelines {
+    fn eq(&self, other: &HashMap<String, Vec<String>>) -> bool {
+        self.directives == *other
+    }
+}
+
+impl Modelines {
+    /// returns `destfile` or `symlink` for each destination, in the order
+    /// they were declared in.
+    pub fn destination_order(&self) -> &[String] {
+        &self.destinations
+    }
+
+    fn push(&mut self, directive: String, value: String) {
+        if directive == "destfile" || directive == "symlink" {
+            self.destinations.push(directive.clone());
+        }
+        self.directives.entry(directive).or_default().push(value);
+    }
+}
+
+pub fn read_modelines<P: AsRef<Path>>(path: P) -> Result<Modelines, ParseError> {
     log::debug!("Reading modelines from file '{}'", path.as_ref().display());
     let file = File::open(path)?;
     let reader = io::BufReader::new(file);
 
-    let mut result = HashMap::new();
+    let mut result = Modelines::default();
     for line in reader.lines() {
         let line = match line {
             Ok(line) if line.contains("pets:") => line,
@@ -69,7 +145,7 @@ pub fn read_modelines<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Vec<Str
                     if !KNOWN_DIRECTIVES.contains(&k.as_str()) {
                         return Err(ParseError::UnknownDirective(k));
                     }
-                    result.entry(k).or_insert_with(Vec::new).push(v);
+                    result.push(k, v);
                 }
                 Err(e) => return Err(e),
             }
@@ -81,11 +157,33 @@ pub fn read_modelines<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Vec<Str
 fn parse_multiple_key_value(
     content: &str,
 ) -> impl Iterator<Item = Result<(String, String), ParseError>> + '_ {
-    content
+    let mut pairs: Vec<String> = Vec::new();
+    for part in content
         .split(',')
         .map(str::trim)
         .filter(|s| !s.is_empty() && *s != "\t")
-        .map(parse_key_value)
+    {
+        // Symbolic modes such as 'mode=u=rw,g=r,o=' contain commas themselves.
+        match pairs.last_mut() {
+            Some(last) if is_mode_pair(last) && is_symbolic_clause(part) => {
+                last.push(',');
+                last.push_str(part);
+            }
+            _ => pairs.push(part.to_string()),
+        }
+    }
+    pairs.into_iter().map(|pair| parse_key_value(&pair))
+}
+
+fn is_mode_pair(pair: &str) -> bool {
+    pair.split_once('=')
+        .is_some_and(|(k, _)| matches!(k.trim(), "mode" | "dir_mode"))
+}
+
+/// returns true for chmod(1) clauses like 'g=r', 'o=' or 'go-w'.
+fn is_symbolic_clause(part: &str) -> bool {
+    part.trim_start_matches(['u', 'g', 'o', 'a'])
+        .starts_with(['+', '-', '='])
 }
 
 fn extract_modeline(line: String) -> Result<String, ParseError> {
@@ -114,6 +212,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_multiple_key_value_keeps_symbolic_mode_together() {
+        let pairs = parse_multiple_key_value(" mode=u=rw,g=r,o=, owner=root, dir_mode=go-w,+X")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            pairs,
+            [
+                ("mode".to_string(), "u=rw,g=r,o=".to_string()),
+                ("owner".to_string(), "root".to_string()),
+                ("dir_mode".to_string(), "go-w,+X".to_string()),
+            ]
+        );
+    }
+
     #[test]
     fn test_extract_modeline() {
         assert_eq!(
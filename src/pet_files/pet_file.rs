@@ -1,38 +1,126 @@
-use super::{condition::Condition, destination, mode, parser};
-use crate::actions::{Action, ActionError, Cause, Package, package_manager::PackageManager};
+use super::{account, condition::Condition, destination, mode, parser, variables::Variables};
+use crate::{
+    actions::{
+        AclEntry, Action, ActionError, Cause, Change, Package, acl, package_manager::PackageManager,
+    },
+    facts::Facts,
+    state::StateDir,
+};
 use std::{
-    fs,
+    borrow::Cow,
+    collections::HashMap,
+    env, fs,
     os::unix::fs::{MetadataExt, PermissionsExt},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
+use walkdir::WalkDir;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SyncStatus {
     InSync,
     Missing,
     Modified,
     LinkMissing,
     LinkWrong,
+    // Exists although it has to be absent
+    Present,
+}
+
+/// Whether one aspect of a destination matches the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AspectStatus {
+    // Not configured for this file
+    Unmanaged,
+    InSync,
+    // Differs from the configuration, as described
+    Drifted(String),
+}
+
+impl AspectStatus {
+    pub fn is_drifted(&self) -> bool {
+        matches!(self, Self::Drifted(_))
+    }
+}
+
+/// Sync status of every aspect of a destination, as checked by the planner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStatus {
+    pub content: AspectStatus,
+    pub owner: AspectStatus,
+    pub group: AspectStatus,
+    pub mode: AspectStatus,
+    // ACL entries and extended attributes
+    pub attributes: AspectStatus,
+    pub packages: AspectStatus,
+}
+
+impl FileStatus {
+    pub const ASPECTS: [&'static str; 6] = [
+        "content",
+        "owner",
+        "group",
+        "mode",
+        "attributes",
+        "packages",
+    ];
+
+    /// returns every aspect with its name, in the order of `ASPECTS`.
+    pub fn aspects(&self) -> [(&'static str, &AspectStatus); 6] {
+        [
+            (Self::ASPECTS[0], &self.content),
+            (Self::ASPECTS[1], &self.owner),
+            (Self::ASPECTS[2], &self.group),
+            (Self::ASPECTS[3], &self.mode),
+            (Self::ASPECTS[4], &self.attributes),
+            (Self::ASPECTS[5], &self.packages),
+        ]
+    }
 }
 
+#[derive(Clone)]
 pub struct PetsFile {
     // Absolute path to the configuration file
     source: String,
     dest: destination::Destination,
     pkgs: Vec<Package>,
-    user: Option<uzers::User>,
-    group: Option<uzers::Group>,
+    user: Option<String>,
+    group: Option<String>,
+    // Whether `owner` and `group` were declared rather than defaulted to the
+    // current user
+    owner_declared: bool,
+    group_declared: bool,
     mode: mode::Mode,
+    // Ownership and mode of directories created for the destination, and
+    // of directories in copied `.petsfile` trees
+    dir_user: Option<String>,
+    dir_group: Option<String>,
+    // Accounts to create if missing
+    users: Vec<account::User>,
+    groups: Vec<account::Group>,
+    dir_mode: mode::Mode,
+    // Named ACL entries and extended attributes of the destination
+    acl: Vec<AclEntry>,
+    xattrs: Vec<(String, String)>,
     pre: Option<Vec<String>>,
     post: Option<Vec<String>>,
     conditions: Vec<Condition>,
+    // Make sure nothing exists at the destination instead
+    absent: bool,
+    // Whether changes need root, guessed from the destination if unset
+    system_scope: Option<bool>,
+    // User whose home `~` refers to, with `for_user` or `--users`
+    account: Option<String>,
 }
 
 impl PetsFile {
+    /// parses the pets file at `path`, returning one `PetsFile` for every
+    /// `destfile` and `symlink` directive in it.
     pub fn from_path(
         path: &PathBuf,
         package_manager: PackageManager,
-    ) -> Result<Self, parser::ParseError> {
+        vars: &Variables,
+    ) -> Result<Vec<Self>, parser::ParseError> {
         let modelines = parser::read_modelines(path)?;
         if modelines.is_empty() {
             return Err(parser::ParseError::NotPetsFile);
@@ -43,6 +131,28 @@ impl PetsFile {
             path.display()
         );
 
+        if let Some(names) = modelines.get("for_user") {
+            let mut files = Vec::new();
+            for name in names {
+                let vars = vars.for_user(&vars.expand(name)?)?;
+                files.extend(Self::from_modelines(
+                    path,
+                    &modelines,
+                    package_manager,
+                    &vars,
+                )?);
+            }
+            return Ok(files);
+        }
+        Self::from_modelines(path, &modelines, package_manager, vars)
+    }
+
+    fn from_modelines(
+        path: &PathBuf,
+        modelines: &parser::Modelines,
+        package_manager: PackageManager,
+        vars: &Variables,
+    ) -> Result<Vec<Self>, parser::ParseError> {
         // Get absolute path to the source.
         let abs = fs::canonicalize(path)?;
         let source = abs.to_string_lossy().into_owned();
@@ -51,12 +161,28 @@ impl PetsFile {
             _ => false,
         };
 
-        let dest = match modelines.get("destfile") {
-            Some(dest) => destination::Destination::new(&dest[0], false, is_petsfile),
-            None => match modelines.get("symlink") {
-                Some(dest) => destination::Destination::new(&dest[0], true, is_petsfile),
-                None => return Err(parser::ParseError::MissingDestFile(source)),
-            },
+        let vars = parse_variables(vars.clone(), modelines.get("var"))?;
+
+        let force = match modelines.get("force") {
+            Some(force) => parse_bool("force", &force[0])?,
+            None => false,
+        };
+        let dests = parse_destinations(modelines, &vars, force, is_petsfile)?;
+        if dests.is_empty() {
+            return Err(parser::ParseError::MissingDestFile(source));
+        }
+
+        let absent = match modelines.get("ensure").map(|ensure| ensure[0].as_str()) {
+            None | Some("present") => false,
+            Some("absent") => true,
+            Some(other) => return Err(parser::ParseError::InvalidEnsure(other.to_string())),
+        };
+
+        let system_scope = match modelines.get("scope").map(|scope| scope[0].as_str()) {
+            None => None,
+            Some("user") => Some(false),
+            Some("system") => Some(true),
+            Some(other) => return Err(parser::ParseError::InvalidScope(other.to_string())),
         };
 
         let mode = match modelines.get("mode") {
@@ -64,56 +190,73 @@ impl PetsFile {
             None => mode::Mode::default(),
         };
 
+        let dir_mode = match modelines.get("dir_mode") {
+            Some(mode) => mode::Mode::try_from(&mode[0])?,
+            None => mode::Mode::default(),
+        };
+        // Files deployed for another user belong to that user by default.
+        let account = vars.account().map(ToString::to_string);
+        let account_group = account
+            .as_deref()
+            .and_then(uzers::get_user_by_name)
+            .and_then(|user| uzers::get_group_by_gid(user.primary_group_id()))
+            .map(|group| group.name().to_string_lossy().into_owned());
+        let dir_user = first(modelines.get("dir_owner")).or_else(|| account.clone());
+        let dir_group = first(modelines.get("dir_group")).or_else(|| account_group.clone());
+        let acl = parse_acl(modelines.get("acl"))?;
+        let xattrs = parse_xattrs(modelines.get("xattr"), &vars)?;
+
         let pkgs = match modelines.get("package") {
             Some(pkgs) => pkgs
                 .iter()
-                .map(|pkg| Package::new(pkg, package_manager))
-                .collect(),
+                .map(|pkg| Ok(Package::new(&vars.expand(pkg)?, package_manager)))
+                .collect::<Result<_, parser::ParseError>>()?,
             None => Vec::new(),
         };
 
-        let user = match modelines.get("owner") {
-            Some(user) => {
-                if let Some(user) = uzers::get_user_by_name(&user[0]) {
-                    Some(user)
-                } else {
-                    // TODO: one day we may add support for creating users
-                    log::warn!("unknown 'owner' {}, skipping directive", user[0]);
-                    uzers::get_user_by_uid(uzers::get_current_uid())
-                }
-            }
-            None => uzers::get_user_by_uid(uzers::get_current_uid()),
-        };
-
-        let group = match modelines.get("group") {
-            Some(group) => {
-                if let Some(group) = uzers::get_group_by_name(&group[0]) {
-                    Some(group)
-                } else {
-                    // TODO: one day we may add support for creating groups
-                    log::warn!("unknown 'group' {}, skipping directive", group[0]);
-                    uzers::get_group_by_gid(uzers::get_current_gid())
-                }
-            }
-            None => uzers::get_group_by_gid(uzers::get_current_gid()),
-        };
-
-        let pre = parse_command_directive(modelines.get("pre"));
-        let post = parse_command_directive(modelines.get("post"));
-        let conditions = parse_conditions(modelines.get("when"))?;
+        let owner_declared = modelines.contains_key("owner") || account.is_some();
+        let group_declared = modelines.contains_key("group") || account_group.is_some();
+        let user = first(modelines.get("owner"))
+            .or_else(|| account.clone())
+            .or_else(|| {
+                uzers::get_current_username().map(|name| name.to_string_lossy().into_owned())
+            });
+        let group = first(modelines.get("group")).or(account_group).or_else(|| {
+            uzers::get_current_groupname().map(|name| name.to_string_lossy().into_owned())
+        });
+        let (users, groups) = parse_accounts(modelines)?;
+
+        let pre = parse_command_directive(modelines.get("pre"), &vars)?;
+        let post = parse_command_directive(modelines.get("post"), &vars)?;
+        let conditions = parse_conditions(modelines.get("when"), &vars)?;
 
         log::debug!("'{}' pets syntax OK", path.display());
-        Ok(Self {
-            source,
-            dest,
-            pkgs,
-            user,
-            group,
-            mode,
-            pre,
-            post,
-            conditions,
-        })
+        Ok(dests
+            .into_iter()
+            .map(|dest| Self {
+                source: source.clone(),
+                dest,
+                pkgs: pkgs.clone(),
+                user: user.clone(),
+                group: group.clone(),
+                owner_declared,
+                group_declared,
+                mode: mode.clone(),
+                dir_user: dir_user.clone(),
+                dir_group: dir_group.clone(),
+                dir_mode: dir_mode.clone(),
+                users: users.clone(),
+                groups: groups.clone(),
+                acl: acl.clone(),
+                xattrs: xattrs.clone(),
+                pre: pre.clone(),
+                post: post.clone(),
+                conditions: conditions.clone(),
+                absent,
+                system_scope,
+                account: account.clone(),
+            })
+            .collect())
     }
 
     pub fn destination(&self) -> String {
@@ -128,13 +271,100 @@ impl PetsFile {
         &self.pkgs
     }
 
+    /// returns the users this file asks to create if missing.
+    pub fn declared_users(&self) -> &[account::User] {
+        &self.users
+    }
+
+    /// returns the groups this file asks to create if missing.
+    pub fn declared_groups(&self) -> &[account::Group] {
+        &self.groups
+    }
+
+    /// returns the users and groups the destination is owned by, and those
+    /// its ACL names that did not exist when parsed.
+    pub fn owners(&self) -> (Vec<&str>, Vec<&str>) {
+        let users = [&self.user, &self.dir_user];
+        let groups = [&self.group, &self.dir_group];
+        let acl = |kind| {
+            self.acl
+                .iter()
+                .filter_map(AclEntry::unknown_account)
+                .filter(move |account| account.0 == kind)
+                .map(|account| account.1)
+        };
+        (
+            users
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .chain(acl("user"))
+                .collect(),
+            groups
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .chain(acl("group"))
+                .collect(),
+        )
+    }
+
+    /// returns the user this file is deployed for, if not the invoking one.
+    pub fn for_user(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// returns true if the destination lies below `dir`.
+    pub fn is_below(&self, dir: &str) -> bool {
+        Path::new(&self.dest.to_string()).starts_with(dir)
+    }
+
     pub fn is_symlink_config(&self) -> bool {
         self.dest.is_symlink()
     }
 
+    /// returns true for a `.petsfile` directory tree that is copied.
+    pub fn is_tree_config(&self) -> bool {
+        self.dest.is_dir() && !self.dest.is_symlink()
+    }
+
+    pub fn is_absent_config(&self) -> bool {
+        self.absent
+    }
+
+    /// returns true if deploying this file needs root: with `scope=system`,
+    /// or by default when the destination is outside the home directory.
+    pub fn is_system_scope(&self) -> bool {
+        self.system_scope.unwrap_or_else(|| match env::var("HOME") {
+            Ok(home) if !home.is_empty() => !self.is_below(&home),
+            _ => true,
+        })
+    }
+
+    /// replaces conflicting files and re-points wrong links for this
+    /// file's symlink, as if it had the `force` directive.
+    pub fn force_links(&mut self) {
+        self.dest.set_force(true);
+    }
+
+    /// remembers the content deployed by this file in `state`, so later
+    /// runs can tell local edits apart from source changes.
+    pub fn track_deployed(&mut self, state: &StateDir) {
+        let deployed = state.deployed_path(&self.dest.to_string());
+        self.dest.set_deployed(deployed);
+    }
+
+    /// returns whether the content or link target of the destination is in
+    /// sync. Ownership, mode and attributes are reported by `status`.
     pub fn sync_status(&self) -> SyncStatus {
-        if self.dest.is_symlink() {
-            match self.dest.needs_link(&self.source) {
+        if self.absent {
+            match self.dest.needs_removal() {
+                None => SyncStatus::InSync,
+                Some(_) => SyncStatus::Present,
+            }
+        } else if self.dest.is_symlink() {
+            // Plan as if forced, so anything in the way is reported as wrong.
+            match self.dest.plan_link(&self.source, true) {
                 None => SyncStatus::InSync,
                 Some(action) => match action.cause() {
                     Cause::Link => SyncStatus::LinkMissing,
@@ -152,13 +382,228 @@ impl PetsFile {
         }
     }
 
-    pub fn matches_conditions(&self) -> bool {
-        self.conditions.iter().all(Condition::is_met)
+    /// checks content, ownership, mode, ACLs, extended attributes and
+    /// packages the way the planner does, each on its own.
+    pub fn status(&self) -> FileStatus {
+        let content = match self.sync_status() {
+            SyncStatus::InSync => AspectStatus::InSync,
+            SyncStatus::Missing | SyncStatus::LinkMissing => drifted("missing"),
+            SyncStatus::Modified => drifted("modified"),
+            SyncStatus::LinkWrong => drifted("wrong target"),
+            SyncStatus::Present => drifted("present"),
+        };
+        let managed = |configured: bool| {
+            if configured && !self.absent {
+                AspectStatus::InSync
+            } else {
+                AspectStatus::Unmanaged
+            }
+        };
+        let mut status = FileStatus {
+            content,
+            owner: managed(self.owner_declared && self.user.is_some()),
+            group: managed(self.group_declared && self.group.is_some()),
+            mode: managed(!self.mode.is_empty()),
+            attributes: managed(!self.acl.is_empty() || !self.xattrs.is_empty()),
+            packages: self.package_status(),
+        };
+
+        let actions: Vec<Action> = self.into();
+        let changes = actions.iter().filter_map(|action| action.changes().ok());
+        // A tree has an entry per file, the first drift found is shown.
+        let drift = |aspect: &mut AspectStatus, description: String| {
+            if !aspect.is_drifted() {
+                *aspect = AspectStatus::Drifted(description);
+            }
+        };
+        for change in changes.flatten() {
+            match change {
+                Change::Owner { old: None, .. } => {
+                    drift(&mut status.owner, "missing".to_string());
+                    drift(&mut status.group, "missing".to_string());
+                }
+                Change::Owner {
+                    old: Some(old),
+                    new,
+                    ..
+                } => {
+                    let (old_user, old_group) = old.split_once(':').unwrap_or((&old, ""));
+                    let (user, group) = new.split_once(':').unwrap_or((&new, ""));
+                    if !user.is_empty() && user != old_user {
+                        drift(&mut status.owner, format!("{old_user}→{user}"));
+                    }
+                    if !group.is_empty() && group != old_group {
+                        drift(&mut status.group, format!("{old_group}→{group}"));
+                    }
+                }
+                Change::Mode { old, new, .. } => {
+                    let description =
+                        old.map_or("missing".to_string(), |old| format!("{old:o}→{new:o}"));
+                    drift(&mut status.mode, description);
+                }
+                Change::Attribute { description, .. } => drift(&mut status.attributes, description),
+                _ => {}
+            }
+        }
+        status
+    }
+
+    /// returns which packages are not installed, the way the planner decides
+    /// which ones to install.
+    fn package_status(&self) -> AspectStatus {
+        if self.pkgs.is_empty() {
+            return AspectStatus::Unmanaged;
+        }
+        let missing = self
+            .pkgs
+            .iter()
+            .filter(|pkg| match pkg.is_installed() {
+                Ok(installed) => !installed,
+                Err(e) => !matches!(e, ActionError::NoPackageManager),
+            })
+            .map(|pkg| pkg.name.as_str())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            AspectStatus::InSync
+        } else {
+            AspectStatus::Drifted(format!("missing {}", missing.join(",")))
+        }
+    }
+
+    pub fn matches_conditions(&self, facts: &Facts) -> bool {
+        self.unmet_condition(facts).is_none()
+    }
+
+    /// returns the first `when` sub-expression that excludes this file.
+    /// Files deployed for another user see that user in `user:` conditions.
+    pub fn unmet_condition(&self, facts: &Facts) -> Option<&Condition> {
+        let facts = self.account_facts(facts);
+        self.conditions
+            .iter()
+            .find_map(|condition| condition.unmet(&facts))
+    }
+
+    /// returns the `when` term deciding whether this file applies that
+    /// cannot be evaluated without running commands `facts` may not run.
+    pub fn unknown_condition(&self, facts: &Facts) -> Option<&Condition> {
+        let facts = self.account_facts(facts);
+        if self
+            .conditions
+            .iter()
+            .any(|condition| condition.evaluate(&facts) == Some(false))
+        {
+            return None;
+        }
+        self.conditions
+            .iter()
+            .find_map(|condition| condition.unknown(&facts))
+    }
+
+    /// returns `facts` as seen by the user this file is deployed for.
+    fn account_facts<'a>(&self, facts: &'a Facts) -> Cow<'a, Facts> {
+        match &self.account {
+            Some(account) => Cow::Owned(facts.for_user(account)),
+            None => Cow::Borrowed(facts),
+        }
+    }
+
+    /// returns true if any `when` condition probes the local machine.
+    pub fn has_live_conditions(&self) -> bool {
+        self.conditions.iter().any(Condition::is_live)
+    }
+
+    /// describes the steps needed to deploy this file without inspecting
+    /// the destination or running any command.
+    pub fn static_plan(&self) -> Vec<String> {
+        let mut steps = Vec::new();
+        if !self.pkgs.is_empty() {
+            let pkgs = self
+                .pkgs
+                .iter()
+                .map(|pkg| format!("{}:{}", pkg.package_manager, pkg.name))
+                .collect::<Vec<_>>();
+            steps.push(format!("install {}", pkgs.join(" ")));
+        }
+        for group in &self.groups {
+            steps.push(format!("create group {} if missing", group.name()));
+        }
+        for user in &self.users {
+            steps.push(format!("create user {} if missing", user.name()));
+        }
+        if let Some(pre) = &self.pre {
+            steps.push(format!("validate with {} {}", pre.join(" "), self.source));
+        }
+        if self.absent {
+            steps.push(format!("rm -rf {}", self.dest));
+            if let Some(post) = &self.post {
+                steps.push(format!("run {} if removed", post.join(" ")));
+            }
+            return steps;
+        }
+        if let Some(chown) = chown_arg(self.dir_user.as_deref(), self.dir_group.as_deref()) {
+            steps.push(format!("chown {chown} on created directories"));
+        }
+        if !self.dir_mode.is_empty() {
+            steps.push(format!(
+                "chmod {} on created directories{}",
+                self.dir_mode,
+                flag_sensitive(&self.dir_mode, 0o040_000)
+            ));
+        }
+        let source = self.dest.resolve_source(&self.source);
+        if self.dest.is_symlink() {
+            steps.push(format!("ln -s {source} {}", self.dest));
+        } else {
+            steps.push(format!("cp {source} {}", self.dest));
+        }
+        let tree = self.dest.is_dir() && !self.dest.is_symlink();
+        if let Some(chown) = chown_arg(self.user.as_deref(), self.group.as_deref()) {
+            if tree {
+                steps.push(format!("chown -R {chown}"));
+            } else {
+                steps.push(format!("chown {chown}"));
+            }
+        }
+        if !self.mode.is_empty() {
+            if tree {
+                steps.push(format!(
+                    "chmod {} on files{}",
+                    self.mode,
+                    flag_sensitive(&self.mode, 0)
+                ));
+            } else {
+                steps.push(format!(
+                    "chmod {}{}",
+                    self.mode,
+                    flag_sensitive(&self.mode, 0)
+                ));
+            }
+        }
+        if tree && !self.dir_mode.is_empty() {
+            steps.push(format!(
+                "chmod {} on directories{}",
+                self.dir_mode,
+                flag_sensitive(&self.dir_mode, 0o040_000)
+            ));
+        }
+        if !self.acl.is_empty() {
+            let entries = self.acl.iter().map(ToString::to_string).collect::<Vec<_>>();
+            steps.push(format!("set ACL {}", entries.join(",")));
+        }
+        for (name, value) in &self.xattrs {
+            steps.push(format!("setfattr -n {name} -v {value}"));
+        }
+        if let Some(post) = &self.post {
+            steps.push(format!("run {} if changed", post.join(" ")));
+        }
+        steps
     }
 
     /// validates assumptions that must hold for the individual configuration files.
-    /// Ignore `PathErrors` for now. Get a list of valid files.
-    pub fn is_valid(&self) -> bool {
+    /// Ignore `PathErrors` for now. Get a list of valid files. The outcome of
+    /// `pre` is recorded per source in `pre_results`, so it runs only once for
+    /// all destinations of a source.
+    pub fn is_valid(&self, pre_results: &mut HashMap<String, bool>) -> bool {
         log::debug!("validating {}", self.source);
         // Check if the specified package(s) exists
         for pkg in &self.pkgs {
@@ -172,7 +617,11 @@ impl PetsFile {
         }
 
         // Check pre-update validation command if the file has changed.
-        if self.dest.needs_copy(&self.source).is_some() && !self.run_pre(true) {
+        if self.pending_pre().is_some()
+            && !*pre_results
+                .entry(self.source.clone())
+                .or_insert_with(|| self.run_pre(true))
+        {
             log::error!("pre-update validation failed for {}", self.source);
             false
         } else {
@@ -181,6 +630,13 @@ impl PetsFile {
         }
     }
 
+    /// Returns the `pre` validation command if deploying this file would run
+    /// it, that is if one is configured and the destination content changes.
+    pub fn pending_pre(&self) -> Option<&[String]> {
+        let pre = self.pre.as_deref()?;
+        (!self.absent && self.dest.needs_copy(&self.source).is_some()).then_some(pre)
+    }
+
     // runPre returns true if the pre-update validation command passes, or if it
     // was not specified at all. The boolean argument pathErrorOK controls whether
     // or not we want to fail if the validation command is not around.
@@ -248,148 +704,420 @@ impl PetsFile {
     }
 
     /// returns a chown `Action` or nil if none is needed.
-    #[allow(clippy::similar_names)]
     fn chown(&self) -> Option<Action> {
-        // Build arg (eg: 'root:staff', 'root', ':staff')
-        let mut arg = String::new();
-
-        let want_user_id = match &self.user {
-            Some(user) => {
-                let user_name = match user.name().to_str() {
-                    Some(name) => name,
-                    None => &user.name().to_string_lossy(),
-                };
-                arg.push_str(user_name);
-                Some(user.uid())
-            }
-            None => None,
+        chown_action(
+            &self.dest.to_string(),
+            self.user.as_deref(),
+            self.group.as_deref(),
+        )
+    }
+
+    ///  returns a chmod `Action` or nil if none is needed. The group bits
+    /// of a file with an ACL show its mask, unless the file is `replaced`.
+    fn chmod(&self, replaced: bool) -> Option<Action> {
+        let source = self.dest.resolve_source(&self.source);
+        let dest = self.dest.to_string();
+        let acl_bits = if replaced {
+            0
+        } else {
+            acl::mask_bits(Path::new(&dest), &self.acl)
         };
+        chmod_action(&dest, &self.mode, Some(Path::new(&source)), acl_bits)
+    }
 
-        let want_group_id = match &self.group {
-            Some(group) => {
-                if !arg.is_empty() {
-                    arg.push(':');
-                }
-                let group_name = match group.name().to_str() {
-                    Some(name) => name,
-                    None => &group.name().to_string_lossy(),
+    /// returns the `SetAcl` and `SetXattr` actions `path` needs to match the
+    /// `acl` and `xattr` directives. Default ACL entries only apply to
+    /// directories. A `replaced` file is a new inode without either.
+    fn attribute_actions(&self, path: &Path, is_dir: bool, replaced: bool) -> Vec<Action> {
+        let acl = (!self.acl.is_empty())
+            .then(|| {
+                let entries = self
+                    .acl
+                    .iter()
+                    .filter(|entry| is_dir || !entry.is_default())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                acl_action(path, entries, replaced)
+            })
+            .flatten();
+        acl.into_iter()
+            .chain(xattr_actions(path, &self.xattrs, replaced))
+            .collect()
+    }
+
+    /// returns the chown and chmod `Action`s for the directories pets is
+    /// about to create for this destination.
+    fn created_dirs(&self) -> Vec<Action> {
+        self.dest
+            .missing_dirs()
+            .iter()
+            .flat_map(|dir| {
+                let dir = dir.to_string_lossy();
+                [
+                    chown_action(&dir, self.dir_user.as_deref(), self.dir_group.as_deref()),
+                    chmod_action(&dir, &self.dir_mode, None, 0),
+                ]
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// returns the chown, chmod, ACL and xattr `Action`s for every entry of a
+    /// copied `.petsfile` tree: `mode` applies to files, `dir_mode` to
+    /// directories. Files are `replaced` when the tree is copied again.
+    fn tree(&self, replaced: bool) -> Vec<Action> {
+        let source = PathBuf::from(self.dest.resolve_source(&self.source));
+        let dest = PathBuf::from(self.dest.to_string());
+        WalkDir::new(&source)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(Result::ok)
+            .flat_map(|entry| {
+                let relative = entry.path().strip_prefix(&source).unwrap_or(entry.path());
+                let path = dest.join(relative);
+                let is_dir = entry.file_type().is_dir();
+                let replaced = replaced && !is_dir;
+                let mode = if is_dir { &self.dir_mode } else { &self.mode };
+                let acl_bits = if replaced {
+                    0
+                } else {
+                    acl::mask_bits(&path, &self.acl)
                 };
-                arg.push_str(group_name);
+                let name = path.to_string_lossy();
+                [
+                    chown_action(&name, self.user.as_deref(), self.group.as_deref()),
+                    chmod_action(&name, mode, Some(entry.path()), acl_bits),
+                ]
+                .into_iter()
+                .flatten()
+                .chain(self.attribute_actions(&path, is_dir, replaced))
+                .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
 
-                // Get the requested gid as integer
-                Some(group.gid())
-            }
-            None => None,
-        };
+/// returns a note on the security-sensitive bits `mode` sets, given only the
+/// file type bits of the target since plans never look at live state.
+fn flag_sensitive(mode: &mode::Mode, file_type: u32) -> String {
+    let sensitive = mode.sensitive_changes(file_type);
+    if sensitive.is_empty() {
+        String::new()
+    } else {
+        format!(" (sets {})", sensitive.join(", "))
+    }
+}
 
-        if arg.is_empty() {
-            return None;
-        }
+fn drifted(description: &str) -> AspectStatus {
+    AspectStatus::Drifted(description.to_string())
+}
 
-        let action = Action::chown(
-            Cause::Owner,
-            PathBuf::from(self.dest.to_string()),
-            want_user_id,
-            want_group_id,
-            arg,
-        );
+fn first(values: Option<&Vec<String>>) -> Option<String> {
+    values.and_then(|values| values.first()).cloned()
+}
 
-        let destination = self.dest.to_string();
-        // stat() the destination file to see if a chown is needed
-        let stat = match fs::metadata(&destination) {
-            Ok(info) => info,
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::NotFound => {
-                    // If the destination file is not there yet, prepare a chown for later on.
-                    return Some(action);
-                }
-                std::io::ErrorKind::PermissionDenied => {
-                    log::error!("permission denied in chown(): {e}");
-                    return Some(action.use_sudo());
-                }
-                _ => {
-                    log::error!("unexpected error in chown(): {e}");
-                    return None;
+/// parses the `user` and `create_group` directives, eg: 'user=myapp system'.
+fn parse_accounts(
+    modelines: &HashMap<String, Vec<String>>,
+) -> Result<(Vec<account::User>, Vec<account::Group>), parser::ParseError> {
+    let users = modelines
+        .get("user")
+        .into_iter()
+        .flatten()
+        .map(|user| account::User::parse(user))
+        .collect::<Result<_, _>>()?;
+    let groups = modelines
+        .get("create_group")
+        .into_iter()
+        .flatten()
+        .map(|group| account::Group::parse(group))
+        .collect::<Result<_, _>>()?;
+    Ok((users, groups))
+}
+
+/// parses the `acl` directives into sorted named ACL entries. Each directive
+/// holds one setfacl(1) style entry, eg: 'acl=g:adm:r-x'.
+fn parse_acl(values: Option<&Vec<String>>) -> Result<Vec<AclEntry>, parser::ParseError> {
+    let mut entries = values
+        .into_iter()
+        .flatten()
+        .map(|entry| {
+            AclEntry::parse(entry).ok_or_else(|| parser::ParseError::InvalidAcl(entry.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    entries.dedup();
+    Ok(entries)
+}
+
+/// parses the `xattr` directives, eg: 'xattr=user.origin=pets'.
+fn parse_xattrs(
+    values: Option<&Vec<String>>,
+    vars: &Variables,
+) -> Result<Vec<(String, String)>, parser::ParseError> {
+    values
+        .into_iter()
+        .flatten()
+        .map(|xattr| {
+            let expanded = vars.expand(xattr)?;
+            match expanded.split_once('=') {
+                Some((name, value)) if name.contains('.') && !name.ends_with('.') => {
+                    Ok((name.to_string(), value.to_string()))
                 }
-            },
-        };
+                _ => Err(parser::ParseError::InvalidXattr(xattr.clone())),
+            }
+        })
+        .collect()
+}
 
-        let file_uid = stat.uid();
-        let file_gid = stat.gid();
+/// returns the `chown` argument for `user` and `group` (eg: 'root:staff',
+/// 'root', ':staff'), if any.
+fn chown_arg(user: Option<&str>, group: Option<&str>) -> Option<String> {
+    match (user, group) {
+        (Some(user), Some(group)) => Some(format!("{user}:{group}")),
+        (Some(user), None) => Some(user.to_string()),
+        (None, Some(group)) => Some(format!(":{group}")),
+        (None, None) => None,
+    }
+}
 
-        // Get the file ownership details from the metadata
-        if let Some(want_uid) = want_user_id {
-            if file_uid != want_uid {
-                log::info!("{destination} is owned by uid {file_uid} instead of {want_uid}");
+/// returns a chown `Action` for `destination` or nil if none is needed.
+#[allow(clippy::similar_names)]
+fn chown_action(destination: &str, user: Option<&str>, group: Option<&str>) -> Option<Action> {
+    let arg = chown_arg(user, group)?;
+    let want_user_id = user.map(|name| uzers::get_user_by_name(name).map(|u| u.uid()));
+    let want_group_id = group.map(|name| uzers::get_group_by_name(name).map(|g| g.gid()));
+    if want_user_id == Some(None) || want_group_id == Some(None) {
+        // The account is created earlier in this run, look it up by name then.
+        return Some(Action::chown(
+            Cause::Owner,
+            PathBuf::from(destination),
+            None,
+            None,
+            arg,
+        ));
+    }
+    let want_user_id = want_user_id.flatten();
+    let want_group_id = want_group_id.flatten();
+
+    let action = Action::chown(
+        Cause::Owner,
+        PathBuf::from(destination),
+        want_user_id,
+        want_group_id,
+        arg,
+    );
+
+    // stat() the destination file to see if a chown is needed
+    let stat = match fs::metadata(destination) {
+        Ok(info) => info,
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                // If the destination file is not there yet, prepare a chown for later on.
                 return Some(action);
             }
+            std::io::ErrorKind::PermissionDenied => {
+                log::error!("permission denied in chown(): {e}");
+                return Some(action.use_sudo());
+            }
+            _ => {
+                log::error!("unexpected error in chown(): {e}");
+                return None;
+            }
+        },
+    };
+
+    let file_uid = stat.uid();
+    let file_gid = stat.gid();
+
+    // Get the file ownership details from the metadata
+    if let Some(want_uid) = want_user_id {
+        if file_uid != want_uid {
+            log::info!("{destination} is owned by uid {file_uid} instead of {want_uid}");
+            return Some(action);
         }
+    }
 
-        if let Some(want_gid) = want_group_id {
-            if file_gid != want_gid {
-                log::info!("{destination} is owned by gid {file_gid} instead of {want_gid}");
-                return Some(action);
-            }
+    if let Some(want_gid) = want_group_id {
+        if file_gid != want_gid {
+            log::info!("{destination} is owned by gid {file_gid} instead of {want_gid}");
+            return Some(action);
         }
+    }
 
-        log::debug!("{destination} is owned by {file_uid}:{file_gid} already");
-        None
+    log::debug!("{destination} is owned by {file_uid}:{file_gid} already");
+    None
+}
+
+///  returns a chmod `Action` for `destination` or nil if none is needed.
+/// Symbolic modes are resolved against the current mode of `destination`, or
+/// against `template` when the destination does not exist yet. `acl_bits`
+/// are the group bits the ACL mask of `destination` adds to `mode`.
+fn chmod_action(
+    destination: &str,
+    mode: &mode::Mode,
+    template: Option<&Path>,
+    acl_bits: u32,
+) -> Option<Action> {
+    if mode.is_empty() {
+        return None;
     }
 
-    ///  returns a chmod `Action` or nil if none is needed.
-    fn chmod(&self) -> Option<Action> {
-        if self.mode.is_empty() {
+    // stat(2) the destination file to see if a chmod is needed
+    let (old_mode, exists) = match fs::metadata(destination) {
+        Ok(info) => (info.permissions().mode(), true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let template = template.and_then(|path| fs::metadata(path).ok());
+            // Directories pets creates itself start out as 0755.
+            (
+                template.map_or(0o040_755, |info| info.permissions().mode()),
+                false,
+            )
+        }
+        Err(e) => {
+            log::error!("unexpected error in chmod(): {e}");
             return None;
         }
-
-        let action = Action::chmod(
-            Cause::Mode,
-            PathBuf::from(self.dest.to_string()),
-            self.mode.as_raw(),
+    };
+
+    let new_mode = mode.resolve(old_mode) | acl_bits;
+    let sensitive = mode.sensitive_changes(old_mode);
+    if !sensitive.is_empty() {
+        log::warn!(
+            "{destination} will be {} ({new_mode:o})",
+            sensitive.join(", ")
         );
+    }
+    let action = Action::chmod(Cause::Mode, PathBuf::from(destination), new_mode);
 
-        // stat(2) the destination file to see if a chmod is needed
-        let file_info = match fs::metadata(self.dest.to_string()) {
-            Ok(info) => info,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                return Some(action);
-            }
-            Err(e) => {
-                log::error!("unexpected error in chmod(): {e}");
-                return None;
-            }
-        };
+    // See if the desired mode and reality differ.
+    if !exists {
+        Some(action)
+    } else if new_mode == old_mode & 0o7777 {
+        log::debug!("{destination} is {mode} already");
+        None
+    } else {
+        log::info!("{destination} is {old_mode:o} instead of {mode}");
+        Some(action)
+    }
+}
 
-        let old_mode = file_info.permissions().mode();
+/// returns the `destfile` and `symlink` destinations in the order they were
+/// declared in.
+fn parse_destinations(
+    modelines: &parser::Modelines,
+    vars: &Variables,
+    force: bool,
+    is_petsfile: bool,
+) -> Result<Vec<destination::Destination>, parser::ParseError> {
+    let mut taken = HashMap::<&str, usize>::new();
+    let mut dests = Vec::new();
+    for directive in modelines.destination_order() {
+        let index = taken.entry(directive.as_str()).or_default();
+        let Some(dest) = modelines.get(directive).and_then(|dests| dests.get(*index)) else {
+            continue;
+        };
+        *index += 1;
+        let is_symlink = directive == "symlink";
+        let mut dest = destination::Destination::new(&vars.expand(dest)?, is_symlink, is_petsfile);
+        dest.set_force(force);
+        dests.push(dest);
+    }
+    Ok(dests)
+}
 
-        // See if the desired mode and reality differ.
-        if self.mode == old_mode {
-            log::debug!("{} is {} already", self.dest, self.mode);
+/// returns a `SetAcl` action if the named ACL entries of `path` or its mask
+/// differ from `entries`, or nil if none is needed.
+fn acl_action(path: &Path, entries: Vec<AclEntry>, replaced: bool) -> Option<Action> {
+    let action = |entries| Some(Action::set_acl(Cause::Acl, path.to_path_buf(), entries));
+    if replaced {
+        return action(entries);
+    }
+    match acl::is_applied(path, &entries) {
+        Ok(true) => {
+            log::debug!("{} has the desired ACL already", path.display());
+            None
+        }
+        Ok(false) => action(entries),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => action(entries),
+        Err(e) => {
+            log::error!("cannot read ACL of {}: {e}", path.display());
             None
-        } else {
-            log::info!("{} is {:o} instead of {}", self.dest, old_mode, self.mode);
-            Some(action)
         }
     }
 }
 
-fn parse_command_directive(directive: Option<&Vec<String>>) -> Option<Vec<String>> {
-    directive
-        .and_then(|values| values.first())
-        .map(|value| {
-            value
-                .split_whitespace()
-                .map(std::string::ToString::to_string)
-                .collect::<Vec<String>>()
+/// returns a `SetXattr` action for every extended attribute of `path` that
+/// differs from its `xattr` directive.
+fn xattr_actions(path: &Path, xattrs: &[(String, String)], replaced: bool) -> Vec<Action> {
+    xattrs
+        .iter()
+        .filter(|(name, value)| match xattr::get(path, name) {
+            _ if replaced => true,
+            Ok(current) => current.as_deref() != Some(value.as_bytes()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+            Err(e) => {
+                log::error!("cannot read {name} of {}: {e}", path.display());
+                false
+            }
+        })
+        .map(|(name, value)| {
+            Action::set_xattr(
+                Cause::Xattr,
+                path.to_path_buf(),
+                name.clone(),
+                value.clone(),
+            )
         })
-        .filter(|args| !args.is_empty())
+        .collect()
+}
+
+fn parse_command_directive(
+    directive: Option<&Vec<String>>,
+    vars: &Variables,
+) -> Result<Option<Vec<String>>, parser::ParseError> {
+    let Some(value) = directive.and_then(|values| values.first()) else {
+        return Ok(None);
+    };
+    let args = vars
+        .expand(value)?
+        .split_whitespace()
+        .map(std::string::ToString::to_string)
+        .collect::<Vec<String>>();
+    Ok(Some(args).filter(|args| !args.is_empty()))
+}
+
+fn parse_bool(directive: &str, value: &str) -> Result<bool, parser::ParseError> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(parser::ParseError::InvalidBoolean(
+            directive.to_string(),
+            value.to_string(),
+        )),
+    }
+}
+
+fn parse_variables(
+    mut vars: Variables,
+    definitions: Option<&Vec<String>>,
+) -> Result<Variables, parser::ParseError> {
+    for definition in definitions.into_iter().flatten() {
+        vars.define(definition)?;
+    }
+    Ok(vars)
 }
 
 fn parse_conditions(
     conditions: Option<&Vec<String>>,
+    vars: &Variables,
 ) -> Result<Vec<Condition>, parser::ParseError> {
     conditions
-        .map(|when| when.iter().map(|value| Condition::parse(value)).collect())
+        .map(|when| {
+            when.iter()
+                .map(|value| Condition::parse_with(value, vars))
+                .collect()
+        })
         .transpose()
         .map(Option::unwrap_or_default)
 }
@@ -397,16 +1125,39 @@ fn parse_conditions(
 impl From<&PetsFile> for Vec<Action> {
     fn from(val: &PetsFile) -> Self {
         log::debug!("planning actions for {}", val.source);
-        let actions = vec![
-            val.dest.needs_dir(),
-            val.dest.needs_copy(&val.source),
-            val.dest.needs_link(&val.source),
-            val.chown(),
-            val.chmod(),
-        ]
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
+        let mut actions = Vec::new();
+        actions.extend(val.dest.needs_cleanup());
+        if val.absent {
+            actions.extend(val.dest.needs_removal());
+        } else {
+            actions.extend(val.dest.needs_dir());
+            actions.extend(val.created_dirs());
+            if val.dest.is_dir() && !val.dest.is_symlink() {
+                // Like a single file, every file of a copied tree gets its
+                // final ownership and mode before it is renamed into place.
+                let copy = val.dest.needs_copy(&val.source);
+                let tree = val.tree(copy.is_some());
+                actions.extend(copy.map(|copy| copy.with_attributes(&tree)));
+                actions.extend(val.dest.needs_link(&val.source));
+                actions.extend(tree);
+            } else if val.dest.is_symlink() {
+                actions.extend(val.dest.needs_link(&val.source));
+            } else {
+                // A copied file gets its final ownership and mode before it
+                // is renamed into place.
+                let copy = val.dest.needs_copy(&val.source);
+                let replaced = copy.is_some();
+                let attributes = val
+                    .chown()
+                    .into_iter()
+                    .chain(val.chmod(replaced))
+                    .collect::<Vec<_>>();
+                actions.extend(copy.map(|copy| copy.with_attributes(&attributes)));
+                actions.extend(attributes);
+                let dest = PathBuf::from(val.dest.to_string());
+                actions.extend(val.attribute_actions(&dest, false, replaced));
+            }
+        }
         // If any actions are performed, check for a post-action
         if actions.is_empty() {
             actions
@@ -450,7 +1201,10 @@ mod tests {
             "value=true",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         assert_eq!(parsed.destination(), dest.to_string_lossy());
     }
 
@@ -465,18 +1219,82 @@ mod tests {
             "value=true",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         assert!(parsed.dest.is_symlink());
         assert_eq!(parsed.destination(), link_path.to_string_lossy());
     }
 
+    #[test]
+    fn test_from_path_returns_one_file_per_destination() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("authorized_keys");
+        let root = dir.path().join("root_keys");
+        let deploy = dir.path().join("deploy_keys");
+        let link = dir.path().join("keys_link");
+        write_pets_file(
+            &source,
+            &[
+                &format!("# pets: destfile={}, mode=0600", root.display()),
+                &format!("# pets: destfile={}", deploy.display()),
+                &format!("# pets: symlink={}", link.display()),
+            ],
+            "ssh-ed25519 AAAA",
+        );
+        std::fs::copy(&source, &root).unwrap();
+
+        let files =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap();
+        let dests = files.iter().map(PetsFile::destination).collect::<Vec<_>>();
+        assert_eq!(
+            dests,
+            vec![
+                root.to_string_lossy(),
+                deploy.to_string_lossy(),
+                link.to_string_lossy()
+            ]
+        );
+        assert!(files.iter().all(|pf| pf.mode == 0o600));
+        assert!(files[2].is_symlink_config());
+        assert_eq!(files[0].sync_status(), SyncStatus::InSync);
+        assert_eq!(files[1].sync_status(), SyncStatus::Missing);
+        assert_eq!(files[2].sync_status(), SyncStatus::LinkMissing);
+    }
+
+    #[test]
+    fn test_from_path_keeps_declared_destination_order() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        let link = dir.path().join("link");
+        let copy = dir.path().join("copy");
+        write_pets_file(
+            &source,
+            &[&format!(
+                "# pets: symlink={}, destfile={}",
+                link.display(),
+                copy.display()
+            )],
+            "set nu",
+        );
+        let files =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap();
+        let dests = files.iter().map(PetsFile::destination).collect::<Vec<_>>();
+        assert_eq!(dests, vec![link.to_string_lossy(), copy.to_string_lossy()]);
+        assert!(files[0].is_symlink_config());
+    }
+
     #[test]
     fn test_from_path_missing_destfile_or_symlink_returns_error() {
         let dir = tempdir().unwrap();
         let source = dir.path().join("config.conf");
         write_pets_file(&source, &["# pets: package=exa"], "value=true");
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests());
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default());
         assert!(matches!(
             parsed,
             Err(parser::ParseError::MissingDestFile(_))
@@ -497,8 +1315,11 @@ mod tests {
             "value=true",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
-        assert_eq!(parsed.mode.as_raw(), 0o640);
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        assert_eq!(parsed.mode.resolve(0), 0o640);
     }
 
     #[test]
@@ -516,7 +1337,10 @@ mod tests {
             "value=true",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         let packages = parsed.packages();
         assert_eq!(packages.len(), 2);
         assert_eq!(packages[0].name, "exa");
@@ -544,36 +1368,46 @@ mod tests {
             "value=true",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
-        assert_eq!(
-            parsed.user.as_ref().map(uzers::User::uid),
-            Some(current_user.uid())
-        );
-        assert_eq!(
-            parsed.group.as_ref().map(uzers::Group::gid),
-            Some(current_group.gid())
-        );
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        assert_eq!(parsed.user, Some(user_name));
+        assert_eq!(parsed.group, Some(group_name));
     }
 
     #[test]
-    fn test_from_path_unknown_owner_falls_back_to_current_user() {
+    fn test_from_path_keeps_unknown_owner_for_account_checks() {
         let dir = tempdir().unwrap();
         let source = dir.path().join("config.conf");
         let dest = dir.path().join("dest.conf");
-        let current_uid = uzers::get_current_uid();
         write_pets_file(
             &source,
             &[
                 &format!("# pets: destfile={}", dest.display()),
                 "# pets: owner=definitely_unknown_user_name_12345",
+                "# pets: user=definitely_unknown_user_name_12345 system shell=/bin/false",
+                "# pets: group=shared_unknown_group_12345",
+                "# pets: create_group=shared_unknown_group_12345 system",
             ],
             "value=true",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         assert_eq!(
-            parsed.user.as_ref().map(uzers::User::uid),
-            Some(current_uid)
+            parsed.owners(),
+            (
+                vec!["definitely_unknown_user_name_12345"],
+                vec!["shared_unknown_group_12345"]
+            )
+        );
+        assert_eq!(parsed.declared_users().len(), 1);
+        assert_eq!(
+            parsed.declared_groups()[0].name(),
+            "shared_unknown_group_12345"
         );
     }
 
@@ -599,7 +1433,10 @@ mod tests {
             "value=true",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         assert_eq!(
             parsed.pre,
             Some(vec!["/usr/bin/true".to_string(), "--check".to_string()])
@@ -612,7 +1449,95 @@ mod tests {
             parsed.conditions,
             vec![Condition::Os(current_os.to_string())]
         );
-        assert!(parsed.matches_conditions());
+        assert!(parsed.matches_conditions(&Facts::gather()));
+    }
+
+    #[test]
+    fn test_from_path_expands_variables_in_directives() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("config.conf");
+
+        write_pets_file(
+            &source,
+            &[
+                &format!("# pets: var=root={}", dir.path().display()),
+                "# pets: var=app=myapp",
+                "# pets: destfile=${root}/${app}/${PETS_TEST_UNSET_SUBDIR:-conf}/app.conf",
+                "# pets: package=cargo:$app",
+                "# pets: post=/bin/echo $app reloaded",
+            ],
+            "value=true",
+        );
+
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        assert_eq!(
+            parsed.destination(),
+            dir.path().join("myapp/conf/app.conf").to_string_lossy()
+        );
+        assert_eq!(parsed.packages()[0].name, "myapp");
+        assert_eq!(
+            parsed.post,
+            Some(vec![
+                "/bin/echo".to_string(),
+                "myapp".to_string(),
+                "reloaded".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_from_path_undefined_variable_returns_error() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("config.conf");
+        write_pets_file(
+            &source,
+            &["# pets: destfile=/etc/$PETS_TEST_DEFINITELY_UNDEFINED/app.conf"],
+            "value=true",
+        );
+
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default());
+        assert!(matches!(
+            parsed,
+            Err(parser::ParseError::UndefinedVariable(_))
+        ));
+    }
+
+    #[test]
+    fn test_static_plan_lists_steps_in_order() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("config.conf");
+        let dest = dir.path().join("dest.conf");
+        write_pets_file(
+            &source,
+            &[
+                &format!("# pets: destfile={}", dest.display()),
+                "# pets: package=cargo:bat, mode=0600",
+                "# pets: pre=/usr/bin/true, post=/bin/echo done",
+            ],
+            "value=true",
+        );
+
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        let steps = parsed.static_plan();
+        assert_eq!(steps.len(), 5);
+        assert_eq!(steps[0], "install cargo:bat");
+        assert!(steps[1].starts_with("validate with /usr/bin/true "));
+        assert_eq!(
+            steps[2],
+            format!("cp {} {}", parsed.source(), dest.display())
+        );
+        assert_eq!(steps[3], "chmod 600");
+        assert_eq!(steps[4], "run /bin/echo done if changed");
     }
 
     #[test]
@@ -626,7 +1551,10 @@ mod tests {
             "same-content",
         );
 
-        let parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         let expected_source = std::fs::canonicalize(&source)
             .unwrap()
             .to_string_lossy()
@@ -634,8 +1562,8 @@ mod tests {
         assert_eq!(parsed.source(), expected_source);
         assert_eq!(parsed.destination(), dest.to_string_lossy());
         assert!(parsed.packages().is_empty());
-        assert!(parsed.matches_conditions());
-        assert!(parsed.is_valid());
+        assert!(parsed.matches_conditions(&Facts::gather()));
+        assert!(parsed.is_valid(&mut HashMap::new()));
     }
 
     #[test]
@@ -649,7 +1577,10 @@ mod tests {
             "copy-content",
         );
 
-        let mut parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         parsed.user = None;
         parsed.group = None;
 
@@ -672,7 +1603,10 @@ mod tests {
             "link-content",
         );
 
-        let mut parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         parsed.user = None;
         parsed.group = None;
 
@@ -684,6 +1618,513 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_force_directive_replaces_conflicting_file() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("force_source.conf");
+        let dest = dir.path().join("force_dest");
+        write_pets_file(
+            &source,
+            &[&format!("# pets: symlink={}, force=true", dest.display())],
+            "link-content",
+        );
+        std::fs::write(&dest, "local").unwrap();
+
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        let source_abs = std::fs::canonicalize(&source).unwrap();
+        let actions: Vec<Action> = (&parsed).into();
+        assert_eq!(
+            actions,
+            vec![Action::replace_with_symlink(
+                Cause::Replace,
+                source_abs,
+                dest
+            )]
+        );
+        assert_eq!(parsed.sync_status(), SyncStatus::LinkWrong);
+    }
+
+    #[test]
+    fn test_conflicting_file_without_force_is_reported_but_kept() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("conflict_source.conf");
+        let dest = dir.path().join("conflict_dest");
+        write_pets_file(
+            &source,
+            &[&format!("# pets: symlink={}", dest.display())],
+            "link-content",
+        );
+        std::fs::write(&dest, "local").unwrap();
+
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        let actions: Vec<Action> = (&parsed).into();
+        assert!(actions.is_empty());
+        assert_eq!(parsed.sync_status(), SyncStatus::LinkWrong);
+
+        parsed.force_links();
+        let actions: Vec<Action> = (&parsed).into();
+        assert_eq!(actions[0].cause(), Cause::Replace);
+    }
+
+    #[test]
+    fn test_from_path_invalid_force_value_returns_error() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("bad_force.conf");
+        write_pets_file(&source, &["# pets: symlink=/tmp/x, force=maybe"], "x");
+
+        assert!(matches!(
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default()),
+            Err(parser::ParseError::InvalidBoolean(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_ensure_absent_plans_removal_and_post() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("legacy.pets");
+        let dest = dir.path().join("legacy.list");
+        write_pets_file(
+            &source,
+            &[
+                &format!("# pets: destfile={}, ensure=absent", dest.display()),
+                "# pets: post=/bin/echo removed",
+            ],
+            "",
+        );
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+
+        let actions: Vec<Action> = (&parsed).into();
+        assert!(actions.is_empty());
+        assert_eq!(parsed.sync_status(), SyncStatus::InSync);
+
+        std::fs::write(&dest, "deb http://old").unwrap();
+        let actions: Vec<Action> = (&parsed).into();
+        assert_eq!(
+            actions,
+            vec![
+                Action::remove(Cause::Remove, dest.clone()),
+                Action::command(
+                    Cause::Post,
+                    vec!["/bin/echo".to_string(), "removed".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(parsed.sync_status(), SyncStatus::Present);
+        assert_eq!(
+            parsed.static_plan(),
+            vec![
+                format!("rm -rf {}", dest.display()),
+                "run /bin/echo removed if removed".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_path_invalid_ensure_value_returns_error() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("bad_ensure.conf");
+        write_pets_file(&source, &["# pets: destfile=/tmp/x, ensure=gone"], "x");
+
+        assert!(matches!(
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default()),
+            Err(parser::ParseError::InvalidEnsure(_))
+        ));
+    }
+
+    #[test]
+    fn test_created_directories_get_dir_mode() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("authorized_keys");
+        let dest = dir.path().join("home/.ssh/authorized_keys");
+        write_pets_file(
+            &source,
+            &[&format!(
+                "# pets: destfile={}, mode=0600, dir_mode=0700",
+                dest.display()
+            )],
+            "ssh-ed25519 AAAA",
+        );
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        let actions: Vec<Action> = (&parsed).into();
+        assert_eq!(
+            actions[..3],
+            [
+                Action::create_dir(Cause::Dir, dir.path().join("home/.ssh")),
+                Action::chmod(Cause::Mode, dir.path().join("home"), 0o700),
+                Action::chmod(Cause::Mode, dir.path().join("home/.ssh"), 0o700),
+            ]
+        );
+        assert_eq!(
+            actions.last().unwrap(),
+            &Action::chmod(Cause::Mode, dest, 0o600)
+        );
+    }
+
+    #[test]
+    fn test_status_reports_mode_and_owner_drift_of_synced_content() {
+        // Giving the file away needs root.
+        if uzers::get_current_uid() != 0 {
+            return;
+        }
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("secret");
+        let dest = dir.path().join("secret.conf");
+        write_pets_file(
+            &source,
+            &[&format!(
+                "# pets: destfile={}, mode=0600, owner=root",
+                dest.display()
+            )],
+            "token",
+        );
+        fs::copy(&source, &dest).unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o644)).unwrap();
+        let uid = 65534;
+        std::os::unix::fs::chown(&dest, Some(uid), None).unwrap();
+        let old_owner = uzers::get_user_by_uid(uid).map_or_else(
+            || uid.to_string(),
+            |user| user.name().to_string_lossy().into_owned(),
+        );
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+
+        let status = parsed.status();
+        assert_eq!(status.content, AspectStatus::InSync);
+        assert_eq!(
+            status.owner,
+            AspectStatus::Drifted(format!("{old_owner}→root"))
+        );
+        // The group defaults to the current one but was not declared.
+        assert_eq!(status.group, AspectStatus::Unmanaged);
+        assert_eq!(status.mode, AspectStatus::Drifted("644→600".to_string()));
+        assert_eq!(status.packages, AspectStatus::Unmanaged);
+        assert_eq!(parsed.sync_status(), SyncStatus::InSync);
+    }
+
+    #[test]
+    fn test_symbolic_mode_resolves_against_source_and_flags_setuid() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("helper");
+        let dest = dir.path().join("bin/helper");
+        write_pets_file(
+            &source,
+            &[&format!(
+                "# pets: destfile={}, mode=u+s,go-w",
+                dest.display()
+            )],
+            "echo hi",
+        );
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o775)).unwrap();
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        assert!(
+            parsed
+                .static_plan()
+                .contains(&"chmod u+s,go-w (sets setuid)".to_string())
+        );
+        let actions: Vec<Action> = (&parsed).into();
+        assert_eq!(
+            actions.last().unwrap(),
+            &Action::chmod(Cause::Mode, dest, 0o4755)
+        );
+    }
+
+    /// returns whether the file system of `path` supports ACLs and user
+    /// extended attributes, which tmpfs and some containers lack.
+    fn supports_attributes(path: &Path) -> bool {
+        let probe = xattr::set(path, "user.pets-probe", b"x")
+            .and_then(|()| xattr::remove(path, "user.pets-probe"))
+            .and_then(|()| acl::apply(path, &[]));
+        match probe {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => false,
+            probe => {
+                probe.unwrap();
+                true
+            }
+        }
+    }
+
+    fn perform_all(actions: Vec<Action>) {
+        let config = crate::actions::RunConfig {
+            dry_run: false,
+            diff_limit: crate::actions::diff::DEFAULT_DIFF_LIMIT,
+            backup: None,
+        };
+        for action in actions {
+            action.perform(&config).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_acl_and_xattr_drift_is_detected_and_applied() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("app.log.conf");
+        let dest = dir.path().join("app.conf");
+        let lines = [
+            &format!("# pets: destfile={}, mode=0640", dest.display()),
+            "# pets: acl=g:0:r-x, acl=u:0:rw, xattr=user.origin=pets",
+        ];
+        write_pets_file(&source, &lines, "x");
+        std::fs::copy(&source, &dest).unwrap();
+        std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o640)).unwrap();
+        if !supports_attributes(&dest) {
+            return;
+        }
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        assert_eq!(parsed.sync_status(), SyncStatus::InSync);
+        assert!(parsed.status().attributes.is_drifted());
+        let actions: Vec<Action> = (&parsed).into();
+        assert_eq!(
+            actions.iter().map(Action::cause).collect::<Vec<_>>(),
+            [Cause::Acl, Cause::Xattr]
+        );
+        perform_all(actions);
+
+        // The mask is the union of the owning group and the named entries,
+        // which the group bits of the mode show.
+        let mode = fs::metadata(&dest).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o670);
+        assert!(acl::is_applied(&dest, &parsed.acl).unwrap());
+        assert!(parsed.chmod(false).is_none());
+        assert_eq!(parsed.status().attributes, AspectStatus::InSync);
+
+        // A chmod lowering the mask is drift, on top of changed content.
+        std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o640)).unwrap();
+        write_pets_file(&source, &lines, "y");
+        assert_eq!(parsed.sync_status(), SyncStatus::Modified);
+        assert!(parsed.status().attributes.is_drifted());
+        perform_all((&parsed).into());
+        assert!(acl::is_applied(&dest, &parsed.acl).unwrap());
+        assert_eq!(
+            xattr::get(&dest, "user.origin").unwrap().as_deref(),
+            Some(&b"pets"[..])
+        );
+        let actions: Vec<Action> = (&parsed).into();
+        assert!(actions.is_empty(), "{actions:?}");
+    }
+
+    #[test]
+    fn test_acl_and_xattr_apply_to_every_tree_entry() {
+        let dir = tempdir().unwrap();
+        let tree = dir.path().join("project");
+        std::fs::create_dir_all(tree.join("sub")).unwrap();
+        std::fs::write(tree.join("sub/file"), "x").unwrap();
+        let dest = dir.path().join("deployed");
+        write_pets_file(
+            &tree.join(".petsfile"),
+            &[
+                &format!("# pets: destfile={}", dest.display()),
+                "# pets: acl=g:0:rwx, acl=d:g:0:rwx, xattr=user.origin=pets",
+            ],
+            "",
+        );
+        if !supports_attributes(&tree.join("sub/file")) {
+            return;
+        }
+        let mut parsed = PetsFile::from_path(
+            &tree.join(".petsfile"),
+            package_manager_for_tests(),
+            &Variables::default(),
+        )
+        .unwrap()
+        .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        let actions: Vec<Action> = (&parsed).into();
+        let acls = actions
+            .iter()
+            .filter(|action| action.cause() == Cause::Acl)
+            .count();
+        assert!(acls >= 3, "{actions:?}");
+        perform_all(actions);
+
+        let file = dest.join("sub/file");
+        assert_eq!(
+            acl::named_entries(&file).unwrap(),
+            [AclEntry::parse("g:0:rwx").unwrap()]
+        );
+        assert_eq!(acl::named_entries(&dest.join("sub")).unwrap(), parsed.acl);
+        assert!(xattr::get(&file, "user.origin").unwrap().is_some());
+        let actions: Vec<Action> = (&parsed).into();
+        assert!(actions.is_empty(), "{actions:?}");
+    }
+
+    #[test]
+    fn test_from_path_invalid_acl_returns_error() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("bad_acl.conf");
+        write_pets_file(&source, &["# pets: destfile=/tmp/x, acl=m::rwx"], "x");
+
+        assert!(matches!(
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default()),
+            Err(parser::ParseError::InvalidAcl(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_path_scope_overrides_destination_guess() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("scoped.conf");
+        let parse = |lines: &[&str]| {
+            write_pets_file(&source, lines, "x");
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+        };
+
+        assert!(parse(&["# pets: destfile=/etc/x.conf"]).unwrap()[0].is_system_scope());
+        assert!(
+            !parse(&["# pets: destfile=/etc/x.conf, scope=user"]).unwrap()[0].is_system_scope()
+        );
+        assert!(matches!(
+            parse(&["# pets: destfile=/etc/x.conf, scope=root"]),
+            Err(parser::ParseError::InvalidScope(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_path_for_user_resolves_home_owner_and_conditions() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("profile");
+        write_pets_file(
+            &source,
+            &["# pets: destfile=~/.pets-test-profile, for_user=root, when=user:root"],
+            "x",
+        );
+        let mut vars = Variables::default();
+        vars.insert("HOME", "/home/pets-test-invoker".to_string());
+
+        let parsed = PetsFile::from_path(&source, package_manager_for_tests(), &vars)
+            .unwrap()
+            .remove(0);
+        assert_eq!(parsed.destination(), "/root/.pets-test-profile");
+        assert_eq!(parsed.for_user(), Some("root"));
+        assert_eq!(parsed.user.as_deref(), Some("root"));
+        assert_eq!(parsed.dir_user.as_deref(), Some("root"));
+        assert_eq!(parsed.group.as_deref(), Some("root"));
+
+        let mut facts = Facts::default();
+        facts.insert("user", "pets-test-invoker".to_string());
+        assert!(parsed.matches_conditions(&facts));
+    }
+
+    #[test]
+    fn test_exists_condition_expands_the_variables_of_the_file() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("tool");
+        fs::create_dir(dir.path().join("bin")).unwrap();
+        write_pets_file(
+            &source,
+            &[&format!(
+                "# pets: var=PREFIX={}, destfile=$PREFIX/etc/tool.conf, when=exists:$PREFIX/bin",
+                dir.path().display()
+            )],
+            "x",
+        );
+        let parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        assert!(parsed.matches_conditions(&Facts::default()));
+
+        let source = dir.path().join("profile");
+        write_pets_file(
+            &source,
+            &["# pets: destfile=~/.pets-test-profile, for_user=root, when=exists:~"],
+            "x",
+        );
+        let mut vars = Variables::default();
+        vars.insert("HOME", "/nonexistent/pets-test-invoker".to_string());
+        let parsed = PetsFile::from_path(&source, package_manager_for_tests(), &vars)
+            .unwrap()
+            .remove(0);
+        assert!(parsed.matches_conditions(&Facts::default()));
+    }
+
+    #[test]
+    fn test_petsfile_tree_gets_file_and_dir_modes_recursively() {
+        let dir = tempdir().unwrap();
+        let tree = dir.path().join("app");
+        std::fs::create_dir_all(tree.join("sub")).unwrap();
+        std::fs::write(tree.join("sub/x.conf"), "x").unwrap();
+        let source = tree.join(".petsfile");
+        let dest = dir.path().join("deployed");
+        write_pets_file(
+            &source,
+            &[&format!(
+                "# pets: destfile={}, mode=0640, dir_mode=0750",
+                dest.display()
+            )],
+            "",
+        );
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
+        parsed.user = None;
+        parsed.group = None;
+
+        let actions: Vec<Action> = (&parsed).into();
+        let attributes = vec![
+            Action::chmod(Cause::Mode, dest.clone(), 0o750),
+            Action::chmod(Cause::Mode, dest.join(".petsfile"), 0o640),
+            Action::chmod(Cause::Mode, dest.join("sub"), 0o750),
+            Action::chmod(Cause::Mode, dest.join("sub/x.conf"), 0o640),
+        ];
+        let copy = Action::copy_file(
+            Cause::Create,
+            std::fs::canonicalize(&tree).unwrap(),
+            dest.clone(),
+        )
+        .with_attributes(&attributes);
+        assert_eq!(actions, [vec![copy], attributes].concat());
+
+        // Files are written with their final mode rather than the source's.
+        std::fs::set_permissions(
+            tree.join("sub/x.conf"),
+            std::fs::Permissions::from_mode(0o666),
+        )
+        .unwrap();
+        perform_all(actions);
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&dest.join("sub/x.conf")), 0o640);
+        assert_eq!(mode(&dest.join("sub")), 0o750);
+        assert!(!dest.join("sub/x.conf.pets-tmp").exists());
+    }
+
     #[test]
     fn test_actions_from_pets_file_appends_post_after_file_actions() {
         let dir = tempdir().unwrap();
@@ -698,7 +2139,10 @@ mod tests {
             "copy-content",
         );
 
-        let mut parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         parsed.user = None;
         parsed.group = None;
 
@@ -731,7 +2175,10 @@ mod tests {
 
         std::fs::copy(&source, &dest).unwrap();
 
-        let mut parsed = PetsFile::from_path(&source, package_manager_for_tests()).unwrap();
+        let mut parsed =
+            PetsFile::from_path(&source, package_manager_for_tests(), &Variables::default())
+                .unwrap()
+                .remove(0);
         parsed.user = None;
         parsed.group = None;
 
@@ -0,0 +1,346 @@
+use super::parser::ParseError;
+use crate::facts::Facts;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+};
+use uzers::os::unix::UserExt;
+
+/// Variables available for expansion in directive values. User-defined
+/// values take precedence over facts, available as `fact.<name>`, and the
+/// process environment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Variables {
+    values: HashMap<String, String>,
+    facts: Option<Facts>,
+    // User the files are deployed for, see `for_user`
+    account: Option<String>,
+}
+
+impl From<&BTreeMap<String, String>> for Variables {
+    fn from(values: &BTreeMap<String, String>) -> Self {
+        Self {
+            values: values.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            ..Self::default()
+        }
+    }
+}
+
+impl Variables {
+    /// returns these variables with `facts` available as `fact.<name>`.
+    pub fn with_facts(mut self, facts: &Facts) -> Self {
+        self.facts = Some(facts.clone());
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: String) {
+        self.values.insert(name.to_string(), value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.values
+            .get(name)
+            .cloned()
+            .or_else(|| {
+                let facts = self.facts.as_ref()?;
+                facts.get(name.strip_prefix("fact.")?).map(str::to_string)
+            })
+            .or_else(|| env::var(name).ok())
+    }
+
+    /// returns a copy resolving `~`, `$HOME` and `$USER` against the passwd
+    /// entry of `name`, to deploy files into that user's home.
+    pub fn for_user(&self, name: &str) -> Result<Self, ParseError> {
+        let user = uzers::get_user_by_name(name)
+            .ok_or_else(|| ParseError::UnknownUser(name.to_string()))?;
+        let mut vars = self.clone();
+        vars.insert("HOME", user.home_dir().to_string_lossy().into_owned());
+        vars.insert("USER", name.to_string());
+        vars.account = Some(name.to_string());
+        Ok(vars)
+    }
+
+    /// returns the user set by `for_user`, if any.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// Parses a `var` directive of the form `NAME=value`, expanding the value
+    /// against the variables defined so far.
+    pub fn define(&mut self, directive: &str) -> Result<(), ParseError> {
+        let (name, value) = directive
+            .split_once('=')
+            .map(|(n, v)| (n.trim(), v.trim()))
+            .filter(|(n, _)| is_valid_name(n))
+            .ok_or_else(|| ParseError::InvalidVariable(directive.to_string()))?;
+        let value = self.expand(value)?;
+        self.insert(name, value);
+        Ok(())
+    }
+
+    /// Expands a leading `~`, `$NAME`, `${NAME}` and `${NAME:-default}` in
+    /// `value`. `$$` produces a literal `$`. Referencing an undefined variable
+    /// without a default is an error.
+    pub fn expand(&self, value: &str) -> Result<String, ParseError> {
+        let (mut out, rest) = match self.expand_tilde(value)? {
+            Some(home) => (home, &value[1..]),
+            None => (String::new(), value),
+        };
+        let mut chars = rest.char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            if c != '$' {
+                out.push(c);
+                continue;
+            }
+            match chars.peek().map(|&(_, next)| next) {
+                Some('$') => {
+                    chars.next();
+                    out.push('$');
+                }
+                Some('{') => {
+                    let start = i + 2;
+                    let end = find_closing_brace(&rest[start..])
+                        .map(|len| start + len)
+                        .ok_or_else(|| ParseError::InvalidVariable(value.to_string()))?;
+                    out.push_str(&self.expand_braced(&rest[start..end], value)?);
+                    while chars.next_if(|&(j, _)| j <= end).is_some() {}
+                }
+                Some(next) if next == '_' || next.is_ascii_alphabetic() => {
+                    let name = self.unbraced_name(&rest[i + 1..]);
+                    let resolved = self
+                        .get(name)
+                        .ok_or_else(|| ParseError::UndefinedVariable(name.to_string()))?;
+                    out.push_str(&resolved);
+                    let end = i + 1 + name.len();
+                    while chars.next_if(|&(j, _)| j < end).is_some() {}
+                }
+                _ => out.push('$'),
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// returns the variable name at the start of `s`. A `.` only continues
+    /// the name if the dotted name is defined, so `$fact.cpu_count` means the
+    /// same as `${fact.cpu_count}` while `$app.service` still expands `app`.
+    fn unbraced_name<'a>(&self, s: &'a str) -> &'a str {
+        let mut len = identifier_len(s);
+        while let Some(segment) = s[len..].strip_prefix('.') {
+            let next = len + 1 + identifier_len(segment);
+            if next == len + 1 || self.get(&s[..next]).is_none() {
+                break;
+            }
+            len = next;
+        }
+        &s[..len]
+    }
+
+    /// Resolves a leading `~` or `~/` against `HOME`.
+    fn expand_tilde(&self, value: &str) -> Result<Option<String>, ParseError> {
+        if value == "~" || value.starts_with("~/") {
+            self.get("HOME")
+                .map(Some)
+                .ok_or_else(|| ParseError::UndefinedVariable("HOME".to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn expand_braced(&self, inner: &str, value: &str) -> Result<String, ParseError> {
+        let (name, default) = match inner.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (inner, None),
+        };
+        if !is_valid_name(name) {
+            return Err(ParseError::InvalidVariable(value.to_string()));
+        }
+
+        // Like the shell, an empty value takes the default but is no error.
+        match (self.get(name), default) {
+            (Some(resolved), Some(default)) if resolved.is_empty() => self.expand(default),
+            (Some(resolved), _) => Ok(resolved),
+            (None, Some(default)) => self.expand(default),
+            (None, None) => Err(ParseError::UndefinedVariable(name.to_string())),
+        }
+    }
+}
+
+/// a name is one or more identifiers joined by `.`, like `fact.cpu_count`.
+fn is_valid_name(name: &str) -> bool {
+    name.split('.')
+        .all(|segment| !segment.is_empty() && identifier_len(segment) == segment.len())
+}
+
+/// returns the length of the identifier at the start of `s`, 0 if none.
+fn identifier_len(s: &str) -> usize {
+    s.char_indices()
+        .find(|&(i, c)| !(c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit())))
+        .map_or(s.len(), |(i, _)| i)
+}
+
+/// returns the byte offset of the `}` matching an already consumed `${`.
+fn find_closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Variables {
+        let mut vars = Variables::default();
+        for (name, value) in pairs {
+            vars.insert(name, (*value).to_string());
+        }
+        vars
+    }
+
+    #[test]
+    fn expand_plain_value_is_unchanged() {
+        assert_eq!(Variables::default().expand("/etc/foo").unwrap(), "/etc/foo");
+    }
+
+    #[test]
+    fn expand_simple_and_braced_variables() {
+        let vars = vars(&[("APP", "myapp"), ("ROOT", "/opt")]);
+        assert_eq!(vars.expand("$ROOT/$APP.conf").unwrap(), "/opt/myapp.conf");
+        assert_eq!(vars.expand("${ROOT}/${APP}d").unwrap(), "/opt/myappd");
+    }
+
+    #[test]
+    fn expand_dotted_names_alike_with_and_without_braces() {
+        let vars = vars(&[("fact.cpu_count", "8"), ("app", "web")]);
+        assert_eq!(vars.expand("$fact.cpu_count").unwrap(), "8");
+        assert_eq!(vars.expand("${fact.cpu_count}").unwrap(), "8");
+        assert_eq!(vars.expand("$app.service").unwrap(), "web.service");
+        assert_eq!(vars.expand("$app.").unwrap(), "web.");
+        assert!(matches!(
+            vars.expand("${app.service}"),
+            Err(ParseError::UndefinedVariable(name)) if name == "app.service"
+        ));
+    }
+
+    #[test]
+    fn expand_facts_by_dotted_name() {
+        let mut facts = Facts::default();
+        facts.insert("gpu_vendor", "nvidia".to_string());
+        let vars = Variables::default().with_facts(&facts);
+        assert_eq!(vars.expand("${fact.gpu_vendor}").unwrap(), "nvidia");
+        assert_eq!(vars.expand("$fact.gpu_vendor.conf").unwrap(), "nvidia.conf");
+        assert!(vars.expand("${fact.missing}").is_err());
+    }
+
+    #[test]
+    fn expand_default_when_unset_or_empty() {
+        let vars = vars(&[("HOME", "/home/me"), ("EMPTY", "")]);
+        assert_eq!(
+            vars.expand("${PETS_TEST_UNSET_XDG:-~/.config}/nvim")
+                .unwrap(),
+            "/home/me/.config/nvim"
+        );
+        assert_eq!(vars.expand("${EMPTY:-fallback}").unwrap(), "fallback");
+        assert_eq!(vars.expand("a${EMPTY}b").unwrap(), "ab");
+        assert_eq!(vars.expand("a$EMPTY/b").unwrap(), "a/b");
+    }
+
+    #[test]
+    fn expand_default_is_ignored_when_set() {
+        let vars = vars(&[("XDG_CONFIG_HOME", "/xdg")]);
+        assert_eq!(
+            vars.expand("${XDG_CONFIG_HOME:-~/.config}/git").unwrap(),
+            "/xdg/git"
+        );
+    }
+
+    #[test]
+    fn expand_tilde_uses_home_variable() {
+        let vars = vars(&[("HOME", "/home/alice")]);
+        assert_eq!(vars.expand("~").unwrap(), "/home/alice");
+        assert_eq!(vars.expand("~/.vimrc").unwrap(), "/home/alice/.vimrc");
+        assert_eq!(vars.expand("/srv/~/x").unwrap(), "/srv/~/x");
+    }
+
+    #[test]
+    fn for_user_resolves_home_from_passwd() {
+        let vars = Variables::default().for_user("root").unwrap();
+        assert_eq!(vars.account(), Some("root"));
+        assert_eq!(vars.expand("~/.profile").unwrap(), "/root/.profile");
+        assert_eq!(vars.expand("$USER").unwrap(), "root");
+        assert!(matches!(
+            Variables::default().for_user("pets-no-such-user"),
+            Err(ParseError::UnknownUser(_))
+        ));
+    }
+
+    #[test]
+    fn expand_falls_back_to_environment() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(
+            Variables::default().expand("$HOME/.zshrc").unwrap(),
+            format!("{home}/.zshrc")
+        );
+    }
+
+    #[test]
+    fn expand_undefined_variable_is_error() {
+        let err = Variables::default()
+            .expand("/etc/$PETS_TEST_DEFINITELY_UNDEFINED/x")
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::UndefinedVariable(name) if name == "PETS_TEST_DEFINITELY_UNDEFINED"
+        ));
+    }
+
+    #[test]
+    fn expand_escaped_and_lone_dollar() {
+        let vars = Variables::default();
+        assert_eq!(vars.expand("cost $$5").unwrap(), "cost $5");
+        assert_eq!(vars.expand("echo $1 $").unwrap(), "echo $1 $");
+    }
+
+    #[test]
+    fn expand_unterminated_brace_is_error() {
+        assert!(matches!(
+            Variables::default().expand("${HOME"),
+            Err(ParseError::InvalidVariable(_))
+        ));
+    }
+
+    #[test]
+    fn define_parses_and_expands_value() {
+        let mut vars = vars(&[("ROOT", "/opt")]);
+        vars.define("app_dir = $ROOT/app").unwrap();
+        assert_eq!(vars.get("app_dir").unwrap(), "/opt/app");
+    }
+
+    #[test]
+    fn define_rejects_invalid_name() {
+        let mut vars = Variables::default();
+        assert!(matches!(
+            vars.define("1abc=foo"),
+            Err(ParseError::InvalidVariable(_))
+        ));
+        for name in ["a..b", "a.", ".a", "a.1b"] {
+            assert!(matches!(
+                vars.define(&format!("{name}=foo")),
+                Err(ParseError::InvalidVariable(_))
+            ));
+        }
+        assert!(matches!(
+            vars.define("novalue"),
+            Err(ParseError::InvalidVariable(_))
+        ));
+    }
+}
@@ -4,8 +4,9 @@
 // commands must succeed.
 
 use crate::{
-    actions::{self, Package, package_manager::PackageManager},
-    pet_files::PetsFile,
+    actions::{self, Cause, Package, package_manager::PackageManager},
+    facts::Facts,
+    pet_files::{PetsFile, account},
 };
 use std::{
     collections::{HashMap, HashSet},
@@ -51,23 +52,53 @@ pub fn check_global_constraints(files: &[PetsFile]) -> Result<(), DuplicateDefin
     Ok(())
 }
 
-pub fn plan_actions(files: Vec<PetsFile>) -> Vec<actions::Action> {
+#[derive(Debug, Error)]
+#[error(
+    "'{src}' refers to unknown {kind} '{name}', declare it with a '{kind}' directive to create it"
+)]
+pub struct UnknownAccountError {
+    kind: &'static str,
+    name: String,
+    src: String,
+}
+
+/// How much of a configuration file's validation the planner may run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    /// Search package repositories and run `pre` validation commands.
+    Full,
+    /// Only inspect state: skip package searches, `pre` commands, fact
+    /// scripts and `cmd:` probes, marking the changes they could still skip
+    /// or reject as gated.
+    InspectOnly,
+}
+
+pub fn plan_actions(
+    files: Vec<PetsFile>,
+    facts: &Facts,
+    validation: Validation,
+) -> Vec<actions::Action> {
     // Check validation errors in individual files. At this stage, the
     // command in the "pre" validation directive may not be installed yet.
     // An error in one file means we're gonna skip it but proceed with the rest.
+    // A source with several destinations runs its "pre" command only once.
+    let mut pre_results = HashMap::new();
     let good_pets = files
         .into_iter()
-        .filter(|pf| {
-            let matches = pf.matches_conditions();
-            if !matches {
+        .filter(|pf| match pf.unmet_condition(facts) {
+            Some(condition) if pf.unknown_condition(facts).is_none() => {
                 log::debug!(
-                    "skipping '{}' due to unmatched 'when' condition(s)",
+                    "skipping '{}': 'when' condition '{condition}' not met",
                     pf.source()
                 );
+                false
             }
-            matches
+            _ => true,
+        })
+        .filter(|pf| match validation {
+            Validation::Full => pf.is_valid(&mut pre_results),
+            Validation::InspectOnly => true,
         })
-        .filter(PetsFile::is_valid)
         .collect::<Vec<_>>();
 
     // Determines a list of packages that need to be installed.
@@ -85,45 +116,173 @@ pub fn plan_actions(files: Vec<PetsFile>) -> Vec<actions::Action> {
         })
         .collect::<HashSet<Package>>();
 
-    // Generate the list of actions to perform.
+    // Generate the list of actions to perform. The destinations of a source
+    // share its "post" command, which runs once after all of them.
     let trigger_actions = good_pets
+        .chunk_by(|a, b| a.source() == b.source())
+        .flat_map(|dests| {
+            let mut actions = Vec::new();
+            let mut posts = Vec::new();
+            for pf in dests {
+                let gate = gate(pf, facts, validation);
+                if let Some(gate) = &gate {
+                    log::warn!(
+                        "{}: {gate}, applying may still skip or reject this change",
+                        pf.destination()
+                    );
+                }
+                for action in Vec::<actions::Action>::from(pf) {
+                    let action = if pf.is_system_scope() {
+                        action.privileged()
+                    } else {
+                        action
+                    };
+                    let action = match &gate {
+                        Some(gate) => action.gated(gate.clone()),
+                        None => action,
+                    };
+                    if action.cause() != Cause::Post {
+                        actions.push(action);
+                    } else if !posts.contains(&action) {
+                        posts.push(action);
+                    }
+                }
+            }
+            actions.extend(posts);
+            actions
+        });
+    let mut packages: HashMap<String, Vec<String>> = HashMap::new();
+    for pkg in pkgs {
+        packages
+            .entry(pkg.package_manager.to_string())
+            .or_default()
+            .push(pkg.name);
+    }
+    packages
+        .iter()
+        .map(|(pkg_manager, packages)| {
+            let pkg_manager = PackageManager::from_str(pkg_manager).unwrap();
+            let install_vec = pkg_manager.install_command();
+
+            let packages_to_install = install_vec
+                .into_iter()
+                .chain(packages.iter().map(ToString::to_string))
+                .collect();
+            if pkg_manager.requires_sudo() {
+                actions::Action::command_with_sudo(actions::Cause::Pkg, packages_to_install)
+                    .privileged()
+            } else {
+                actions::Action::command(actions::Cause::Pkg, packages_to_install)
+            }
+        })
+        .chain(account_actions(&good_pets, facts))
+        .chain(trigger_actions)
+        .collect()
+}
+
+/// returns what was not run that could still skip or reject the changes to
+/// `pf`: a `when` condition that needs a command to evaluate, or a pending
+/// `pre` command when only inspecting.
+fn gate(pf: &PetsFile, facts: &Facts, validation: Validation) -> Option<String> {
+    if let Some(condition) = pf.unknown_condition(facts) {
+        return Some(format!("'when' condition '{condition}' not evaluated"));
+    }
+    match validation {
+        Validation::Full => None,
+        Validation::InspectOnly => pf
+            .pending_pre()
+            .map(|pre| format!("'pre' validation '{}' not run", pre.join(" "))),
+    }
+}
+
+/// validates that every owner, group and ACL entry of the configuration
+/// files names a user or group that either exists or is declared by a `user`
+/// or `group` directive.
+pub fn check_accounts(files: &[PetsFile]) -> Result<(), UnknownAccountError> {
+    let users = files
+        .iter()
+        .flat_map(PetsFile::declared_users)
+        .map(account::User::name)
+        .collect::<HashSet<_>>();
+    // Users are created with a primary group of the same name.
+    let groups = files
         .iter()
-        .flat_map(Into::<Vec<actions::Action>>::into);
-    if pkgs.is_empty() {
-        trigger_actions.collect()
-    } else {
-        let mut packages: HashMap<String, Vec<String>> = HashMap::new();
-        for pkg in pkgs {
-            packages
-                .entry(pkg.package_manager.to_string())
-                .or_default()
-                .push(pkg.name);
+        .flat_map(PetsFile::declared_groups)
+        .map(account::Group::name)
+        .chain(users.iter().copied())
+        .collect::<HashSet<_>>();
+
+    for pf in files {
+        let (owners, owner_groups) = pf.owners();
+        let unknown_user = owners
+            .into_iter()
+            .find(|name| !users.contains(name) && uzers::get_user_by_name(name).is_none())
+            .map(|name| ("user", name));
+        let unknown_group = owner_groups
+            .into_iter()
+            .find(|name| !groups.contains(name) && uzers::get_group_by_name(name).is_none())
+            .map(|name| ("group", name));
+        if let Some((kind, name)) = unknown_user.or(unknown_group) {
+            return Err(UnknownAccountError {
+                kind,
+                name: name.to_string(),
+                src: pf.source().to_string(),
+            });
         }
-        packages
-            .iter()
-            .map(|(pkg_manager, packages)| {
-                let pkg_manager = PackageManager::from_str(pkg_manager).unwrap();
-                let install_vec = pkg_manager.install_command();
-
-                let packages_to_install = install_vec
-                    .into_iter()
-                    .chain(packages.iter().map(ToString::to_string))
-                    .collect();
-                if pkg_manager.requires_sudo() {
-                    actions::Action::command_with_sudo(actions::Cause::Pkg, packages_to_install)
-                } else {
-                    actions::Action::command(actions::Cause::Pkg, packages_to_install)
-                }
-            })
-            .chain(trigger_actions)
-            .collect()
     }
+    Ok(())
+}
+
+/// returns the actions creating the declared users and groups that do not
+/// exist yet, groups first.
+fn account_actions(files: &[PetsFile], facts: &Facts) -> Vec<actions::Action> {
+    let alpine = facts.distro_family().any(|distro| distro == "alpine");
+    // Users and groups are separate namespaces, so a group and a user of
+    // the same name are both created.
+    let mut seen_groups = HashSet::new();
+    let groups = files
+        .iter()
+        .flat_map(PetsFile::declared_groups)
+        .filter(|group| seen_groups.insert(group.name()))
+        .collect::<Vec<_>>();
+    let mut seen_users = HashSet::new();
+    let users = files
+        .iter()
+        .flat_map(PetsFile::declared_users)
+        .filter(|user| seen_users.insert(user.name()))
+        .collect::<Vec<_>>();
+
+    let as_root = |cause, args| {
+        if uzers::get_current_uid() == 0 {
+            actions::Action::command(cause, args)
+        } else {
+            actions::Action::command_with_sudo(cause, args).privileged()
+        }
+    };
+    let group_actions = groups
+        .iter()
+        .filter(|group| uzers::get_group_by_name(group.name()).is_none())
+        .map(|group| as_root(actions::Cause::Group, group.create_command(alpine)));
+    let user_actions = users
+        .iter()
+        .filter(|user| uzers::get_user_by_name(user.name()).is_none())
+        .flat_map(|user| {
+            let has_group = uzers::get_group_by_name(user.name()).is_some()
+                || groups.iter().any(|group| group.name() == user.name());
+            user.create_commands(alpine, has_group)
+        })
+        .map(|args| as_root(actions::Cause::User, args));
+    group_actions.chain(user_actions).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{fs::File, io::Write};
+    use crate::pet_files::Variables;
+    use std::{
+        fs::{self, File},
+        io::Write,
+    };
     use tempfile::tempdir;
 
     fn make_pets_file(
@@ -135,7 +294,8 @@ mod tests {
         let mut file = File::create(&path)?;
         writeln!(file, "# pets: destfile={destfile}")?;
         writeln!(file, "body")?;
-        PetsFile::from_path(&path, PackageManager::Cargo)
+        PetsFile::from_path(&path, PackageManager::Cargo, &Variables::default())
+            .map(|mut files| files.remove(0))
     }
 
     #[test]
@@ -160,6 +320,243 @@ mod tests {
         assert!(msg.contains("a.conf") || msg.contains("b.conf"));
     }
 
+    #[test]
+    fn test_check_global_constraints_err_on_repeated_destfile_in_one_file() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("twice.conf");
+        fs::write(
+            &path,
+            "# pets: destfile=/tmp/pets-test-twice, destfile=/tmp/pets-test-twice\n",
+        )
+        .unwrap();
+        let files =
+            PetsFile::from_path(&path, PackageManager::Cargo, &Variables::default()).unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert!(check_global_constraints(&files).is_err());
+    }
+
+    #[test]
+    fn test_check_accounts_err_on_unknown_owner() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("app.conf");
+        fs::write(
+            &path,
+            "# pets: destfile=/tmp/pets-test-app, owner=pets_test_no_such_user\n",
+        )
+        .unwrap();
+        let files =
+            PetsFile::from_path(&path, PackageManager::Cargo, &Variables::default()).unwrap();
+
+        let err = check_accounts(&files).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("unknown user 'pets_test_no_such_user'")
+        );
+    }
+
+    #[test]
+    fn test_accounts_declared_anywhere_are_created_first() {
+        let tmp = tempdir().unwrap();
+        let app = tmp.path().join("app.conf");
+        fs::write(
+            &app,
+            "# pets: destfile=/tmp/pets-test-app, owner=pets_test_app, group=pets_test_app\n",
+        )
+        .unwrap();
+        let account = tmp.path().join("account.conf");
+        fs::write(
+            &account,
+            "# pets: destfile=/tmp/pets-test-account, user=pets_test_app system\n\
+             # pets: create_group=pets_test_shared system\n",
+        )
+        .unwrap();
+        let files = [app, account]
+            .iter()
+            .flat_map(|path| {
+                PetsFile::from_path(path, PackageManager::Cargo, &Variables::default()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(check_accounts(&files).is_ok());
+
+        let overrides = [("distro".to_string(), "alpine".to_string())];
+        let facts = Facts::simulate(tmp.path().to_str().unwrap(), &overrides).unwrap();
+        let planned = plan_actions(files, &facts, Validation::Full);
+        let commands = planned
+            .iter()
+            .take(3)
+            .map(|action| (action.cause(), action.to_string()))
+            .collect::<Vec<_>>();
+        let sudo = if uzers::get_current_uid() == 0 {
+            ""
+        } else {
+            "sudo "
+        };
+        assert_eq!(
+            commands,
+            [
+                (
+                    actions::Cause::Group,
+                    format!(
+                        "{}: {sudo}addgroup -S pets_test_shared",
+                        actions::Cause::Group
+                    )
+                ),
+                (
+                    actions::Cause::User,
+                    format!("{}: {sudo}addgroup -S pets_test_app", actions::Cause::User)
+                ),
+                (
+                    actions::Cause::User,
+                    format!(
+                        "{}: {sudo}adduser -D -S -G pets_test_app pets_test_app",
+                        actions::Cause::User
+                    )
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_and_user_of_the_same_name_are_both_created() {
+        let tmp = tempdir().unwrap();
+        let source = tmp.path().join("app.conf");
+        fs::write(
+            &source,
+            "# pets: destfile=/tmp/pets-test-same-name, acl=g:pets_test_same:r\n\
+             # pets: create_group=pets_test_same system, user=pets_test_same system\n",
+        )
+        .unwrap();
+        let files =
+            PetsFile::from_path(&source, PackageManager::Cargo, &Variables::default()).unwrap();
+        assert!(check_accounts(&files).is_ok());
+
+        let overrides = [("distro".to_string(), "debian".to_string())];
+        let facts = Facts::simulate(tmp.path().to_str().unwrap(), &overrides).unwrap();
+        let causes = account_actions(&files, &facts)
+            .iter()
+            .map(actions::Action::cause)
+            .collect::<Vec<_>>();
+        assert_eq!(causes, [actions::Cause::Group, actions::Cause::User]);
+    }
+
+    #[test]
+    fn test_check_accounts_err_on_unknown_acl_entry() {
+        let tmp = tempdir().unwrap();
+        let source = tmp.path().join("app.conf");
+        fs::write(
+            &source,
+            "# pets: destfile=/tmp/pets-test-acl, acl=u:pets_test_acl_unknown:rw\n",
+        )
+        .unwrap();
+        let files =
+            PetsFile::from_path(&source, PackageManager::Cargo, &Variables::default()).unwrap();
+        let err = check_accounts(&files).unwrap_err();
+        assert_eq!(err.name, "pets_test_acl_unknown");
+    }
+
+    #[test]
+    fn test_pre_and_post_run_once_per_source() {
+        let tmp = tempdir().unwrap();
+        // Fails when run a second time for the same source.
+        let pre = tmp.path().join("pre.sh");
+        fs::write(&pre, "mkdir \"$1.pre-ran\"\n").unwrap();
+        let first = tmp.path().join("first.conf");
+        let second = tmp.path().join("second.conf");
+        let source = tmp.path().join("app.conf");
+        fs::write(
+            &source,
+            format!(
+                "# pets: destfile={}, destfile={}, pre=sh {}, post=true\nbody\n",
+                first.display(),
+                second.display(),
+                pre.display()
+            ),
+        )
+        .unwrap();
+        let files =
+            PetsFile::from_path(&source, PackageManager::Cargo, &Variables::default()).unwrap();
+
+        let planned = plan_actions(files, &Facts::default(), Validation::Full);
+        let rendered = planned.iter().map(ToString::to_string).collect::<Vec<_>>();
+        for dest in [&first, &second] {
+            assert!(
+                rendered
+                    .iter()
+                    .any(|a| a.contains(&*dest.to_string_lossy())),
+                "{rendered:?}"
+            );
+        }
+        let posts = planned
+            .iter()
+            .filter(|action| action.cause() == actions::Cause::Post)
+            .count();
+        assert_eq!(posts, 1);
+        assert_eq!(planned.last().unwrap().cause(), actions::Cause::Post);
+    }
+
+    #[test]
+    fn test_inspect_only_gates_changes_instead_of_running_commands() {
+        let tmp = tempdir().unwrap();
+        let marker = tmp.path().join("ran");
+        let probed = tmp.path().join("probed.conf");
+        let validated = tmp.path().join("validated.conf");
+        let skipped = tmp.path().join("skipped.conf");
+        let mut files = Vec::new();
+        for (name, dest, directives) in [
+            (
+                "probed",
+                &probed,
+                format!("when=cmd:touch {}", marker.display()),
+            ),
+            (
+                "validated",
+                &validated,
+                format!("pre=touch {}", marker.display()),
+            ),
+            (
+                "skipped",
+                &skipped,
+                format!("when=os:macos & cmd:touch {}", marker.display()),
+            ),
+        ] {
+            let source = tmp.path().join(name);
+            fs::write(
+                &source,
+                format!("# pets: destfile={}, {directives}\n", dest.display()),
+            )
+            .unwrap();
+            files.extend(
+                PetsFile::from_path(&source, PackageManager::Cargo, &Variables::default()).unwrap(),
+            );
+        }
+
+        let facts = Facts::gather().without_commands();
+        let planned = plan_actions(files, &facts, Validation::InspectOnly);
+        assert!(!marker.exists());
+        let gate = |dest: &std::path::Path| {
+            planned
+                .iter()
+                .find(|action| action.to_string().contains(&*dest.to_string_lossy()))
+                .map(|action| action.gate().unwrap_or_default().to_string())
+        };
+        assert_eq!(
+            gate(&probed),
+            Some(format!(
+                "'when' condition 'cmd:touch {}' not evaluated",
+                marker.display()
+            ))
+        );
+        assert_eq!(
+            gate(&validated),
+            Some(format!(
+                "'pre' validation 'touch {}' not run",
+                marker.display()
+            ))
+        );
+        assert_eq!(gate(&skipped), None);
+    }
+
     #[test]
     fn test_duplicate_definition_error_display() {
         let err = DuplicateDefinitionError::new(
@@ -0,0 +1,271 @@
+// Privilege escalation for `--become`. pets keeps running as the invoking
+// user and starts a single root helper through sudo, doas or run0, so there
+// is one authentication up front. Privileged actions are sent to the helper
+// over a private Unix socket, one JSON line each, and performed there.
+
+use crate::actions::{Action, ActionError, BackupStore, RunConfig};
+use clap::ValueEnum;
+use serde_json::{Value, json};
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitCode},
+    thread,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Method {
+    Sudo,
+    Doas,
+    Run0,
+}
+
+impl Method {
+    fn program(self) -> &'static str {
+        match self {
+            Method::Sudo => "sudo",
+            Method::Doas => "doas",
+            Method::Run0 => "run0",
+        }
+    }
+}
+
+/// creates a directory only the invoking user can enter, under an
+/// unpredictable name like mkdtemp(3), so no one can put a socket of their
+/// own in its place.
+fn create_socket_dir() -> io::Result<PathBuf> {
+    let mut urandom = fs::File::open("/dev/urandom")?;
+    for _ in 0..16 {
+        let mut suffix = [0; 8];
+        urandom.read_exact(&mut suffix)?;
+        let suffix = u64::from_ne_bytes(suffix);
+        let dir = env::temp_dir().join(format!("pets-become-{suffix:016x}"));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {
+                fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+                return Ok(dir);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "cannot create a unique socket directory",
+    ))
+}
+
+pub struct RootHelper {
+    child: Child,
+    stream: BufReader<UnixStream>,
+    socket_dir: PathBuf,
+}
+
+impl RootHelper {
+    /// starts the root helper with `method`, which may ask for a password.
+    pub fn start(
+        method: Method,
+        backup: Option<&BackupStore>,
+        diff_limit: u64,
+    ) -> io::Result<Self> {
+        let socket_dir = create_socket_dir()?;
+
+        // Pass on the options the helper needs to behave like this process.
+        let mut args = Vec::new();
+        match log::max_level() {
+            log::LevelFilter::Debug | log::LevelFilter::Trace => args.push("--debug".to_string()),
+            log::LevelFilter::Info => {}
+            _ => args.push("--quiet".to_string()),
+        }
+        match backup {
+            Some(store) => args.extend(store.args()),
+            None => args.push("--no-backup".to_string()),
+        }
+        args.extend(["--diff-max-size".to_string(), diff_limit.to_string()]);
+
+        let result = Self::connect(method, &args, &socket_dir);
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&socket_dir);
+        }
+        result
+    }
+
+    fn connect(method: Method, args: &[String], socket_dir: &Path) -> io::Result<Self> {
+        let socket = socket_dir.join("helper.sock");
+        let listener = UnixListener::bind(&socket)?;
+        listener.set_nonblocking(true)?;
+        let mut child = Command::new(method.program())
+            .arg(env::current_exe()?)
+            .args(args)
+            .arg("root-helper")
+            .arg("--socket")
+            .arg(&socket)
+            .spawn()?;
+
+        // Wait for the helper to connect, or for authentication to fail.
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    return Ok(Self {
+                        child,
+                        stream: BufReader::new(stream),
+                        socket_dir: socket_dir.to_path_buf(),
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if let Some(status) = child.try_wait()? {
+                        return Err(io::Error::other(format!(
+                            "{} exited with {status}",
+                            method.program()
+                        )));
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(err) => {
+                    let _ = child.kill();
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// performs `action` as root and returns its result.
+    pub fn perform(&mut self, action: &Action) -> Result<i32, ActionError> {
+        writeln!(self.stream.get_mut(), "{}", action.to_json())?;
+        let mut response = String::new();
+        if self.stream.read_line(&mut response)? == 0 {
+            return Err(ActionError::Escalation("root helper exited".to_string()));
+        }
+
+        let response = serde_json::from_str::<Value>(&response)
+            .map_err(|err| ActionError::Escalation(err.to_string()))?;
+        if let Some(code) = response.get("ok").and_then(Value::as_i64) {
+            return Ok(i32::try_from(code).unwrap_or(1));
+        }
+        match response.get("error").and_then(Value::as_str) {
+            Some(err) => Err(ActionError::Escalation(err.to_string())),
+            None => Err(ActionError::Escalation(format!(
+                "invalid response {response}"
+            ))),
+        }
+    }
+}
+
+impl Drop for RootHelper {
+    fn drop(&mut self) {
+        let _ = self.stream.get_ref().shutdown(Shutdown::Both);
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.socket_dir);
+    }
+}
+
+/// runs the root helper: performs the actions received on `socket` until
+/// pets closes it.
+pub fn serve(socket: &Path, backup: Option<BackupStore>, diff_limit: u64) -> ExitCode {
+    let stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::error!("cannot connect to {}: {err}", socket.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut responses = match stream.try_clone() {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let config = RunConfig {
+        dry_run: false,
+        diff_limit,
+        backup,
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return ExitCode::FAILURE;
+        };
+        let action = serde_json::from_str(&line)
+            .ok()
+            .as_ref()
+            .and_then(Action::from_json);
+        let response = match action.map(|action| action.perform(&config)) {
+            Some(Ok(code)) => json!({ "ok": code }),
+            Some(Err(err)) => json!({ "error": err.to_string() }),
+            None => json!({ "error": format!("invalid request: {line}") }),
+        };
+        if writeln!(responses, "{response}").is_err() {
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::Cause;
+    use tempfile::tempdir;
+
+    #[test]
+    fn socket_dir_is_private_and_unpredictable() {
+        let first = create_socket_dir().unwrap();
+        let second = create_socket_dir().unwrap();
+        assert_ne!(first, second);
+        let mode = fs::metadata(&first).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        fs::remove_dir(first).unwrap();
+        fs::remove_dir(second).unwrap();
+    }
+
+    #[test]
+    fn serve_performs_requests_and_reports_errors() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("helper.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let helper = thread::spawn({
+            let socket = socket.clone();
+            move || serve(&socket, None, crate::actions::diff::DEFAULT_DIFF_LIMIT)
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = BufReader::new(stream);
+
+        let created = dir.path().join("a/b");
+        let requests = [
+            Action::create_dir(Cause::Dir, created.clone()).to_json(),
+            Action::remove(Cause::Remove, dir.path().join("missing")).to_json(),
+            json!({ "cause": "Nope" }),
+        ];
+        let mut responses = Vec::new();
+        for request in requests {
+            writeln!(stream.get_mut(), "{request}").unwrap();
+            let mut response = String::new();
+            stream.read_line(&mut response).unwrap();
+            responses.push(serde_json::from_str::<Value>(&response).unwrap());
+        }
+        stream.get_ref().shutdown(Shutdown::Both).unwrap();
+
+        assert!(created.is_dir());
+        assert_eq!(responses[0], json!({ "ok": 0 }));
+        assert!(responses[1].get("error").is_some());
+        assert!(
+            responses[2]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid request")
+        );
+        assert_eq!(
+            format!("{:?}", helper.join().unwrap()),
+            format!("{:?}", ExitCode::SUCCESS)
+        );
+    }
+}
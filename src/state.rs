@@ -0,0 +1,87 @@
+// Pets remembers the content it last deployed to every destination, so that
+// a later run can tell source changes apart from local edits. Copies live in
+// the state directory, mirroring the destination path:
+//
+// ~/.local/state/pets/deployed/etc/nginx/nginx.conf
+//
+// After local changes were merged, the source that was merged is kept next to
+// the deployed copy, so later runs compare the source against it instead.
+
+use std::{
+    env,
+    path::{Component, Path, PathBuf},
+};
+
+pub const STATE_DIR_ENV: &str = "PETS_STATE_DIR";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDir {
+    dir: PathBuf,
+}
+
+impl StateDir {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// returns `$PETS_STATE_DIR`, or `pets` below `${XDG_STATE_HOME:-~/.local/state}`.
+    pub fn from_env() -> Option<Self> {
+        let non_empty = |name| env::var(name).ok().filter(|v| !v.is_empty());
+        if let Some(dir) = non_empty(STATE_DIR_ENV) {
+            return Some(Self::new(dir));
+        }
+        let state_home = non_empty("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| non_empty("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+        Some(Self::new(state_home.join("pets")))
+    }
+
+    /// returns where the content last deployed to `dest` is kept.
+    pub fn deployed_path(&self, dest: &str) -> PathBuf {
+        let relative = Path::new(dest)
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<PathBuf>();
+        self.dir.join("deployed").join(relative)
+    }
+}
+
+/// returns where the source is kept that was merged with local changes into
+/// the content deployed at `deployed`. Without it, the deployed content is
+/// the source itself.
+pub fn merged_source_path(deployed: &Path) -> PathBuf {
+    let mut path = deployed.as_os_str().to_owned();
+    path.push(".pets-source");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deployed_path_mirrors_destination() {
+        let state = StateDir::new("/var/lib/pets");
+        assert_eq!(
+            state.deployed_path("/etc/nginx/nginx.conf"),
+            PathBuf::from("/var/lib/pets/deployed/etc/nginx/nginx.conf")
+        );
+    }
+
+    #[test]
+    fn merged_source_path_sits_next_to_deployed_copy() {
+        assert_eq!(
+            merged_source_path(Path::new("/var/lib/pets/deployed/etc/app.conf")),
+            PathBuf::from("/var/lib/pets/deployed/etc/app.conf.pets-source")
+        );
+    }
+
+    #[test]
+    fn deployed_path_stays_inside_state_dir() {
+        let state = StateDir::new("/var/lib/pets");
+        assert_eq!(
+            state.deployed_path("/etc/../../root/x"),
+            PathBuf::from("/var/lib/pets/deployed/etc/root/x")
+        );
+    }
+}
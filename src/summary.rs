@@ -3,12 +3,22 @@ use crate::actions::Cause;
 #[derive(Default)]
 pub struct RunSummary {
     packages_installed: usize,
+    groups_created: usize,
+    users_created: usize,
     files_created: usize,
     files_updated: usize,
+    files_merged: usize,
+    local_changes: usize,
     links_created: usize,
+    links_repointed: usize,
+    links_replaced: usize,
     dirs_created: usize,
+    removed: usize,
+    leftovers_removed: usize,
     ownership_changes: usize,
     mode_changes: usize,
+    acl_changes: usize,
+    xattr_changes: usize,
     post_commands: usize,
     errors: usize,
     skipped: usize,
@@ -18,12 +28,22 @@ impl RunSummary {
     pub fn record(&mut self, cause: Cause) {
         match cause {
             Cause::Pkg => self.packages_installed += 1,
+            Cause::Group => self.groups_created += 1,
+            Cause::User => self.users_created += 1,
             Cause::Create => self.files_created += 1,
             Cause::Update => self.files_updated += 1,
+            Cause::Merge => self.files_merged += 1,
+            Cause::Drift => self.local_changes += 1,
             Cause::Link => self.links_created += 1,
+            Cause::Relink => self.links_repointed += 1,
+            Cause::Replace => self.links_replaced += 1,
             Cause::Dir => self.dirs_created += 1,
+            Cause::Remove => self.removed += 1,
+            Cause::Cleanup => self.leftovers_removed += 1,
             Cause::Owner => self.ownership_changes += 1,
             Cause::Mode => self.mode_changes += 1,
+            Cause::Acl => self.acl_changes += 1,
+            Cause::Xattr => self.xattr_changes += 1,
             Cause::Post => self.post_commands += 1,
             Cause::None => {}
         }
@@ -41,11 +61,21 @@ impl RunSummary {
         let counts = [
             (self.files_created, "created"),
             (self.files_updated, "updated"),
+            (self.files_merged, "merged"),
+            (self.local_changes, "local changes overwritten"),
             (self.links_created, "links created"),
+            (self.links_repointed, "links re-pointed"),
+            (self.links_replaced, "replaced with links"),
             (self.dirs_created, "dirs created"),
+            (self.removed, "removed"),
+            (self.leftovers_removed, "leftover temp files removed"),
             (self.packages_installed, "packages installed"),
+            (self.groups_created, "groups created"),
+            (self.users_created, "users created"),
             (self.ownership_changes, "ownership changes"),
             (self.mode_changes, "mode changes"),
+            (self.acl_changes, "ACL changes"),
+            (self.xattr_changes, "xattr changes"),
             (self.post_commands, "post commands"),
             (self.skipped, "already in sync"),
             (self.errors, "errors"),
@@ -69,11 +99,21 @@ impl RunSummary {
         let counts = [
             (self.files_created, "created"),
             (self.files_updated, "updated"),
+            (self.files_merged, "merged"),
+            (self.local_changes, "local changes overwritten"),
             (self.links_created, "links created"),
+            (self.links_repointed, "links re-pointed"),
+            (self.links_replaced, "replaced with links"),
             (self.dirs_created, "dirs created"),
+            (self.removed, "removed"),
+            (self.leftovers_removed, "leftover temp files removed"),
             (self.packages_installed, "packages installed"),
+            (self.groups_created, "groups created"),
+            (self.users_created, "users created"),
             (self.ownership_changes, "ownership changes"),
             (self.mode_changes, "mode changes"),
+            (self.acl_changes, "ACL changes"),
+            (self.xattr_changes, "xattr changes"),
             (self.post_commands, "post commands"),
             (self.skipped, "already in sync"),
             (self.errors, "errors"),